@@ -0,0 +1,275 @@
+//! Throughput and memory of the piece pipeline and of whole transfers over loopback.
+//!
+//! `cargo bench -p client --bench throughput -- --save <name>` keeps the results in
+//! `target/throughput/<name>.json`, and `-- --baseline <name>` prints how far the
+//! current results are from kept ones, so commits can be compared.
+
+#[path = "../tests/harness/mod.rs"]
+mod harness;
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    fs,
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use client::protocol::{ChannelCrypto, Compression, Message, Opened, Role, PIECE_SIZE};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use harness::{random_bytes, wait_for_file, Harness};
+
+// Pieces can't be bigger than `PIECE_SIZE`, which both ends of a transfer agree on
+const CHUNK_SIZES: [usize; 4] = [PIECE_SIZE / 8, PIECE_SIZE / 4, PIECE_SIZE / 2, PIECE_SIZE];
+const PIPELINE_BYTES: usize = 64 * 1024 * 1024;
+const FILE_SIZES: [usize; 3] = [1024 * 1024, 8 * 1024 * 1024, 32 * 1024 * 1024];
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(300);
+const MIB: f64 = 1024.0 * 1024.0;
+
+/// Counts the bytes allocated, so memory is measured the same way on every machine
+struct CountingAlloc;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            grow(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = System.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+            grow(new_size);
+        }
+        new_ptr
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+fn grow(size: usize) {
+    let allocated = ALLOCATED.fetch_add(size, Ordering::Relaxed) + size;
+    PEAK.fetch_max(allocated, Ordering::Relaxed);
+}
+
+/// Resets the peak, returns what is allocated already
+fn start_measuring() -> usize {
+    let allocated = ALLOCATED.load(Ordering::Relaxed);
+    PEAK.store(allocated, Ordering::Relaxed);
+    allocated
+}
+
+/// The most that was allocated on top of `allocated` since measuring started
+fn peak_since(allocated: usize) -> usize {
+    PEAK.load(Ordering::Relaxed).saturating_sub(allocated)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct BenchResult {
+    #[serde(rename = "name")]
+    name: String,
+    #[serde(rename = "mibPerSec")]
+    mib_per_sec: f64,
+    #[serde(rename = "peakBytes")]
+    peak_bytes: usize,
+}
+
+fn text_bytes(size: usize) -> Vec<u8> {
+    b"the quick brown fox jumps over the lazy dog\n"
+        .iter()
+        .copied()
+        .cycle()
+        .take(size)
+        .collect()
+}
+
+/// Two ends of an encrypted channel, done with their handshake
+fn established() -> (ChannelCrypto, ChannelCrypto) {
+    let file_id = Uuid::new_v4();
+    let mut source = ChannelCrypto::new(Some("purple-monkey"), file_id, Role::Source);
+    let mut sink = ChannelCrypto::new(Some("purple-monkey"), file_id, Role::Sink);
+
+    let source_pake = source.start().expect("pake message").encode();
+    let sink_pake = sink.start().expect("pake message").encode();
+    let replies = (
+        source.open(sink_pake).expect("handshake"),
+        sink.open(source_pake).expect("handshake"),
+    );
+    if let (Opened::Reply(source_confirm), Opened::Reply(sink_confirm)) = replies {
+        sink.open(source_confirm.encode()).expect("confirmed");
+        source.open(sink_confirm.encode()).expect("confirmed");
+    }
+
+    (source, sink)
+}
+
+/// What a data source does to a piece before it goes out, and what a data sink does
+/// once it arrives
+fn bench_pipeline(
+    name: &str,
+    data: &[u8],
+    chunk_size: usize,
+    compression: Compression,
+    encrypted: bool,
+) -> BenchResult {
+    let (mut source, mut sink) = if encrypted {
+        established()
+    } else {
+        let file_id = Uuid::new_v4();
+        (
+            ChannelCrypto::new(None, file_id, Role::Source),
+            ChannelCrypto::new(None, file_id, Role::Sink),
+        )
+    };
+    let chunks: Vec<Bytes> = data
+        .chunks(chunk_size)
+        .map(Bytes::copy_from_slice)
+        .collect();
+
+    let allocated = start_measuring();
+    let started = Instant::now();
+    for (piece, chunk) in chunks.iter().enumerate() {
+        let message = Message::Piece {
+            piece: piece as u32,
+            data: chunk.clone(),
+        };
+        let sealed = source
+            .seal(message.encode_with(compression))
+            .expect("sealed");
+        match sink.open(sealed).expect("opened") {
+            Opened::Message(Message::Piece { data, .. }) => assert_eq!(data.len(), chunk.len()),
+            opened => panic!("unexpected message: {:?}", opened),
+        }
+    }
+    let elapsed = started.elapsed();
+
+    BenchResult {
+        name: format!("pipeline/{}/{}k", name, chunk_size / 1024),
+        mib_per_sec: data.len() as f64 / MIB / elapsed.as_secs_f64(),
+        peak_bytes: peak_since(allocated),
+    }
+}
+
+/// A whole transfer, from the data sink starting until the file is written, so it
+/// includes connecting
+async fn bench_transfer(harness: &Harness, size: usize) -> BenchResult {
+    let name = format!("payload-{}.bin", size);
+    let contents = random_bytes(size);
+    let path = harness.write_file(&name, &contents);
+    let file_id = harness.share(None, &path, false).await;
+    let download_dir = harness.path(&format!("received-{}", size));
+
+    let allocated = start_measuring();
+    let started = Instant::now();
+    harness.fetch(None, file_id, &download_dir, false);
+    let received = wait_for_file(&download_dir.join(&name), size, TRANSFER_TIMEOUT).await;
+    let elapsed = started.elapsed();
+    assert!(received == Some(contents), "file wasn't received intact");
+
+    BenchResult {
+        name: format!("transfer/{}m", size / (1024 * 1024)),
+        mib_per_sec: size as f64 / MIB / elapsed.as_secs_f64(),
+        peak_bytes: peak_since(allocated),
+    }
+}
+
+fn results_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("../target/throughput")
+        .join(format!("{}.json", name))
+}
+
+fn arg_value(args: &[String], flag: &str) -> Option<String> {
+    args.iter()
+        .position(|arg| arg == flag)
+        .and_then(|position| args.get(position + 1))
+        .cloned()
+}
+
+fn report(result: &BenchResult, baseline: &[BenchResult]) {
+    let change = baseline
+        .iter()
+        .find(|base| base.name == result.name)
+        .map(|base| {
+            format!(
+                "  {:+.1}%",
+                (result.mib_per_sec / base.mib_per_sec - 1.0) * 100.0
+            )
+        })
+        .unwrap_or_default();
+
+    println!(
+        "{:<32} {:>10.1} MiB/s {:>10.1} MiB peak{}",
+        result.name,
+        result.mib_per_sec,
+        result.peak_bytes as f64 / MIB,
+        change
+    );
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let baseline: Vec<BenchResult> = match arg_value(&args, "--baseline") {
+        Some(name) => {
+            let contents = fs::read_to_string(results_path(&name)).expect("baseline exists");
+            serde_json::from_str(&contents).expect("valid baseline")
+        }
+        None => vec![],
+    };
+
+    let mut results = vec![];
+
+    let random = random_bytes(PIPELINE_BYTES);
+    let text = text_bytes(PIPELINE_BYTES);
+    for chunk_size in CHUNK_SIZES {
+        for (name, data, compression, encrypted) in [
+            ("random", &random, Compression::None, false),
+            ("random-zstd", &random, Compression::Zstd, false),
+            ("text-zstd", &text, Compression::Zstd, false),
+            ("text-zstd-encrypted", &text, Compression::Zstd, true),
+        ] {
+            let result = bench_pipeline(name, data, chunk_size, compression, encrypted);
+            report(&result, &baseline);
+            results.push(result);
+        }
+    }
+    drop((random, text));
+
+    let harness = Harness::loopback().await;
+    for size in FILE_SIZES {
+        let result = bench_transfer(&harness, size).await;
+        report(&result, &baseline);
+        results.push(result);
+    }
+    drop(harness);
+
+    if let Some(name) = arg_value(&args, "--save") {
+        let path = results_path(&name);
+        fs::create_dir_all(path.parent().expect("results dir")).expect("results dir created");
+        fs::write(
+            &path,
+            serde_json::to_string_pretty(&results).expect("results serialized"),
+        )
+        .expect("results saved");
+        println!("Saved results to {:?}", path);
+    }
+
+    // Peers keep serving on their own threads
+    std::process::exit(0);
+}
@@ -1,29 +1,90 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, time::Duration};
 
 use anyhow::Result;
-use reqwest::Client;
+use reqwest::{Client, RequestBuilder};
 
-use crate::errors::{ApiError, ClientError};
+use crate::{
+    config::Config,
+    errors::{ApiError, ClientError},
+};
 use common::models::{
-    CandidateReq, FindServerForFileReq, FindServerForFileRes, OfferReq, OfferRes,
-    RegisterOrRefreshServerReq,
+    CandidateReq, ClaimNameplateReq, ClaimNameplateRes, DeregisterServerReq, FindServerForFileReq,
+    FindServerForFileRes, LookupNameplateRes, OfferReq, OfferRes, RegisterOrRefreshServerReq,
+    TurnCredentialsRes,
 };
 
+const DEFAULT_DISCOVERY_URL: &str = "http://localhost:8000";
+const DEFAULT_SIGNALING_TIMEOUT_SECS: u64 = 10;
+
 #[derive(Clone)]
 pub struct Api {
     client: Client,
+    discovery_url: String,
+    discovery_token: Option<String>,
+}
+
+impl Default for Api {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Api {
     pub fn new() -> Self {
         let client = reqwest::Client::new();
 
-        Self { client }
+        Self {
+            client,
+            discovery_url: DEFAULT_DISCOVERY_URL.to_string(),
+            discovery_token: None,
+        }
+    }
+
+    /// An api talking to the discovery of the config, authenticating with its token.
+    /// Requests which take longer than the signaling timeout fail.
+    pub fn from_config(config: &Config) -> Self {
+        let mut api = Self::new();
+        let timeout = config
+            .signaling_timeout_secs
+            .unwrap_or(DEFAULT_SIGNALING_TIMEOUT_SECS);
+        if let Ok(client) = Client::builder()
+            .timeout(Duration::from_secs(timeout))
+            .build()
+        {
+            api.client = client;
+        }
+        if let Some(discovery_url) = &config.discovery_url {
+            api.discovery_url = discovery_url.trim_end_matches('/').to_string();
+        }
+        api.discovery_token = config.discovery_token.clone();
+
+        api
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.discovery_url, path)
+    }
+
+    fn authorized(&self, builder: RequestBuilder) -> RequestBuilder {
+        match &self.discovery_token {
+            Some(token) => builder.bearer_auth(token),
+            None => builder,
+        }
+    }
+
+    /// Discovery answers with 401 or 403 when the token is missing or lacks a scope
+    fn check_authorized(res: reqwest::Response) -> Result<reqwest::Response, ClientError> {
+        match res.status() {
+            reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::FORBIDDEN => {
+                Err(ClientError::ApiError(ApiError::ErrUnauthorized))
+            }
+            _ => Ok(res),
+        }
     }
 
     pub async fn discovery_hello(&self) -> Result<(), ClientError> {
         self.client
-            .get("http://localhost:8000/")
+            .get(self.url("/"))
             .send()
             .await
             .map_err(|err| ClientError::ApiError(ApiError::ReqwestError(err)))?
@@ -38,12 +99,39 @@ impl Api {
         &self,
         req_body: RegisterOrRefreshServerReq,
     ) -> Result<(), ClientError> {
-        self.client
-            .post("http://localhost:8000/api/server/register")
-            .json(&req_body)
+        let res = self
+            .authorized(
+                self.client
+                    .post(self.url("/api/server/register"))
+                    .json(&req_body),
+            )
             .send()
             .await
-            .map_err(|err| ClientError::ApiError(ApiError::ReqwestError(err)))?
+            .map_err(|err| ClientError::ApiError(ApiError::ReqwestError(err)))?;
+
+        Self::check_authorized(res)?
+            .json::<HashMap<String, bool>>()
+            .await
+            .map_err(|err| ClientError::ApiError(ApiError::ReqwestError(err)))?;
+
+        Ok(())
+    }
+
+    pub async fn deregister_server(
+        &self,
+        req_body: DeregisterServerReq,
+    ) -> Result<(), ClientError> {
+        let res = self
+            .authorized(
+                self.client
+                    .post(self.url("/api/server/deregister"))
+                    .json(&req_body),
+            )
+            .send()
+            .await
+            .map_err(|err| ClientError::ApiError(ApiError::ReqwestError(err)))?;
+
+        Self::check_authorized(res)?
             .json::<HashMap<String, bool>>()
             .await
             .map_err(|err| ClientError::ApiError(ApiError::ReqwestError(err)))?;
@@ -55,13 +143,18 @@ impl Api {
         &self,
         req_body: FindServerForFileReq,
     ) -> Result<FindServerForFileRes, ClientError> {
-        let url = String::from("http://localhost:8000/api/server/") + &req_body.file_id;
-        let resp = self
-            .client
-            .get(url)
+        let url = self.url("/api/server/") + &req_body.file_id;
+        let mut builder = self.authorized(self.client.get(url));
+        if let Some(share_token) = &req_body.share_token {
+            builder = builder.header("X-Share-Token", share_token);
+        }
+
+        let res = builder
             .send()
             .await
-            .map_err(|err| ClientError::ApiError(ApiError::ReqwestError(err)))?
+            .map_err(|err| ClientError::ApiError(ApiError::ReqwestError(err)))?;
+
+        let resp = Self::check_authorized(res)?
             .json::<FindServerForFileRes>()
             .await
             .map_err(|err| ClientError::ApiError(ApiError::ReqwestError(err)))?;
@@ -69,6 +162,62 @@ impl Api {
         Ok(resp)
     }
 
+    pub async fn claim_nameplate(
+        &self,
+        req_body: ClaimNameplateReq,
+    ) -> Result<ClaimNameplateRes, ClientError> {
+        let res = self
+            .authorized(
+                self.client
+                    .post(self.url("/api/nameplate/"))
+                    .json(&req_body),
+            )
+            .send()
+            .await
+            .map_err(|err| ClientError::ApiError(ApiError::ReqwestError(err)))?;
+
+        let resp = Self::check_authorized(res)?
+            .json::<ClaimNameplateRes>()
+            .await
+            .map_err(|err| ClientError::ApiError(ApiError::ReqwestError(err)))?;
+
+        Ok(resp)
+    }
+
+    pub async fn lookup_nameplate(
+        &self,
+        nameplate: String,
+    ) -> Result<LookupNameplateRes, ClientError> {
+        let url = self.url("/api/nameplate/") + &nameplate;
+        let res = self
+            .authorized(self.client.get(url))
+            .send()
+            .await
+            .map_err(|err| ClientError::ApiError(ApiError::ReqwestError(err)))?;
+
+        let resp = Self::check_authorized(res)?
+            .json::<LookupNameplateRes>()
+            .await
+            .map_err(|err| ClientError::ApiError(ApiError::ReqwestError(err)))?;
+
+        Ok(resp)
+    }
+
+    pub async fn turn_credentials(&self) -> Result<TurnCredentialsRes, ClientError> {
+        let res = self
+            .authorized(self.client.get(self.url("/api/turn/credentials")))
+            .send()
+            .await
+            .map_err(|err| ClientError::ApiError(ApiError::ReqwestError(err)))?;
+
+        let resp = Self::check_authorized(res)?
+            .json::<TurnCredentialsRes>()
+            .await
+            .map_err(|err| ClientError::ApiError(ApiError::ReqwestError(err)))?;
+
+        Ok(resp)
+    }
+
     pub async fn send_offer(
         &self,
         url: String,
@@ -0,0 +1,64 @@
+use rand::seq::SliceRandom;
+
+use super::words::WORDS;
+
+const CODE_WORDS: usize = 2;
+
+/// Makes a code like `7-purple-sausages` out of a nameplate handed out by discovery.
+/// The whole code doubles as the passphrase of the transfer, only the nameplate is ever
+/// sent to discovery.
+pub fn generate_code(nameplate: &str) -> String {
+    let mut rng = rand::thread_rng();
+
+    let mut code = nameplate.to_string();
+    for _ in 0..CODE_WORDS {
+        code.push('-');
+        code.push_str(WORDS.choose(&mut rng).unwrap_or(&WORDS[0]));
+    }
+
+    code
+}
+
+/// Returns the nameplate of a code, `None` if it isn't one
+pub fn parse_code(code: &str) -> Option<String> {
+    let mut parts = code.split('-');
+
+    let nameplate = parts.next()?;
+    if nameplate.is_empty() || !nameplate.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    let words: Vec<&str> = parts.collect();
+    if words.is_empty()
+        || !words
+            .iter()
+            .all(|word| !word.is_empty() && word.chars().all(|c| c.is_ascii_lowercase()))
+    {
+        return None;
+    }
+
+    Some(nameplate.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{generate_code, parse_code};
+
+    #[test]
+    fn test_code_round_trip() {
+        let code = generate_code("7");
+        assert_eq!(code.split('-').count(), 3);
+        assert_eq!(parse_code(&code), Some(String::from("7")));
+
+        assert_eq!(parse_code("12-purple-sausages"), Some(String::from("12")));
+    }
+
+    #[test]
+    fn test_invalid_codes() {
+        assert_eq!(parse_code("67e55044-10b1-426f-9247-bb680e5ff1b8"), None);
+        assert_eq!(parse_code("purple-sausages"), None);
+        assert_eq!(parse_code("7"), None);
+        assert_eq!(parse_code("7--sausages"), None);
+        assert_eq!(parse_code("7-Purple-sausages"), None);
+    }
+}
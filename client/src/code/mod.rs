@@ -0,0 +1,3 @@
+pub use code::*;
+mod code;
+mod words;
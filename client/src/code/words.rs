@@ -0,0 +1,31 @@
+// Short and easy to tell apart when read out loud, two of them make 16 bits of a code
+pub const WORDS: [&str; 256] = [
+    "acorn", "adult", "agent", "alarm", "album", "alert", "alien", "alpha", "amber", "anchor",
+    "angel", "ankle", "apple", "apron", "arena", "armor", "arrow", "atlas", "attic", "autumn",
+    "badge", "bagel", "baker", "balloon", "bamboo", "banana", "banjo", "barrel", "basket",
+    "beacon", "beard", "beaver", "bench", "berry", "bicycle", "binder", "biscuit", "blanket",
+    "blender", "blossom", "bonnet", "border", "bottle", "boulder", "bracket", "breeze", "brick",
+    "bridge", "bubble", "bucket", "buffalo", "bugle", "bundle", "burger", "butter", "button",
+    "cabin", "cactus", "camel", "candle", "canoe", "canvas", "canyon", "captain", "carbon",
+    "carpet", "carrot", "castle", "cattle", "cement", "cheese", "cherry", "chimney", "circus",
+    "citrus", "clover", "cobra", "coconut", "coffee", "comet", "copper", "coral", "cotton",
+    "cowboy", "coyote", "crayon", "cricket", "crystal", "cupcake", "curtain", "cushion", "dagger",
+    "daisy", "dancer", "denim", "desert", "diamond", "dinner", "doctor", "dolphin", "donkey",
+    "dragon", "drawer", "dream", "drum", "eagle", "easel", "echo", "eclipse", "elbow", "ember",
+    "engine", "falcon", "feather", "fiddle", "finger", "flamingo", "flannel", "flute", "forest",
+    "fossil", "fountain", "fox", "galaxy", "garden", "garlic", "gecko", "giant", "ginger",
+    "giraffe", "glacier", "glove", "goblin", "gopher", "granite", "grape", "guitar", "hammer",
+    "harbor", "harvest", "hazel", "helmet", "hermit", "hippo", "honey", "hornet", "husky", "igloo",
+    "island", "ivory", "jacket", "jaguar", "jelly", "jigsaw", "jungle", "kayak", "kettle",
+    "kitten", "koala", "ladder", "lagoon", "lantern", "lemon", "leopard", "lettuce", "lizard",
+    "llama", "lobster", "locket", "lotus", "magnet", "mango", "maple", "marble", "meadow", "melon",
+    "mermaid", "meteor", "mitten", "monkey", "moose", "mosaic", "muffin", "mustard", "napkin",
+    "nectar", "needle", "noodle", "nugget", "oasis", "ocean", "octopus", "olive", "onion",
+    "orange", "orbit", "orchid", "otter", "oyster", "paddle", "panda", "panther", "papaya",
+    "parrot", "peanut", "pebble", "pelican", "pencil", "pepper", "pickle", "pigeon", "pillow",
+    "pirate", "planet", "plum", "pocket", "poppy", "potato", "pretzel", "pudding", "puffin",
+    "pumpkin", "puppet", "purple", "quartz", "quill", "rabbit", "radish", "raven", "ribbon",
+    "rocket", "saddle", "salmon", "sausages", "scarf", "shovel", "silver", "sparrow", "spider",
+    "sprout", "squash", "summer", "sunset", "tiger", "tomato", "tulip", "turtle", "velvet",
+    "violin", "volcano", "waffle", "walrus", "willow", "wizard", "yogurt", "zebra",
+];
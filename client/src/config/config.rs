@@ -0,0 +1,120 @@
+use std::{fs, path::PathBuf};
+
+use common::entities::IceServer;
+use serde::{Deserialize, Serialize};
+
+use crate::{errors::ClientError, ice::VirtualNet};
+
+const TURENT_DIR: &str = ".turent";
+const CONFIG_FILE: &str = "config.json";
+const CONTROL_TOKEN_FILE: &str = "control_token";
+
+/// Settings of a client, read from `~/.turent/config.json`. Every field is optional so
+/// a missing file just means the defaults.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Config {
+    /// Bearer token sent to discovery, when it requires one
+    #[serde(rename = "discoveryToken", default)]
+    pub discovery_token: Option<String>,
+    /// STUN and TURN servers to connect through, replacing the public STUN server
+    #[serde(rename = "iceServers", default)]
+    pub ice_servers: Option<Vec<IceServer>>,
+    /// Connects through TURN only, for networks which forbid direct UDP
+    #[serde(rename = "relayOnly", default)]
+    pub relay_only: bool,
+    /// Where discovery runs, `http://localhost:8000` when there is none
+    #[serde(rename = "discoveryUrl", default)]
+    pub discovery_url: Option<String>,
+    /// Port of the local server peers signal through, 8080 to share and 8081 to fetch
+    /// when there is none
+    #[serde(rename = "port", default)]
+    pub port: Option<u16>,
+    /// Port of a daemon's control API, only reachable from the same host, 8082 when
+    /// there is none
+    #[serde(rename = "controlPort", default)]
+    pub control_port: Option<u16>,
+    /// File the bearer token of a daemon's control API is kept in, generated when
+    /// missing, `~/.turent/control_token` when there is none
+    #[serde(rename = "controlTokenFile", default)]
+    pub control_token_file: Option<PathBuf>,
+    /// Bytes per second every file served shares, unlimited when there is none, never 0
+    #[serde(rename = "uploadLimit", default)]
+    pub upload_limit: Option<u64>,
+    /// Bytes per second every download shares, unlimited when there is none, never 0
+    #[serde(rename = "downloadLimit", default)]
+    pub download_limit: Option<u64>,
+    /// Seconds a request to discovery or another peer may take, 10 when there is none
+    #[serde(rename = "signalingTimeoutSecs", default)]
+    pub signaling_timeout_secs: Option<u64>,
+    /// Seconds a peer connection gets to connect once signalled, 30 when there is none
+    #[serde(rename = "connectTimeoutSecs", default)]
+    pub connect_timeout_secs: Option<u64>,
+    /// Seconds a peer may stay quiet while pieces are expected from it, or requests
+    /// for them, 60 when there is none
+    #[serde(rename = "inactivityTimeoutSecs", default)]
+    pub inactivity_timeout_secs: Option<u64>,
+    /// Virtual network peer connections run on instead of the host's, for tests
+    #[serde(skip)]
+    pub vnet: Option<VirtualNet>,
+}
+
+impl Config {
+    /// `TURENT_DISCOVERY_TOKEN` overrides the token of the file
+    pub fn load() -> Result<Self, ClientError> {
+        let path = turent_dir().join(CONFIG_FILE);
+
+        let mut config = match fs::read_to_string(&path) {
+            Ok(contents) => Self::from_json(&contents)?,
+            Err(_) => Self::default(),
+        };
+
+        if let Ok(token) = std::env::var("TURENT_DISCOVERY_TOKEN") {
+            config.discovery_token = Some(token);
+        }
+
+        Ok(config)
+    }
+
+    pub fn from_json(contents: &str) -> Result<Self, ClientError> {
+        let config: Self =
+            serde_json::from_str(contents).map_err(|_| ClientError::InvalidConfiguration)?;
+        if config.upload_limit == Some(0) || config.download_limit == Some(0) {
+            return Err(ClientError::InvalidConfiguration);
+        }
+
+        Ok(config)
+    }
+
+    pub fn control_token_file(&self) -> PathBuf {
+        self.control_token_file
+            .clone()
+            .unwrap_or_else(|| turent_dir().join(CONTROL_TOKEN_FILE))
+    }
+}
+
+/// `~/.turent`, where the identity and configuration of a client live
+pub fn turent_dir() -> PathBuf {
+    let home = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
+    PathBuf::from(home).join(TURENT_DIR)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Config;
+
+    #[test]
+    fn test_from_json() {
+        let config = Config::from_json(r#"{"discoveryToken": "abcd"}"#).unwrap();
+        assert_eq!(config.discovery_token, Some("abcd".to_string()));
+
+        assert_eq!(Config::from_json("{}").unwrap().discovery_token, None);
+        assert_eq!(
+            Config::from_json(r#"{"uploadLimit": 1048576}"#)
+                .unwrap()
+                .upload_limit,
+            Some(1048576)
+        );
+        assert!(Config::from_json("not json").is_err());
+        assert!(Config::from_json(r#"{"downloadLimit": 0}"#).is_err());
+    }
+}
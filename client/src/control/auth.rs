@@ -0,0 +1,134 @@
+use std::{fs, path::Path};
+
+use actix_web::http::header::{self, HeaderMap};
+use ring::{
+    constant_time::verify_slices_are_equal,
+    rand::{SecureRandom, SystemRandom},
+};
+use tracing::info;
+
+use crate::errors::ClientError;
+
+const TOKEN_LEN: usize = 32;
+// Names of this host, whatever port they come with
+const LOOPBACK_HOSTS: [&str; 3] = ["localhost", "127.0.0.1", "[::1]"];
+
+/// The bearer token every request to the control API carries, kept in a file only the
+/// user can read so that other users and web pages can't drive the daemon
+#[derive(Clone)]
+pub struct ControlToken {
+    token: String,
+}
+
+impl ControlToken {
+    /// Loads the token from `path`, generating it on first use
+    pub fn load_or_generate(path: &Path) -> Result<Self, ClientError> {
+        if let Ok(token) = fs::read_to_string(path) {
+            return Ok(Self {
+                token: token.trim().to_string(),
+            });
+        }
+
+        let mut bytes = [0; TOKEN_LEN];
+        SystemRandom::new()
+            .fill(&mut bytes)
+            .map_err(|_| ClientError::InvalidConfiguration)?;
+        let token = hex::encode(bytes);
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|err| ClientError::ErrWritingFile(err.to_string()))?;
+        }
+        fs::write(path, &token).map_err(|err| ClientError::ErrWritingFile(err.to_string()))?;
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+                .map_err(|err| ClientError::ErrWritingFile(err.to_string()))?;
+        }
+
+        info!(?path, "Generated control API token");
+
+        Ok(Self { token })
+    }
+
+    /// Requests need the `Authorization: Bearer <token>` header, and a `Host` naming this
+    /// host so that pages rebinding their own domain to 127.0.0.1 are turned away
+    pub fn authorize(&self, headers: &HeaderMap) -> Result<(), ClientError> {
+        let host = headers
+            .get(header::HOST)
+            .and_then(|host| host.to_str().ok());
+        if !host.is_some_and(is_loopback) {
+            return Err(ClientError::ControlHostRejected);
+        }
+
+        let token = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|header| header.to_str().ok())
+            .and_then(|header| header.strip_prefix("Bearer "))
+            .ok_or(ClientError::ControlUnauthorized)?;
+
+        verify_slices_are_equal(token.as_bytes(), self.token.as_bytes())
+            .map_err(|_| ClientError::ControlUnauthorized)
+    }
+}
+
+fn is_loopback(host: &str) -> bool {
+    // The port follows the last colon, unless that colon is inside an IPv6 address
+    let name = match host.rfind(':') {
+        Some(colon) if !host[colon..].contains(']') => &host[..colon],
+        _ => host,
+    };
+
+    LOOPBACK_HOSTS.contains(&name)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::{is_loopback, ControlToken};
+
+    #[test]
+    fn test_is_loopback() {
+        assert!(is_loopback("localhost"));
+        assert!(is_loopback("localhost:8082"));
+        assert!(is_loopback("127.0.0.1:8082"));
+        assert!(is_loopback("[::1]:8082"));
+        assert!(is_loopback("[::1]"));
+
+        assert!(!is_loopback("evil.example:8082"));
+        assert!(!is_loopback("localhost.evil.example"));
+        assert!(!is_loopback("192.168.1.2:8082"));
+    }
+
+    #[test]
+    fn test_authorize() {
+        let token = ControlToken {
+            token: "abcd".to_string(),
+        };
+
+        let req = TestRequest::default()
+            .insert_header(("Host", "127.0.0.1:8082"))
+            .insert_header(("Authorization", "Bearer abcd"))
+            .to_http_request();
+        assert!(token.authorize(req.headers()).is_ok());
+
+        let req = TestRequest::default()
+            .insert_header(("Host", "127.0.0.1:8082"))
+            .insert_header(("Authorization", "Bearer abce"))
+            .to_http_request();
+        assert!(token.authorize(req.headers()).is_err());
+
+        let req = TestRequest::default()
+            .insert_header(("Host", "127.0.0.1:8082"))
+            .to_http_request();
+        assert!(token.authorize(req.headers()).is_err());
+
+        let req = TestRequest::default()
+            .insert_header(("Host", "evil.example:8082"))
+            .insert_header(("Authorization", "Bearer abcd"))
+            .to_http_request();
+        assert!(token.authorize(req.headers()).is_err());
+    }
+}
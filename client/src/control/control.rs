@@ -0,0 +1,307 @@
+use actix_web::{delete, get, post, put, web, HttpResponse};
+use common::entities::FileCompleteness;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::{
+    code::parse_code,
+    engine::{AppState, Engine, ShareAccess, DEFAULT_SHARE_EXPIRY_SECS},
+    errors::{ApiError, ClientError},
+    protocol::TransferReport,
+};
+
+const DEFAULT_DOWNLOAD_DIR: &str = "received";
+
+/// A file being served or downloaded, as the control API lists it
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct TransferStatus {
+    #[serde(rename = "progress")]
+    pub progress: FileCompleteness,
+    #[serde(rename = "report")]
+    pub report: TransferReport,
+    #[serde(rename = "paused")]
+    pub paused: bool,
+    // Bytes per second, on top of the limits of the client
+    #[serde(rename = "limit")]
+    pub limit: Option<u64>,
+}
+
+/// Bytes per second shared by every upload, and every download, unlimited when None.
+/// A limit of 0 is refused, stopping transfers is what pausing them is for
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct Limits {
+    #[serde(rename = "uploadLimit", default)]
+    pub upload_limit: Option<u64>,
+    #[serde(rename = "downloadLimit", default)]
+    pub download_limit: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct LimitReq {
+    #[serde(rename = "limit", default)]
+    pub limit: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ShareReq {
+    #[serde(rename = "path")]
+    pub path: String,
+    #[serde(rename = "passphrase", default)]
+    pub passphrase: Option<String>,
+    #[serde(rename = "withCode", default)]
+    pub with_code: bool,
+    #[serde(rename = "private", default)]
+    pub private: bool,
+    #[serde(rename = "expiresInSecs", default)]
+    pub expires_in_secs: Option<u64>,
+    #[serde(rename = "maxDownloads", default)]
+    pub max_downloads: Option<u32>,
+    #[serde(rename = "limit", default)]
+    pub limit: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct DownloadReq {
+    // A file id or a short code
+    #[serde(rename = "file")]
+    pub file: String,
+    #[serde(rename = "downloadDir", default)]
+    pub download_dir: Option<String>,
+    #[serde(rename = "passphrase", default)]
+    pub passphrase: Option<String>,
+    #[serde(rename = "shareToken", default)]
+    pub share_token: Option<String>,
+    #[serde(rename = "limit", default)]
+    pub limit: Option<u64>,
+}
+
+/// Every file being served or downloaded
+#[get("/transfers")]
+pub async fn transfers(data: web::Data<AppState>) -> Result<HttpResponse, ClientError> {
+    let engine = data.engine.lock().await;
+
+    Ok(HttpResponse::Ok().json(engine.transfers()?))
+}
+
+/// The transfers of one file, both ways when it's downloaded and seeded
+#[get("/transfers/{file_id}")]
+pub async fn transfer(
+    file_id: web::Path<String>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ClientError> {
+    let file_id = parse_file_id(&file_id)?;
+    let engine = data.engine.lock().await;
+
+    let of_file: Vec<TransferStatus> = engine
+        .transfers()?
+        .into_iter()
+        .filter(|transfer| transfer.report.file_id == file_id.to_string())
+        .collect();
+    if of_file.is_empty() {
+        return Err(ClientError::TransferNotFound);
+    }
+
+    Ok(HttpResponse::Ok().json(of_file))
+}
+
+#[post("/shares")]
+pub async fn add_share(
+    req: web::Json<ShareReq>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ClientError> {
+    let req = req.into_inner();
+    let access = if req.private {
+        Some(ShareAccess {
+            expires_in_secs: req.expires_in_secs.unwrap_or(DEFAULT_SHARE_EXPIRY_SECS),
+            max_downloads: req.max_downloads,
+        })
+    } else {
+        None
+    };
+
+    let shared = Engine::share(
+        &data.engine,
+        Uuid::new_v4(),
+        req.path,
+        req.passphrase,
+        req.with_code,
+        access,
+        check_limit(req.limit)?,
+    )
+    .await?;
+
+    Ok(HttpResponse::Ok().json(shared))
+}
+
+#[post("/downloads")]
+pub async fn add_download(
+    req: web::Json<DownloadReq>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ClientError> {
+    let req = req.into_inner();
+
+    // A code is the passphrase as well, unless another one is given
+    let (file_id, passphrase) = match Uuid::parse_str(&req.file) {
+        Ok(file_id) => (file_id, req.passphrase),
+        Err(_) if parse_code(&req.file).is_some() => (
+            Engine::resolve_code(&data.engine, &req.file).await?,
+            req.passphrase.or(Some(req.file)),
+        ),
+        Err(_) => return Err(ClientError::ApiError(ApiError::InvalidIdFormat)),
+    };
+
+    let download_dir = req
+        .download_dir
+        .unwrap_or_else(|| DEFAULT_DOWNLOAD_DIR.to_string());
+    Engine::fetch(
+        &data.engine,
+        file_id,
+        download_dir,
+        passphrase,
+        req.share_token,
+        check_limit(req.limit)?,
+    )
+    .await?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "fileId": file_id.to_string(),
+        "success": true,
+    })))
+}
+
+#[post("/downloads/{file_id}/pause")]
+pub async fn pause_download(
+    file_id: web::Path<String>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ClientError> {
+    let file_id = parse_file_id(&file_id)?;
+    data.engine.lock().await.pause_download(file_id).await?;
+
+    Ok(success())
+}
+
+#[post("/downloads/{file_id}/resume")]
+pub async fn resume_download(
+    file_id: web::Path<String>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ClientError> {
+    let file_id = parse_file_id(&file_id)?;
+    data.engine.lock().await.resume_download(file_id).await?;
+
+    Ok(success())
+}
+
+/// Stops a download, closing the connections to its sources
+#[delete("/downloads/{file_id}")]
+pub async fn cancel_download(
+    file_id: web::Path<String>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ClientError> {
+    let file_id = parse_file_id(&file_id)?;
+    data.engine.lock().await.cancel_download(file_id).await?;
+
+    Ok(success())
+}
+
+#[post("/shares/{file_id}/pause")]
+pub async fn pause_share(
+    file_id: web::Path<String>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ClientError> {
+    let file_id = parse_file_id(&file_id)?;
+    data.engine.lock().await.pause_share(file_id).await?;
+
+    Ok(success())
+}
+
+#[post("/shares/{file_id}/resume")]
+pub async fn resume_share(
+    file_id: web::Path<String>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ClientError> {
+    let file_id = parse_file_id(&file_id)?;
+    data.engine.lock().await.resume_share(file_id).await?;
+
+    Ok(success())
+}
+
+/// Stops serving a file, closing the connections to its sinks
+#[delete("/shares/{file_id}")]
+pub async fn cancel_share(
+    file_id: web::Path<String>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ClientError> {
+    let file_id = parse_file_id(&file_id)?;
+    data.engine.lock().await.cancel_share(file_id).await?;
+
+    Ok(success())
+}
+
+#[get("/limits")]
+pub async fn limits(data: web::Data<AppState>) -> Result<HttpResponse, ClientError> {
+    Ok(HttpResponse::Ok().json(data.engine.lock().await.limits()))
+}
+
+/// Replaces both limits, a missing one is lifted
+#[put("/limits")]
+pub async fn set_limits(
+    req: web::Json<Limits>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ClientError> {
+    let req = req.into_inner();
+    check_limit(req.upload_limit)?;
+    check_limit(req.download_limit)?;
+
+    let engine = data.engine.lock().await;
+    engine.set_limits(req);
+
+    Ok(HttpResponse::Ok().json(engine.limits()))
+}
+
+#[put("/downloads/{file_id}/limit")]
+pub async fn set_download_limit(
+    file_id: web::Path<String>,
+    req: web::Json<LimitReq>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ClientError> {
+    let file_id = parse_file_id(&file_id)?;
+    data.engine
+        .lock()
+        .await
+        .set_download_limit(file_id, check_limit(req.limit)?)?;
+
+    Ok(success())
+}
+
+#[put("/shares/{file_id}/limit")]
+pub async fn set_share_limit(
+    file_id: web::Path<String>,
+    req: web::Json<LimitReq>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ClientError> {
+    let file_id = parse_file_id(&file_id)?;
+    data.engine
+        .lock()
+        .await
+        .set_share_limit(file_id, check_limit(req.limit)?)?;
+
+    Ok(success())
+}
+
+fn success() -> HttpResponse {
+    HttpResponse::Ok().json(json!({
+        "success": true,
+    }))
+}
+
+fn check_limit(limit: Option<u64>) -> Result<Option<u64>, ClientError> {
+    match limit {
+        Some(0) => Err(ClientError::InvalidLimit),
+        limit => Ok(limit),
+    }
+}
+
+fn parse_file_id(file_id: &str) -> Result<Uuid, ClientError> {
+    Uuid::parse_str(file_id).map_err(|_| ClientError::ApiError(ApiError::InvalidIdFormat))
+}
@@ -0,0 +1,4 @@
+pub use auth::ControlToken;
+pub use control::*;
+mod auth;
+mod control;
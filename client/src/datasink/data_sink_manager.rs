@@ -1,35 +1,126 @@
-use crate::{api::Api, errors::ClientError};
-use common::{entities::ServerInfo, logger::Logger};
+use std::{collections::HashMap, sync::Arc};
+
+use crate::{
+    api::Api,
+    config::Config,
+    control::TransferStatus,
+    errors::ClientError,
+    file::{PieceStore, SharedPieces},
+    ice::{deployment_servers, merge_servers, ClosedTx, IceConfig},
+    identity::Identity,
+    protocol::{RateLimiter, Throttle},
+};
+use common::entities::ServerInfo;
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::warn;
 use uuid::Uuid;
-use webrtc::ice_transport::ice_candidate::RTCIceCandidate;
 
-use super::datasink::DataSink;
+use super::{
+    datasink::{send_all, DataSink},
+    download::{Download, SharedDownload, StartedDownload},
+};
 
 pub struct DataSinkManager {
-    data_sinks: Vec<DataSink>,
-    logger: Logger,
+    url: String,
+    // Shared with the signaling handlers, which use them without the engine locked
+    data_sinks: Vec<Arc<DataSink>>,
+    // Every data sink of a file feeds the same download
+    downloads: HashMap<Uuid, SharedDownload>,
+    downloads_tx: Option<UnboundedSender<StartedDownload>>,
+    identity: Identity,
+    config: Config,
+    // Shared by every download
+    download_limiter: Arc<RateLimiter>,
+    closed_tx: ClosedTx,
+}
+
+/// What new data sinks are set up with, cloned out of the manager so the engine doesn't
+/// stay locked while they connect to their source
+#[derive(Clone)]
+pub struct SinkSetup {
+    url: String,
+    identity: Identity,
+    config: Config,
+    closed_tx: ClosedTx,
+}
+
+impl SinkSetup {
+    /// A data sink connected to the source at `server_info`, feeding `download`
+    pub async fn data_sink(
+        &self,
+        api: &Api,
+        file_id: Uuid,
+        server_info: ServerInfo,
+        download: SharedDownload,
+    ) -> Result<DataSink, ClientError> {
+        // The source registered the servers it can be reached through
+        let registered = server_info
+            .ice_servers
+            .iter()
+            .cloned()
+            .map(|ice_server| ice_server.to_rtc_ice_server())
+            .collect();
+        let mut ice_config = IceConfig::from_config(&self.config);
+        ice_config.ice_servers = merge_servers(ice_config.ice_servers, registered);
+        ice_config.ice_servers.extend(deployment_servers(api).await);
+
+        //Create new data sink
+        let mut data_sink = DataSink::new(
+            file_id,
+            server_info,
+            self.url.clone(),
+            download,
+            &self.identity,
+            ice_config,
+            self.closed_tx.clone(),
+        )
+        .await?;
+
+        if let Err(err) = data_sink.init(api).await {
+            // Nothing else would ever close it
+            close(data_sink).await;
+            return Err(err);
+        }
+
+        Ok(data_sink)
+    }
 }
 
 impl DataSinkManager {
-    pub fn new(logger: Logger) -> Result<DataSinkManager, ClientError> {
+    pub fn new(
+        url: String,
+        downloads_tx: Option<UnboundedSender<StartedDownload>>,
+        identity: Identity,
+        config: Config,
+        closed_tx: ClosedTx,
+    ) -> Result<DataSinkManager, ClientError> {
         Ok(Self {
+            url,
             data_sinks: vec![],
-            logger,
+            downloads: HashMap::new(),
+            downloads_tx,
+            identity,
+            download_limiter: RateLimiter::new(config.download_limit).shared(),
+            config,
+            closed_tx,
         })
     }
 
     pub async fn new_data_sink(
         &mut self,
         file_id: Uuid,
+        download_dir: String,
+        passphrase: Option<String>,
+        limit: Option<u64>,
         server_info: ServerInfo,
         api: &Api,
     ) -> Result<(), ClientError> {
-        //Create new data sink
-        let mut data_sink = DataSink::new(file_id, server_info, self.logger.clone()).await?;
-
-        data_sink.init(api).await?;
-
-        self.data_sinks.push(data_sink);
+        let download = self.start_download(file_id, download_dir, passphrase, limit);
+        let data_sink = self
+            .setup()
+            .data_sink(api, file_id, server_info, download)
+            .await?;
+        self.add_data_sink(data_sink).await?;
 
         // let (server_id, server_info) = discovery
         //     .get_server_by_file_id(file_id)
@@ -46,19 +137,178 @@ impl DataSinkManager {
         Ok(())
     }
 
-    pub async fn add_ice_candidate(
-        &self,
-        id: Uuid,
-        candidate: RTCIceCandidate,
-    ) -> Result<(), ClientError> {
-        for ds in &self.data_sinks {
-            if ds.id == id {
-                return ds.add_ice_candidate(candidate).await;
+    pub fn setup(&self) -> SinkSetup {
+        SinkSetup {
+            url: self.url.clone(),
+            identity: self.identity.clone(),
+            config: self.config.clone(),
+            closed_tx: self.closed_tx.clone(),
+        }
+    }
+
+    /// The download of `file_id`, which is started unless it already was
+    pub fn start_download(
+        &mut self,
+        file_id: Uuid,
+        download_dir: String,
+        passphrase: Option<String>,
+        limit: Option<u64>,
+    ) -> SharedDownload {
+        let downloads_tx = self.downloads_tx.clone();
+        let download_limiter = Arc::clone(&self.download_limiter);
+        self.downloads
+            .entry(file_id)
+            .or_insert_with(|| {
+                let pieces = PieceStore::new(download_dir, file_id).shared();
+                let throttle = Throttle::new(limit, download_limiter);
+
+                Download::new(file_id, pieces, passphrase, throttle, downloads_tx).shared()
+            })
+            .clone()
+    }
+
+    /// Starts requesting pieces from a data sink which connected to its source. It's
+    /// closed if its download was cancelled while it connected
+    pub async fn add_data_sink(&mut self, data_sink: DataSink) -> Result<(), ClientError> {
+        let download = match self.downloads.get(&data_sink.file_id()) {
+            Some(x) => x,
+            None => {
+                close(data_sink).await;
+                return Err(ClientError::TransferNotFound);
             }
+        };
+
+        // Only sources which could be reached get pieces requested from them
+        download
+            .lock()
+            .map_err(|_| ClientError::ErrAccessingPieces)?
+            .add_peer(data_sink.id, data_sink.channel());
+
+        self.data_sinks.push(Arc::new(data_sink));
+
+        Ok(())
+    }
+
+    /// The data sink a source sends candidates for
+    pub fn data_sink(&self, id: Uuid) -> Option<Arc<DataSink>> {
+        self.data_sinks
+            .iter()
+            .find(|data_sink| data_sink.id == id)
+            .cloned()
+    }
+
+    /// Drops a data sink whose peer connection closed
+    pub fn remove_data_sink(&mut self, id: Uuid) {
+        self.data_sinks.retain(|data_sink| data_sink.id != id);
+    }
+
+    /// Every download, however far along it is
+    pub fn transfers(&self) -> Result<Vec<TransferStatus>, ClientError> {
+        self.downloads
+            .values()
+            .map(|download| {
+                let download = download
+                    .lock()
+                    .map_err(|_| ClientError::ErrAccessingPieces)?;
+
+                Ok(TransferStatus {
+                    progress: download.completeness()?,
+                    report: download.report(),
+                    paused: download.is_paused(),
+                    limit: download.throttle().limit(),
+                })
+            })
+            .collect()
+    }
+
+    pub fn download_limit(&self) -> Option<u64> {
+        self.download_limiter.rate()
+    }
+
+    /// Bytes per second every download shares, at most
+    pub fn set_download_limit(&self, limit: Option<u64>) {
+        self.download_limiter.set_rate(limit);
+    }
+
+    pub fn set_transfer_limit(&self, file_id: Uuid, limit: Option<u64>) -> Result<(), ClientError> {
+        self.download(file_id)?
+            .lock()
+            .map_err(|_| ClientError::ErrAccessingPieces)?
+            .throttle()
+            .set_limit(limit);
+
+        Ok(())
+    }
+
+    /// Stops requesting pieces of `file_id` until it's resumed
+    pub async fn pause_download(&self, file_id: Uuid) -> Result<(), ClientError> {
+        let outgoing = self
+            .download(file_id)?
+            .lock()
+            .map_err(|_| ClientError::ErrAccessingPieces)?
+            .pause();
+        send_all(outgoing).await;
+
+        Ok(())
+    }
+
+    pub async fn resume_download(&self, file_id: Uuid) -> Result<(), ClientError> {
+        let outgoing = self
+            .download(file_id)?
+            .lock()
+            .map_err(|_| ClientError::ErrAccessingPieces)?
+            .resume()?;
+        send_all(outgoing).await;
+
+        Ok(())
+    }
+
+    /// Drops the download of `file_id` along with the pieces received so far and closes
+    /// the connections to its sources. Returns the pieces, anything seeding them has to
+    /// go as well.
+    pub async fn cancel_download(&mut self, file_id: Uuid) -> Result<SharedPieces, ClientError> {
+        let download = self
+            .downloads
+            .remove(&file_id)
+            .ok_or(ClientError::TransferNotFound)?;
+        let (outgoing, pieces) = {
+            let download = download
+                .lock()
+                .map_err(|_| ClientError::ErrAccessingPieces)?;
+            (download.stop(), download.pieces())
+        };
+        send_all(outgoing).await;
+
+        let (cancelled, data_sinks) = self
+            .data_sinks
+            .drain(..)
+            .partition(|data_sink| data_sink.file_id() == file_id);
+        self.data_sinks = data_sinks;
+
+        for data_sink in cancelled {
+            data_sink.close().await?;
         }
 
-        Err(ClientError::ClientWithGivenIdNotFound)
+        // Only the partial download was written to disk so far
+        pieces
+            .lock()
+            .map_err(|_| ClientError::ErrAccessingPieces)?
+            .discard();
+
+        Ok(pieces)
+    }
+
+    fn download(&self, file_id: Uuid) -> Result<&SharedDownload, ClientError> {
+        self.downloads
+            .get(&file_id)
+            .ok_or(ClientError::TransferNotFound)
     }
 
     // pub async fn connect_to_data_source(&self, api: &Api) -> Result<(), ClientError> {}
 }
+
+async fn close(data_sink: DataSink) {
+    if let Err(err) = data_sink.close().await {
+        warn!(?err, "Error closing data sink");
+    }
+}
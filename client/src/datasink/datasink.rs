@@ -1,46 +1,55 @@
 use std::sync::{
     atomic::{AtomicUsize, Ordering},
-    Arc, Mutex,
+    Arc,
 };
 
 use common::{
     entities::{ClientInfo, ServerInfo},
-    logger::Logger,
     models::{CandidateReq, OfferReq},
 };
+use tracing::{debug, info, info_span, warn, Instrument, Span};
 use uuid::Uuid;
 use webrtc::{
     api::{
         interceptor_registry::register_default_interceptors, media_engine::MediaEngine, APIBuilder,
     },
-    data_channel::{data_channel_message::DataChannelMessage, RTCDataChannel},
-    ice_transport::{
-        ice_candidate::RTCIceCandidate, ice_connection_state::RTCIceConnectionState,
-        ice_server::RTCIceServer,
-    },
+    data_channel::data_channel_message::DataChannelMessage,
+    ice_transport::{ice_candidate::RTCIceCandidate, ice_connection_state::RTCIceConnectionState},
     interceptor::registry::Registry,
-    peer_connection::{
-        configuration::RTCConfiguration, peer_connection_state::RTCPeerConnectionState,
-        RTCPeerConnection,
-    },
+    peer_connection::{peer_connection_state::RTCPeerConnectionState, RTCPeerConnection},
 };
 
-use crate::{api::Api, errors::ClientError, file::File};
+use crate::{
+    api::Api,
+    errors::ClientError,
+    file::build_file,
+    ice::{on_state_change, report_candidate_pair, watch, Activity, ClosedTx, IceConfig},
+    identity::{check_fingerprint, Identity},
+    protocol::{Channel, ChannelCrypto, Compression, Message, Received, Role},
+};
+
+use super::download::{Outgoing, SharedDownload};
 
 pub struct DataSink {
     pub id: Uuid,
     file_id: Uuid,
+    url: String,
     peer_connection: Arc<RTCPeerConnection>,
-    data_channel: Arc<RTCDataChannel>,
+    channel: Channel,
     server_info: ServerInfo,
-    logger: Logger,
+    // Everything logged about this peer connection is in it
+    span: Span,
 }
 
 impl DataSink {
     pub async fn new(
         file_id: Uuid,
         server_info: ServerInfo,
-        logger: Logger,
+        url: String,
+        download: SharedDownload,
+        identity: &Identity,
+        ice_config: IceConfig,
+        closed_tx: ClosedTx,
     ) -> Result<DataSink, ClientError> {
         let mut m = MediaEngine::default();
         m.register_default_codecs()
@@ -54,25 +63,24 @@ impl DataSink {
         let api = APIBuilder::new()
             .with_media_engine(m)
             .with_interceptor_registry(registry)
+            .with_setting_engine(ice_config.setting_engine())
             .build();
 
+        let id = Uuid::new_v4();
+        let span = info_span!(
+            "peer",
+            role = "sink",
+            %id,
+            %file_id,
+            server_id = %server_info.id
+        );
+
+        if ice_config.relay_only && !ice_config.can_relay() {
+            span.in_scope(|| warn!("Relay only, but there is no TURN server to relay through"));
+        }
+
         // Prepare the configuration
-        let config = RTCConfiguration {
-            ice_servers: vec![RTCIceServer {
-                urls: vec!["stun:stun.l.google.com:19302".to_owned()],
-                ..Default::default()
-            }],
-            ..Default::default()
-        };
-        // RTCIceServer {
-        //     urls: vec![
-        //         "turn:turn.dyte.in:443?transport=tcp".to_owned(),
-        //         "turn:turn.dyte.in:3478?transport=udp".to_owned(),
-        //     ],
-        //     username: "dyte".to_string(),
-        //     credential: "dytein".to_string(),
-        //     ..Default::default()
-        // },
+        let config = ice_config.rtc_configuration(identity.certificate()?);
 
         //Make peer connection
         let peer_connection = Arc::new(
@@ -80,31 +88,43 @@ impl DataSink {
                 .await
                 .map_err(|err| ClientError::WebRTCError(err))?,
         );
+        // Every source of a download adds the pair it's reached through to its report
+        let stats = download
+            .lock()
+            .map_err(|_| ClientError::ErrAccessingPieces)?
+            .stats();
+        report_candidate_pair(&peer_connection, span.clone(), stats).await;
 
         //Register on_peer_connection_state_change
 
         // Set the handler for Peer connection state
         // This will notify you when the peer has connected/disconnected
+        let span2 = span.clone();
+        let pc = Arc::downgrade(&peer_connection);
+        let timeouts = ice_config.timeouts;
         peer_connection
             .on_peer_connection_state_change(Box::new(move |s: RTCPeerConnectionState| {
-                println!("Peer Connection State has changed, datasink: {}", s);
+                span2.in_scope(|| {
+                    info!(state = %s, "Peer connection state changed");
+                    on_state_change(s, &pc, id, timeouts, &closed_tx);
+                });
 
                 Box::pin(async {})
             }))
             .await;
 
+        let span2 = span.clone();
         peer_connection
-            .on_ice_connection_state_change(Box::new(|connection_state: RTCIceConnectionState| {
-                println!(
-                    "ICE Connection State has changed, datasink: {}",
-                    connection_state
-                );
-                Box::pin(async {})
-            }))
+            .on_ice_connection_state_change(Box::new(
+                move |connection_state: RTCIceConnectionState| {
+                    span2.in_scope(
+                        || info!(state = %connection_state, "ICE connection state changed"),
+                    );
+                    Box::pin(async {})
+                },
+            ))
             .await;
 
-        let id = Uuid::new_v4();
-
         //TODO: Change this label later
         //Create data channel
         let dc = peer_connection
@@ -112,56 +132,104 @@ impl DataSink {
             .await
             .map_err(|err| ClientError::WebRTCError(err))?;
 
-        let total_bytes_received = Arc::new(AtomicUsize::new(0));
-        let file = Arc::new(Mutex::new(File::new(
-            "/Users/feniljain/Projects/rust-projects/turent".to_string(),
-            "example_file_received.txt".to_string(),
-        )));
+        let passphrase = download
+            .lock()
+            .map_err(|_| ClientError::ErrAccessingPieces)?
+            .passphrase();
+        let channel = Channel::new(
+            Arc::clone(&dc),
+            ChannelCrypto::new(passphrase.as_deref(), file_id, Role::Sink),
+        );
+        let transfer_span = info_span!(parent: &span, "transfer", channel = %dc.label());
 
-        let file2 = Arc::clone(&file);
+        let total_bytes_received = Arc::new(AtomicUsize::new(0));
+        let activity = Arc::new(Activity::new());
+        let download2 = Arc::clone(&download);
+        let channel2 = channel.clone();
+        let activity2 = Arc::clone(&activity);
+        let span2 = transfer_span.clone();
         dc.on_message(Box::new(move |msg: DataChannelMessage| {
             let n = msg.data.len();
             total_bytes_received.fetch_add(n, Ordering::SeqCst);
-            if let Ok(mut f) = file2.lock() {
-                f.append_bytes(msg.data);
-            }
-            // file3.append_bytes(msg.data);
-            println!("Received message!: {:?}", n);
-            Box::pin(async {})
+            activity2.touch();
+
+            let download2 = Arc::clone(&download2);
+            let channel2 = channel2.clone();
+            Box::pin(
+                async move {
+                    if let Err(err) = on_message(id, &channel2, download2, msg).await {
+                        warn!(?err, "Error handling message");
+                    }
+                }
+                .instrument(span2.clone()),
+            )
         }))
         .await;
 
-        let file2 = Arc::clone(&file);
+        let download2 = Arc::clone(&download);
+        let span2 = transfer_span.clone();
         dc.on_close(Box::new(move || {
-            println!("Data Channel closing!");
-            if let Ok(f) = file2.lock() {
-                match f.build_file() {
-                    Ok(_) => {}
-                    Err(err) => {
-                        println!("Error while building file: {:?}", err);
+            span2.in_scope(|| info!("Data channel closed"));
+            let download2 = Arc::clone(&download2);
+            Box::pin(
+                async move {
+                    // Whatever was requested from this source has to come from the others now
+                    let outgoing = match download2.lock() {
+                        Ok(mut download) => download.remove_peer(id),
+                        Err(_) => Err(ClientError::ErrAccessingPieces),
+                    };
+                    match outgoing {
+                        Ok(outgoing) => send_all(outgoing).await,
+                        Err(err) => warn!(?err, "Error removing peer"),
                     }
                 }
-            }
-            Box::pin(async {})
+                .instrument(span2.clone()),
+            )
         }))
         .await;
 
-        let d1 = Arc::clone(&dc);
+        let channel2 = channel.clone();
+        let span2 = transfer_span;
         dc.on_open(Box::new(move || {
-            println!("Data channel '{}'-'{}' open", d1.label(), d1.id());
-
-            // let d2 = Arc::clone(&d1);
-            Box::pin(async move {})
+            span2.in_scope(|| info!("Data channel open"));
+
+            Box::pin(
+                async move {
+                    // With a passphrase, hello waits for the handshake to finish
+                    match channel2.start().await {
+                        Ok(true) => send_hello(&channel2).await,
+                        Ok(false) => {}
+                        Err(err) => warn!(?err, "Error starting handshake"),
+                    }
+                }
+                .instrument(span2.clone()),
+            )
         }))
         .await;
 
+        // Sources which never connect, or stop sending what was requested, are let go
+        let download2 = Arc::clone(&download);
+        tokio::spawn(
+            watch(
+                Arc::downgrade(&peer_connection),
+                ice_config.timeouts,
+                activity,
+                move || match download2.lock() {
+                    Ok(download) => download.is_waiting_on(id),
+                    Err(_) => false,
+                },
+            )
+            .instrument(span.clone()),
+        );
+
         Ok(Self {
             id,
             file_id,
+            url,
             peer_connection,
-            data_channel: dc,
+            channel,
             server_info,
-            logger,
+            span,
         })
     }
 
@@ -180,13 +248,12 @@ impl DataSink {
 
         // let _ = gather_complete.recv().await;
 
-        //TODO: Later remove this harcoded URL
         let res = api
             .send_offer(
                 self.server_info.url.clone(),
                 OfferReq {
                     client_info: ClientInfo {
-                        url: String::from("http://localhost:8081"),
+                        url: self.url.clone(),
                         id: self.id.to_string(),
                     },
                     server_id: self.server_info.id.clone(),
@@ -202,47 +269,70 @@ impl DataSink {
         //         .map_err(|err| ClientError::WebRTCError(err))?,
         // );
 
+        // Whoever answers has to be the server which registered, not just anyone able to
+        // tamper with signalling
+        check_fingerprint(&res.session_desc.sdp, &self.server_info.fingerprint)?;
+
         self.peer_connection
             .set_remote_description(res.session_desc)
             .await
             .map_err(|err| ClientError::WebRTCError(err))?;
 
         let server_id = self.server_info.id.clone();
+        let server_url = self.server_info.url.clone();
+        let api = api.clone();
 
         //Register listener for onIceCandidate
+        let span = self.span.clone();
         self.peer_connection
             .on_ice_candidate(Box::new(move |c: Option<RTCIceCandidate>| {
-                println!("on_ice_candidate datasink {:?}", c);
+                span.in_scope(|| debug!(candidate = ?c, "Gathered candidate"));
                 let server_id = server_id.clone();
-                let client_api = Api::new();
-
-                //TODO: Remove this hardcoded URL later
-                Box::pin(async move {
-                    if let Some(ice_candidate) = c {
-                        println!("sending req. to server id: {:?}", server_id);
-                        match client_api
-                            .send_candidate(
-                                "http://localhost:8080".to_string(),
-                                CandidateReq {
-                                    id: server_id,
-                                    candidate: ice_candidate,
-                                },
-                            )
-                            .await
-                        {
-                            Ok(_) => println!("Candidate sent from datasink"),
-                            Err(err) => {
-                                println!("Error sending candidate from datasink, err: {:?}", err)
+                let server_url = server_url.clone();
+                let client_api = api.clone();
+
+                Box::pin(
+                    async move {
+                        if let Some(ice_candidate) = c {
+                            match client_api
+                                .send_candidate(
+                                    server_url,
+                                    CandidateReq {
+                                        id: server_id,
+                                        candidate: ice_candidate,
+                                    },
+                                )
+                                .await
+                            {
+                                Ok(_) => debug!("Candidate sent"),
+                                Err(err) => warn!(?err, "Error sending candidate"),
                             }
                         }
                     }
-                })
+                    .instrument(span.clone()),
+                )
             }))
             .await;
 
         Ok(())
     }
 
+    pub fn channel(&self) -> Channel {
+        self.channel.clone()
+    }
+
+    pub fn file_id(&self) -> Uuid {
+        self.file_id
+    }
+
+    pub async fn close(&self) -> Result<(), ClientError> {
+        self.span.in_scope(|| info!("Closing peer connection"));
+        self.peer_connection
+            .close()
+            .await
+            .map_err(|err| ClientError::WebRTCError(err))
+    }
+
     pub async fn add_ice_candidate(&self, candidate: RTCIceCandidate) -> Result<(), ClientError> {
         // self.peer_connection
         //     .add_ice_candidate(RTCIceCandidateInit {
@@ -267,3 +357,105 @@ impl DataSink {
 
     // pub fn disconnect_from_server(&self) {}
 }
+
+async fn on_message(
+    id: Uuid,
+    channel: &Channel,
+    download: SharedDownload,
+    msg: DataChannelMessage,
+) -> Result<(), ClientError> {
+    let wire_bytes = msg.data.len();
+    let message = match channel.receive(msg.data).await {
+        Ok(Received::Message(message)) => message,
+        Ok(Received::Ready) => {
+            send_hello(channel).await;
+            return Ok(());
+        }
+        Ok(Received::Nothing) => return Ok(()),
+        // A source which doesn't know the passphrase is of no use
+        Err(err @ ClientError::ErrWrongPassphrase) | Err(err @ ClientError::ErrNotEncrypted) => {
+            if let Err(err) = channel.close().await {
+                warn!(?err, "Error closing data channel");
+            }
+            return Err(err);
+        }
+        Err(err) => return Err(err),
+    };
+
+    // Closing the channel hands whatever was requested from this source to the others
+    if message == Message::Stop {
+        info!("Transfer stopped by the source");
+        return channel.close().await;
+    }
+
+    let (outgoing, channels, complete, throttle) = {
+        let mut download = download
+            .lock()
+            .map_err(|_| ClientError::ErrAccessingPieces)?;
+
+        if let Message::Piece { data, .. } = &message {
+            download.stats().record(data.len(), wire_bytes);
+        }
+
+        let outgoing = download.handle_message(id, message);
+        let complete = match &outgoing {
+            Ok(_) => download
+                .take_complete()?
+                .map(|pieces| (pieces, download.report())),
+            Err(_) => None,
+        };
+
+        // Every source is done with once the file is complete
+        let mut channels = vec![];
+        if download.is_complete()? {
+            channels = download.channels();
+        }
+
+        (outgoing, channels, complete, download.throttle())
+    };
+
+    let outgoing = match outgoing {
+        Ok(x) => x,
+        // Neither is a source which keeps sending corrupt pieces
+        Err(err @ ClientError::ErrPieceHashMismatch(_)) => {
+            if let Err(err) = channel.close().await {
+                warn!(?err, "Error closing data channel");
+            }
+            return Err(err);
+        }
+        Err(err) => return Err(err),
+    };
+
+    // Holding back the next requests is all a sink can do to receive less
+    throttle.acquire(wire_bytes).await;
+    send_all(outgoing).await;
+
+    if let Some((pieces, report)) = complete {
+        build_file(&pieces).await?;
+        info!(%report, "File downloaded");
+    }
+
+    for channel in channels {
+        channel.close().await?;
+    }
+
+    Ok(())
+}
+
+/// Goes out before any request, so every piece can be compressed
+async fn send_hello(channel: &Channel) {
+    let hello = Message::Hello {
+        compression: Compression::Zstd,
+    };
+    if let Err(err) = channel.send(&hello).await {
+        warn!(?err, "Error sending hello");
+    }
+}
+
+pub(super) async fn send_all(outgoing: Outgoing) {
+    for (channel, message) in outgoing {
+        if let Err(err) = channel.send(&message).await {
+            warn!(?err, "Error sending message");
+        }
+    }
+}
@@ -0,0 +1,451 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use common::entities::FileCompleteness;
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::{
+    errors::ClientError,
+    file::{Manifest, SharedPieces},
+    protocol::{Bitfield, Channel, Message, Throttle, TransferReport, TransferStats},
+};
+
+use super::piece_picker::PiecePicker;
+
+// Pieces requested from a data source without having received them yet
+const MAX_OUTSTANDING_REQUESTS: usize = 16;
+// Corrupt pieces a data source may send before it is given up on
+const MAX_CORRUPT_PIECES: u32 = 3;
+
+pub type SharedDownload = Arc<Mutex<Download>>;
+
+/// Messages to be sent on the data channels of a download's data sinks
+pub type Outgoing = Vec<(Channel, Message)>;
+
+/// A download whose manifest is known, so its pieces can be served while it is still
+/// in progress.
+#[derive(Clone)]
+pub struct StartedDownload {
+    pub file_id: Uuid,
+    pub pieces: SharedPieces,
+    pub passphrase: Option<String>,
+}
+
+struct Peer {
+    channel: Channel,
+    bitfield: Option<Bitfield>,
+    // When each outstanding piece was requested
+    requested: HashMap<u32, Instant>,
+    // Paused by the source, which answers no requests until it resumes
+    paused: bool,
+    corrupt_pieces: u32,
+}
+
+/// A file being downloaded from several data sources at once, each of them connected
+/// to a data sink of its own.
+pub struct Download {
+    file_id: Uuid,
+    pieces: SharedPieces,
+    passphrase: Option<String>,
+    // Only known once the first data source sends the manifest
+    picker: Option<PiecePicker>,
+    peers: HashMap<Uuid, Peer>,
+    downloads_tx: Option<UnboundedSender<StartedDownload>>,
+    stats: Arc<TransferStats>,
+    // Paces the requests, and so what the sources send
+    throttle: Throttle,
+    file_built: bool,
+    paused: bool,
+}
+
+impl Download {
+    pub fn new(
+        file_id: Uuid,
+        pieces: SharedPieces,
+        passphrase: Option<String>,
+        throttle: Throttle,
+        downloads_tx: Option<UnboundedSender<StartedDownload>>,
+    ) -> Self {
+        Self {
+            file_id,
+            pieces,
+            passphrase,
+            picker: None,
+            peers: HashMap::new(),
+            downloads_tx,
+            stats: Arc::new(TransferStats::new()),
+            throttle,
+            file_built: false,
+            paused: false,
+        }
+    }
+
+    pub fn shared(self) -> SharedDownload {
+        Arc::new(Mutex::new(self))
+    }
+
+    pub fn passphrase(&self) -> Option<String> {
+        self.passphrase.clone()
+    }
+
+    pub fn add_peer(&mut self, peer_id: Uuid, channel: Channel) {
+        self.peers.insert(
+            peer_id,
+            Peer {
+                channel,
+                bitfield: None,
+                requested: HashMap::new(),
+                paused: false,
+                corrupt_pieces: 0,
+            },
+        );
+    }
+
+    /// Drops a data source which went away, handing its outstanding requests over to
+    /// the remaining ones
+    pub fn remove_peer(&mut self, peer_id: Uuid) -> Result<Outgoing, ClientError> {
+        let peer = match self.peers.remove(&peer_id) {
+            Some(x) => x,
+            None => return Ok(vec![]),
+        };
+
+        if let Some(picker) = &mut self.picker {
+            if let Some(bitfield) = &peer.bitfield {
+                picker.remove_bitfield(bitfield);
+            }
+            picker.remove_peer(peer_id);
+        }
+
+        let mut outgoing = vec![];
+        let peer_ids: Vec<Uuid> = self.peers.keys().copied().collect();
+        for peer_id in peer_ids {
+            outgoing.extend(self.fill_requests(peer_id)?);
+        }
+
+        Ok(outgoing)
+    }
+
+    pub fn is_complete(&self) -> Result<bool, ClientError> {
+        Ok(self
+            .pieces
+            .lock()
+            .map_err(|_| ClientError::ErrAccessingPieces)?
+            .is_complete())
+    }
+
+    pub fn stats(&self) -> Arc<TransferStats> {
+        Arc::clone(&self.stats)
+    }
+
+    pub fn completeness(&self) -> Result<FileCompleteness, ClientError> {
+        Ok(self
+            .pieces
+            .lock()
+            .map_err(|_| ClientError::ErrAccessingPieces)?
+            .completeness())
+    }
+
+    pub fn report(&self) -> TransferReport {
+        self.stats.report(self.file_id.to_string(), "sink")
+    }
+
+    pub fn pieces(&self) -> SharedPieces {
+        Arc::clone(&self.pieces)
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Whether pieces are expected from `peer_id`, which were requested and neither end
+    /// paused
+    pub fn is_waiting_on(&self, peer_id: Uuid) -> bool {
+        !self.paused
+            && self
+                .peers
+                .get(&peer_id)
+                .is_some_and(|peer| !peer.paused && !peer.requested.is_empty())
+    }
+
+    pub fn throttle(&self) -> Throttle {
+        self.throttle.clone()
+    }
+
+    /// Stops requesting pieces, the ones already requested still get stored
+    pub fn pause(&mut self) -> Outgoing {
+        self.paused = true;
+
+        self.to_every_peer(Message::Pause)
+    }
+
+    pub fn resume(&mut self) -> Result<Outgoing, ClientError> {
+        self.paused = false;
+
+        let mut outgoing = self.to_every_peer(Message::Resume);
+        let peer_ids: Vec<Uuid> = self.peers.keys().copied().collect();
+        for peer_id in peer_ids {
+            // Time spent paused isn't part of the round trip
+            if let Some(peer) = self.peers.get_mut(&peer_id) {
+                for requested_at in peer.requested.values_mut() {
+                    *requested_at = Instant::now();
+                }
+            }
+            outgoing.extend(self.fill_requests(peer_id)?);
+        }
+
+        Ok(outgoing)
+    }
+
+    /// Lets every source know the download is over
+    pub fn stop(&self) -> Outgoing {
+        self.to_every_peer(Message::Stop)
+    }
+
+    fn to_every_peer(&self, message: Message) -> Outgoing {
+        self.peers
+            .values()
+            .map(|peer| (peer.channel.clone(), message.clone()))
+            .collect()
+    }
+
+    /// The pieces of the bundle the first time it's found complete, its files are to be
+    /// written out then
+    pub fn take_complete(&mut self) -> Result<Option<SharedPieces>, ClientError> {
+        if self.file_built || !self.is_complete()? {
+            return Ok(None);
+        }
+        self.file_built = true;
+
+        Ok(Some(Arc::clone(&self.pieces)))
+    }
+
+    pub fn channels(&self) -> Vec<Channel> {
+        self.peers
+            .values()
+            .map(|peer| peer.channel.clone())
+            .collect()
+    }
+
+    /// Handles a message received by the data sink of `peer_id`, returns the messages
+    /// to be sent in response
+    pub fn handle_message(
+        &mut self,
+        peer_id: Uuid,
+        message: Message,
+    ) -> Result<Outgoing, ClientError> {
+        if !self.peers.contains_key(&peer_id) {
+            return Ok(vec![]);
+        }
+
+        let mut outgoing = vec![];
+        let mut peers_to_fill = vec![peer_id];
+
+        match message {
+            Message::Manifest { manifest } => self.init(manifest)?,
+            Message::Bitfield {
+                file_size,
+                bitfield,
+            } => {
+                let known_file_size = self
+                    .pieces
+                    .lock()
+                    .map_err(|_| ClientError::ErrAccessingPieces)?
+                    .file_size();
+                if known_file_size != Some(file_size) {
+                    return Err(ClientError::ErrFileSizeMismatch);
+                }
+
+                if let Some(peer) = self.peers.get_mut(&peer_id) {
+                    // A bitfield sent again replaces the one before, which mustn't count
+                    // towards the availability twice
+                    if let Some(picker) = &mut self.picker {
+                        if let Some(previous) = &peer.bitfield {
+                            picker.remove_bitfield(previous);
+                        }
+                        picker.add_bitfield(&bitfield);
+                    }
+                    peer.bitfield = Some(bitfield);
+                }
+            }
+            Message::Have { piece } => {
+                if let Some(peer) = self.peers.get_mut(&peer_id) {
+                    if let Some(bitfield) = &mut peer.bitfield {
+                        if !bitfield.has(piece as usize) {
+                            bitfield.set(piece as usize);
+                            if let Some(picker) = &mut self.picker {
+                                picker.add_have(piece);
+                            }
+                        }
+                    }
+                }
+            }
+            Message::Piece { piece, data } => {
+                if let Some(peer) = self.peers.get_mut(&peer_id) {
+                    if let Some(requested_at) = peer.requested.remove(&piece) {
+                        self.stats.record_piece_latency(requested_at.elapsed());
+                    }
+                }
+
+                let mut pieces = self
+                    .pieces
+                    .lock()
+                    .map_err(|_| ClientError::ErrAccessingPieces)?;
+                if pieces.bitfield().has(piece as usize) {
+                    self.stats.record_duplicate();
+                }
+                let stored = pieces.put(piece, data);
+                drop(pieces);
+                match stored {
+                    Ok(_) => {}
+                    Err(err @ ClientError::ErrPieceHashMismatch(_)) => {
+                        return self.reject_piece(peer_id, piece, err)
+                    }
+                    Err(err) => return Err(err),
+                }
+
+                // Requests for the same piece made to other sources during endgame are
+                // no longer needed
+                let cancelled_peers = match &mut self.picker {
+                    Some(picker) => picker.received(piece, peer_id),
+                    None => vec![],
+                };
+                for cancelled_peer_id in cancelled_peers {
+                    if let Some(peer) = self.peers.get_mut(&cancelled_peer_id) {
+                        peer.requested.remove(&piece);
+                        outgoing.push((peer.channel.clone(), Message::Cancel { piece }));
+                        peers_to_fill.push(cancelled_peer_id);
+                    }
+                }
+            }
+            Message::Pause => {
+                if let Some(peer) = self.peers.get_mut(&peer_id) {
+                    peer.paused = true;
+                }
+            }
+            Message::Resume => {
+                if let Some(peer) = self.peers.get_mut(&peer_id) {
+                    peer.paused = false;
+                }
+            }
+            // Nothing is served from a sink's own channels, handshakes and fragments are
+            // taken care of by the channels themselves and a stopping source by its data
+            // sink
+            Message::Hello { .. }
+            | Message::Request { .. }
+            | Message::Cancel { .. }
+            | Message::Pake { .. }
+            | Message::Confirm { .. }
+            | Message::Encrypted { .. }
+            | Message::Stop
+            | Message::Fragment { .. } => {}
+        }
+
+        if self.is_complete()? {
+            return Ok(vec![]);
+        }
+
+        for peer_id in peers_to_fill {
+            outgoing.extend(self.fill_requests(peer_id)?);
+        }
+
+        Ok(outgoing)
+    }
+
+    /// Treats `peer_id` as not having a piece it sent corrupted, so the piece gets
+    /// requested from the other sources. Fails with `err` once the source sent too many
+    /// of them to be of any use.
+    fn reject_piece(
+        &mut self,
+        peer_id: Uuid,
+        piece: u32,
+        err: ClientError,
+    ) -> Result<Outgoing, ClientError> {
+        warn!(%peer_id, piece, "Corrupt piece received");
+
+        if let Some(peer) = self.peers.get_mut(&peer_id) {
+            if let Some(picker) = &mut self.picker {
+                picker.failed(piece, peer_id);
+                if let Some(bitfield) = &mut peer.bitfield {
+                    if bitfield.has(piece as usize) {
+                        bitfield.clear(piece as usize);
+                        picker.remove_have(piece);
+                    }
+                }
+            }
+
+            peer.corrupt_pieces += 1;
+            if peer.corrupt_pieces >= MAX_CORRUPT_PIECES {
+                return Err(err);
+            }
+        }
+
+        let mut outgoing = vec![];
+        let peer_ids: Vec<Uuid> = self.peers.keys().copied().collect();
+        for peer_id in peer_ids {
+            outgoing.extend(self.fill_requests(peer_id)?);
+        }
+
+        Ok(outgoing)
+    }
+
+    fn init(&mut self, manifest: Manifest) -> Result<(), ClientError> {
+        let mut pieces = self
+            .pieces
+            .lock()
+            .map_err(|_| ClientError::ErrAccessingPieces)?;
+
+        // Pieces can only be served once it is known how many there are
+        if pieces.set_manifest(manifest)? {
+            if let Some(downloads_tx) = &self.downloads_tx {
+                let started_download = StartedDownload {
+                    file_id: self.file_id,
+                    pieces: Arc::clone(&self.pieces),
+                    passphrase: self.passphrase.clone(),
+                };
+                if downloads_tx.send(started_download).is_err() {
+                    warn!(file_id = %self.file_id, "Error reporting started download");
+                }
+            }
+        }
+
+        if self.picker.is_none() {
+            self.picker = Some(PiecePicker::new(pieces.bitfield().len()));
+        }
+
+        Ok(())
+    }
+
+    fn fill_requests(&mut self, peer_id: Uuid) -> Result<Outgoing, ClientError> {
+        let pieces = self
+            .pieces
+            .lock()
+            .map_err(|_| ClientError::ErrAccessingPieces)?;
+
+        let (picker, peer) = match (&mut self.picker, self.peers.get_mut(&peer_id)) {
+            (Some(picker), Some(peer)) if !self.paused && !peer.paused => (picker, peer),
+            _ => return Ok(vec![]),
+        };
+        let bitfield = match &peer.bitfield {
+            Some(x) => x,
+            None => return Ok(vec![]),
+        };
+
+        let mut outgoing = vec![];
+        while peer.requested.len() < MAX_OUTSTANDING_REQUESTS {
+            match picker.pick(peer_id, bitfield, pieces.bitfield()) {
+                Some(piece) => {
+                    peer.requested.insert(piece, Instant::now());
+                    outgoing.push((peer.channel.clone(), Message::Request { piece }));
+                }
+                None => break,
+            }
+        }
+
+        Ok(outgoing)
+    }
+}
@@ -1,3 +1,6 @@
-pub use data_sink_manager::DataSinkManager;
+pub use data_sink_manager::{DataSinkManager, SinkSetup};
+pub use download::StartedDownload;
 mod data_sink_manager;
 mod datasink;
+mod download;
+mod piece_picker;
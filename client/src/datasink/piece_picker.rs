@@ -0,0 +1,226 @@
+use std::collections::{HashMap, HashSet};
+
+use uuid::Uuid;
+
+use crate::protocol::Bitfield;
+
+/// Decides which piece to request next from which data source. Pieces few sources have
+/// are requested first, so they spread before those sources leave the swarm, and once
+/// every missing piece is in flight the remaining ones are requested from every source
+/// having them (endgame), so a single slow source can't hold up the end of a download.
+pub struct PiecePicker {
+    // Number of sources having each piece
+    availability: Vec<u32>,
+    // Sources every outstanding piece was requested from
+    requested: HashMap<u32, HashSet<Uuid>>,
+}
+
+impl PiecePicker {
+    pub fn new(piece_count: usize) -> Self {
+        Self {
+            availability: vec![0; piece_count],
+            requested: HashMap::new(),
+        }
+    }
+
+    pub fn add_bitfield(&mut self, bitfield: &Bitfield) {
+        for index in 0..self.availability.len() {
+            if bitfield.has(index) {
+                self.availability[index] += 1;
+            }
+        }
+    }
+
+    pub fn remove_bitfield(&mut self, bitfield: &Bitfield) {
+        for index in 0..self.availability.len() {
+            if bitfield.has(index) {
+                self.availability[index] = self.availability[index].saturating_sub(1);
+            }
+        }
+    }
+
+    pub fn add_have(&mut self, piece: u32) {
+        if let Some(availability) = self.availability.get_mut(piece as usize) {
+            *availability += 1;
+        }
+    }
+
+    pub fn remove_have(&mut self, piece: u32) {
+        if let Some(availability) = self.availability.get_mut(piece as usize) {
+            *availability = availability.saturating_sub(1);
+        }
+    }
+
+    /// Picks the next piece to request from `peer`, and records it as requested
+    pub fn pick(&mut self, peer: Uuid, peer_bitfield: &Bitfield, have: &Bitfield) -> Option<u32> {
+        let len = self.availability.len();
+        if len == 0 {
+            return None;
+        }
+
+        // Starting at a random piece keeps sinks from all going after the same one
+        let offset = rand::random::<usize>() % len;
+        let wanted = |index: &usize| peer_bitfield.has(*index) && !have.has(*index);
+
+        let mut piece = (0..len)
+            .map(|i| (offset + i) % len)
+            .filter(wanted)
+            .filter(|index| !self.requested.contains_key(&(*index as u32)))
+            .min_by_key(|index| self.availability[*index]);
+
+        if piece.is_none() && self.in_endgame(have) {
+            piece = (0..len)
+                .map(|i| (offset + i) % len)
+                .filter(wanted)
+                .find(|index| match self.requested.get(&(*index as u32)) {
+                    Some(peers) => !peers.contains(&peer),
+                    None => false,
+                });
+        }
+
+        let piece = piece? as u32;
+        self.requested.entry(piece).or_default().insert(peer);
+
+        Some(piece)
+    }
+
+    /// Every missing piece has been requested from at least one source
+    pub fn in_endgame(&self, have: &Bitfield) -> bool {
+        (0..self.availability.len())
+            .all(|index| have.has(index) || self.requested.contains_key(&(index as u32)))
+    }
+
+    /// Marks a piece as received from `peer`, returns the other sources it was requested
+    /// from, which should be sent a cancel
+    pub fn received(&mut self, piece: u32, peer: Uuid) -> Vec<Uuid> {
+        let mut peers = self.requested.remove(&piece).unwrap_or_default();
+        peers.remove(&peer);
+        peers.into_iter().collect()
+    }
+
+    /// Forgets the request of a piece `peer` sent corrupted, so it can be made to another
+    /// source. Requests made to other sources during endgame stay as they are.
+    pub fn failed(&mut self, piece: u32, peer: Uuid) {
+        if let Some(peers) = self.requested.get_mut(&piece) {
+            peers.remove(&peer);
+            if peers.is_empty() {
+                self.requested.remove(&piece);
+            }
+        }
+    }
+
+    /// Forgets the requests made to a source which went away, so they can be made to
+    /// another one
+    pub fn remove_peer(&mut self, peer: Uuid) {
+        for peers in self.requested.values_mut() {
+            peers.remove(&peer);
+        }
+        self.requested.retain(|_, peers| !peers.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::PiecePicker;
+    use crate::protocol::Bitfield;
+
+    fn bitfield(len: usize, pieces: &[usize]) -> Bitfield {
+        let mut bitfield = Bitfield::new(len);
+        for piece in pieces {
+            bitfield.set(*piece);
+        }
+        bitfield
+    }
+
+    #[test]
+    fn test_rarest_first() {
+        let mut picker = PiecePicker::new(4);
+        let have = Bitfield::new(4);
+
+        let common_peer = bitfield(4, &[0, 1, 2, 3]);
+        let other_peer = bitfield(4, &[0, 1, 3]);
+        picker.add_bitfield(&common_peer);
+        picker.add_bitfield(&other_peer);
+
+        // Piece 2 is the only one just a single source has
+        let peer = Uuid::new_v4();
+        assert_eq!(picker.pick(peer, &common_peer, &have), Some(2));
+        assert!(!picker.in_endgame(&have));
+    }
+
+    #[test]
+    fn test_skips_pieces_already_had_or_requested() {
+        let mut picker = PiecePicker::new(3);
+        let have = bitfield(3, &[0]);
+        let peer_bitfield = Bitfield::full(3);
+        picker.add_bitfield(&peer_bitfield);
+
+        let peer = Uuid::new_v4();
+        let first = picker.pick(peer, &peer_bitfield, &have).unwrap();
+        let second = picker.pick(peer, &peer_bitfield, &have).unwrap();
+
+        assert_ne!(first, 0);
+        assert_ne!(second, 0);
+        assert_ne!(first, second);
+        assert_eq!(picker.pick(peer, &peer_bitfield, &have), None);
+    }
+
+    #[test]
+    fn test_endgame() {
+        let mut picker = PiecePicker::new(2);
+        let have = bitfield(2, &[0]);
+        let peer_bitfield = Bitfield::full(2);
+        picker.add_bitfield(&peer_bitfield);
+        picker.add_bitfield(&peer_bitfield);
+
+        let slow_peer = Uuid::new_v4();
+        let fast_peer = Uuid::new_v4();
+        assert_eq!(picker.pick(slow_peer, &peer_bitfield, &have), Some(1));
+        assert!(picker.in_endgame(&have));
+
+        // The last piece is requested again from another source, but only once
+        assert_eq!(picker.pick(fast_peer, &peer_bitfield, &have), Some(1));
+        assert_eq!(picker.pick(fast_peer, &peer_bitfield, &have), None);
+
+        assert_eq!(picker.received(1, fast_peer), vec![slow_peer]);
+    }
+
+    #[test]
+    fn test_corrupt_piece_is_requested_from_another_peer() {
+        let mut picker = PiecePicker::new(1);
+        let have = Bitfield::new(1);
+        let mut corrupt_bitfield = Bitfield::full(1);
+        let peer_bitfield = Bitfield::full(1);
+        picker.add_bitfield(&corrupt_bitfield);
+        picker.add_bitfield(&peer_bitfield);
+
+        let corrupt_peer = Uuid::new_v4();
+        assert_eq!(picker.pick(corrupt_peer, &corrupt_bitfield, &have), Some(0));
+        picker.failed(0, corrupt_peer);
+        corrupt_bitfield.clear(0);
+        picker.remove_have(0);
+
+        assert_eq!(picker.pick(corrupt_peer, &corrupt_bitfield, &have), None);
+        let peer = Uuid::new_v4();
+        assert_eq!(picker.pick(peer, &peer_bitfield, &have), Some(0));
+    }
+
+    #[test]
+    fn test_requests_of_removed_peer_are_picked_again() {
+        let mut picker = PiecePicker::new(1);
+        let have = Bitfield::new(1);
+        let peer_bitfield = Bitfield::full(1);
+        picker.add_bitfield(&peer_bitfield);
+
+        let gone_peer = Uuid::new_v4();
+        assert_eq!(picker.pick(gone_peer, &peer_bitfield, &have), Some(0));
+        picker.remove_bitfield(&peer_bitfield);
+        picker.remove_peer(gone_peer);
+
+        let peer = Uuid::new_v4();
+        picker.add_bitfield(&peer_bitfield);
+        assert_eq!(picker.pick(peer, &peer_bitfield, &have), Some(0));
+    }
+}
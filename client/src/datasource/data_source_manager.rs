@@ -1,22 +1,74 @@
-use common::logger::Logger;
+use std::sync::Arc;
+
 use uuid::Uuid;
-use webrtc::{
-    ice_transport::ice_candidate::RTCIceCandidate,
-    peer_connection::sdp::session_description::RTCSessionDescription,
-};
 
-use crate::{api::Api, errors::ClientError};
+use crate::{
+    api::Api,
+    config::Config,
+    control::TransferStatus,
+    errors::ClientError,
+    file::SharedPieces,
+    ice::{ClosedTx, IceConfig},
+    identity::Identity,
+    protocol::RateLimiter,
+};
 
-use super::datasource::DataSource;
+use super::datasource::{DataSource, SourceFile};
 
 pub struct DataSourceManager {
     pub uuid: Uuid,
-    data_sources: Vec<DataSource>,
-    logger: Logger,
+    url: String,
+    // Shared with the signaling handlers, which use them without the engine locked
+    data_sources: Vec<Arc<DataSource>>,
+    identity: Identity,
+    config: Config,
+    // Shared by every file served
+    upload_limiter: Arc<RateLimiter>,
+    closed_tx: ClosedTx,
+}
+
+/// What new data sources are set up with, cloned out of the manager so the engine
+/// doesn't stay locked while they register themselves with discovery
+#[derive(Clone)]
+pub struct SourceSetup {
+    url: String,
+    identity: Identity,
+    config: Config,
+    upload_limiter: Arc<RateLimiter>,
+    closed_tx: ClosedTx,
+}
+
+impl SourceSetup {
+    pub async fn data_source(
+        &self,
+        api: &Api,
+        file: SourceFile,
+    ) -> Result<DataSource, ClientError> {
+        DataSource::new(
+            api,
+            file,
+            &self.identity,
+            IceConfig::from_config(&self.config),
+            self.url.clone(),
+            Arc::clone(&self.upload_limiter),
+            self.closed_tx.clone(),
+        )
+        .await
+    }
+
+    pub fn identity(&self) -> &Identity {
+        &self.identity
+    }
 }
 
 impl DataSourceManager {
-    pub fn new(uuid: Option<Uuid>, logger: Logger) -> Result<DataSourceManager, ClientError> {
+    pub fn new(
+        uuid: Option<Uuid>,
+        url: String,
+        identity: Identity,
+        config: Config,
+        closed_tx: ClosedTx,
+    ) -> Result<DataSourceManager, ClientError> {
         let uuid = match uuid {
             Some(x) => x,
             None => Uuid::new_v4(),
@@ -24,44 +76,160 @@ impl DataSourceManager {
 
         Ok(Self {
             uuid,
+            url,
             data_sources: vec![],
-            logger,
+            identity,
+            upload_limiter: RateLimiter::new(config.upload_limit).shared(),
+            config,
+            closed_tx,
         })
     }
 
-    pub async fn new_data_source(&mut self, api: &Api) -> Result<(), ClientError> {
+    pub async fn new_data_source(
+        &mut self,
+        file_id: Uuid,
+        pieces: SharedPieces,
+        passphrase: Option<String>,
+        private: bool,
+        limit: Option<u64>,
+        api: &Api,
+    ) -> Result<(), ClientError> {
         //Create and init new data source
+        self.add_data_source(
+            self.setup()
+                .data_source(
+                    api,
+                    SourceFile {
+                        file_id,
+                        pieces,
+                        passphrase,
+                        private,
+                        limit,
+                    },
+                )
+                .await?,
+        );
+        Ok(())
+    }
+
+    pub fn setup(&self) -> SourceSetup {
+        SourceSetup {
+            url: self.url.clone(),
+            identity: self.identity.clone(),
+            config: self.config.clone(),
+            upload_limiter: Arc::clone(&self.upload_limiter),
+            closed_tx: self.closed_tx.clone(),
+        }
+    }
+
+    pub fn add_data_source(&mut self, data_source: DataSource) {
+        self.data_sources.push(Arc::new(data_source));
+    }
+
+    /// Drops a data source whose peer connection closed
+    pub fn remove_data_source(&mut self, id: Uuid) {
+        self.data_sources.retain(|data_source| data_source.id != id);
+    }
+
+    /// Every file served, along with how much of it there is to serve
+    pub fn transfers(&self) -> Result<Vec<TransferStatus>, ClientError> {
         self.data_sources
-            .push(DataSource::new(api, self.logger.clone()).await?);
+            .iter()
+            .map(|data_source| {
+                Ok(TransferStatus {
+                    progress: data_source.completeness()?,
+                    report: data_source.report(),
+                    paused: data_source.is_paused(),
+                    limit: data_source.limit(),
+                })
+            })
+            .collect()
+    }
+
+    pub fn upload_limit(&self) -> Option<u64> {
+        self.upload_limiter.rate()
+    }
+
+    /// Bytes per second every file served shares, at most
+    pub fn set_upload_limit(&self, limit: Option<u64>) {
+        self.upload_limiter.set_rate(limit);
+    }
+
+    pub fn set_transfer_limit(&self, file_id: Uuid, limit: Option<u64>) -> Result<(), ClientError> {
+        for data_source in self.sharing(file_id)? {
+            data_source.set_limit(limit);
+        }
+
         Ok(())
     }
 
-    pub async fn connect_to_client(
-        &self,
-        client_id: Uuid,
-        server_id: Uuid,
-        offer: RTCSessionDescription,
-    ) -> Result<RTCSessionDescription, ClientError> {
-        for ds in &self.data_sources {
-            if ds.id == server_id {
-                return ds.accept_connection_req_of_client(client_id, offer).await;
-            }
+    /// Stops answering the requests of every sink of `file_id` until it's resumed
+    pub async fn pause_share(&self, file_id: Uuid) -> Result<(), ClientError> {
+        for data_source in self.sharing(file_id)? {
+            data_source.pause().await?;
         }
 
-        Err(ClientError::ServerWithGivenIdNotFound)
+        Ok(())
     }
 
-    pub async fn add_ice_candidate(
-        &self,
-        id: Uuid,
-        candidate: RTCIceCandidate,
-    ) -> Result<(), ClientError> {
-        for ds in &self.data_sources {
-            if ds.id == id {
-                return ds.add_ice_candidate(candidate).await;
-            }
+    pub async fn resume_share(&self, file_id: Uuid) -> Result<(), ClientError> {
+        for data_source in self.sharing(file_id)? {
+            data_source.resume().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Stops serving `file_id` and closes the connections to its sinks
+    pub async fn cancel_share(&mut self, file_id: Uuid) -> Result<(), ClientError> {
+        self.sharing(file_id)?;
+
+        self.close_where(|data_source| data_source.file_id() == file_id)
+            .await
+    }
+
+    /// Stops seeding the pieces of a download which was cancelled
+    pub async fn cancel_seeding(&mut self, pieces: &SharedPieces) -> Result<(), ClientError> {
+        self.close_where(|data_source| data_source.serves(pieces))
+            .await
+    }
+
+    fn sharing(&self, file_id: Uuid) -> Result<Vec<&DataSource>, ClientError> {
+        let data_sources: Vec<&DataSource> = self
+            .data_sources
+            .iter()
+            .filter(|data_source| data_source.file_id() == file_id)
+            .map(Arc::as_ref)
+            .collect();
+        if data_sources.is_empty() {
+            return Err(ClientError::TransferNotFound);
         }
 
-        Err(ClientError::ServerWithGivenIdNotFound)
+        Ok(data_sources)
+    }
+
+    async fn close_where<F>(&mut self, f: F) -> Result<(), ClientError>
+    where
+        F: Fn(&DataSource) -> bool,
+    {
+        let (closed, data_sources) = self
+            .data_sources
+            .drain(..)
+            .partition(|data_source| f(data_source));
+        self.data_sources = data_sources;
+
+        for data_source in closed {
+            data_source.close().await?;
+        }
+
+        Ok(())
+    }
+
+    /// The data source a sink connects to or sends candidates for
+    pub fn data_source(&self, id: Uuid) -> Option<Arc<DataSource>> {
+        self.data_sources
+            .iter()
+            .find(|data_source| data_source.id == id)
+            .cloned()
     }
 }
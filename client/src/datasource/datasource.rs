@@ -1,36 +1,104 @@
-use bytes::Bytes;
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, Weak,
+    },
+};
 
-use common::{helpers::from_rtc_ice_server, logger::Logger, models::CandidateReq};
+use common::{
+    entities::FileCompleteness,
+    helpers::from_rtc_ice_server,
+    models::{CandidateReq, DeregisterServerReq, RegisterOrRefreshServerReq},
+};
 
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+use tracing::{debug, error, info, info_span, warn, Instrument, Span};
 use uuid::Uuid;
 use webrtc::{
     api::{
         interceptor_registry::register_default_interceptors, media_engine::MediaEngine, APIBuilder,
     },
-    data_channel::RTCDataChannel,
-    ice_transport::{
-        ice_candidate::RTCIceCandidate, ice_connection_state::RTCIceConnectionState,
-        ice_server::RTCIceServer,
-    },
+    data_channel::{data_channel_message::DataChannelMessage, RTCDataChannel},
+    ice_transport::{ice_candidate::RTCIceCandidate, ice_connection_state::RTCIceConnectionState},
     interceptor::registry::Registry,
     peer_connection::{
-        configuration::RTCConfiguration, peer_connection_state::RTCPeerConnectionState,
+        peer_connection_state::RTCPeerConnectionState,
         sdp::session_description::RTCSessionDescription, RTCPeerConnection,
     },
 };
 
-use crate::{api::Api, errors::ClientError, file::File};
+use crate::{
+    api::Api,
+    errors::ClientError,
+    file::SharedPieces,
+    ice::{
+        deployment_servers, on_state_change, report_candidate_pair, watch, Activity, ClosedTx,
+        IceConfig, Timeouts,
+    },
+    identity::Identity,
+    protocol::{
+        Channel, ChannelCrypto, Compression, Message, RateLimiter, Received, Role, Throttle,
+        TransferReport, TransferStats,
+    },
+};
+
+use super::request_queue::RequestQueue;
 
 pub struct DataSource {
     pub id: Uuid,
     // client_id: Option<Uuid>,
+    file_id: Uuid,
+    pieces: SharedPieces,
+    passphrase: Option<String>,
     peer_connection: Arc<RTCPeerConnection>,
-    logger: Logger,
+    // Candidates go out through it
+    api: Api,
+    stats: Arc<TransferStats>,
+    throttle: Throttle,
+    // Every data channel opened by the sink
+    served: Arc<Mutex<Vec<Served>>>,
+    paused: Arc<AtomicBool>,
+    // Set once a sink opened a data channel, with a passphrase it only gets the one
+    // handshake, as short codes could be guessed otherwise
+    handshaken: Arc<AtomicBool>,
+    activity: Arc<Activity>,
+    timeouts: Timeouts,
+    // Everything logged about this peer connection is in it
+    span: Span,
+}
+
+/// A file served by a data source
+pub struct SourceFile {
+    pub file_id: Uuid,
+    pub pieces: SharedPieces,
+    pub passphrase: Option<String>,
+    // Registered as private, only found with a share token
+    pub private: bool,
+    // Bytes per second, on top of the upload limit of the client
+    pub limit: Option<u64>,
 }
 
 impl DataSource {
-    pub async fn new(client_api: &Api, logger: Logger) -> Result<DataSource, ClientError> {
+    /// The ICE servers of `ice_config` are registered along with the file, so they
+    /// shouldn't need credentials. `upload_limiter` is shared by every file served.
+    pub async fn new(
+        client_api: &Api,
+        source_file: SourceFile,
+        identity: &Identity,
+        mut ice_config: IceConfig,
+        url: String,
+        upload_limiter: Arc<RateLimiter>,
+        closed_tx: ClosedTx,
+    ) -> Result<DataSource, ClientError> {
+        let SourceFile {
+            file_id,
+            pieces,
+            passphrase,
+            private,
+            limit,
+        } = source_file;
+
         let mut m = MediaEngine::default();
 
         m.register_default_codecs()
@@ -44,26 +112,24 @@ impl DataSource {
         let api = APIBuilder::new()
             .with_media_engine(m)
             .with_interceptor_registry(registry)
+            .with_setting_engine(ice_config.setting_engine())
             .build();
 
-        // Prepare the configuration
-        let config = RTCConfiguration {
-            ice_servers: vec![RTCIceServer {
-                urls: vec!["stun:stun.l.google.com:19302".to_owned()],
-                ..Default::default()
-            }],
-            ..Default::default()
-        };
-        // RTCIceServer {
-        //     urls: vec![
-        //         "turn:turn.dyte.in:443?transport=tcp".to_owned(),
-        //         "turn:turn.dyte.in:3478?transport=udp".to_owned(),
-        //     ],
-        //     username: "dyte".to_string(),
-        //     credential: "dytein".to_string(),
-        //     ..Default::default()
-        // },
-        let ice_servers = config.ice_servers.clone();
+        let uuid = Uuid::new_v4();
+        let span = info_span!("peer", role = "source", id = %uuid, %file_id);
+
+        // TURN credentials expire, so the servers of the deployment aren't registered
+        let ice_servers = ice_config.ice_servers.clone();
+        ice_config
+            .ice_servers
+            .extend(deployment_servers(client_api).await);
+        if ice_config.relay_only && !ice_config.can_relay() {
+            span.in_scope(|| warn!("Relay only, but there is no TURN server to relay through"));
+        }
+
+        // Prepare the configuration, sinks check the answer against the fingerprint
+        // registered below
+        let config = ice_config.rtc_configuration(identity.certificate()?);
 
         //Make peer connection
         let peer_connection = Arc::new(
@@ -71,30 +137,89 @@ impl DataSource {
                 .await
                 .map_err(|err| ClientError::WebRTCError(err))?,
         );
+        let stats = Arc::new(TransferStats::new());
+        report_candidate_pair(&peer_connection, span.clone(), Arc::clone(&stats)).await;
 
-        let uuid = Uuid::new_v4();
+        let (completeness, have_rx) = {
+            let pieces = pieces.lock().map_err(|_| ClientError::ErrAccessingPieces)?;
+            (pieces.completeness(), pieces.subscribe())
+        };
+
+        let mut req = RegisterOrRefreshServerReq {
+            server_id: uuid.to_string(),
+            files: Some(vec![file_id.to_string()]),
+            ice_candidates: Some(
+                ice_servers
+                    .into_iter()
+                    .map(|x| from_rtc_ice_server(x))
+                    .collect(),
+            ),
+            url,
+            completeness: None,
+            fingerprint: Some(identity.fingerprint()?),
+            private_files: if private {
+                Some(vec![file_id.to_string()])
+            } else {
+                None
+            },
+            public_key: String::new(),
+            timestamp: 0,
+            signature: String::new(),
+        };
 
-        client_api
-            .register_server(common::models::RegisterOrRefreshServerReq {
-                server_id: uuid.to_string(),
-                files: Some(vec![String::from("67e55044-10b1-426f-9247-bb680e5ff1b8")]),
-                ice_candidates: Some(
-                    ice_servers
-                        .into_iter()
-                        .map(|x| from_rtc_ice_server(x))
-                        .collect(),
-                ),
-                url: String::from("http://localhost:8080"),
-            })
-            .await?;
+        let deregistered = Arc::new(tokio::sync::Mutex::new(false));
+
+        // Only files which are still being downloaded need their completeness tracked
+        if !completeness.is_complete() {
+            req.completeness = Some(HashMap::from([(file_id.to_string(), completeness)]));
+            identity.sign_registration(&mut req)?;
+            client_api.register_server(req.clone()).await?;
+
+            tokio::spawn(
+                refresh_registration(
+                    client_api.clone(),
+                    req,
+                    identity.clone(),
+                    file_id,
+                    Arc::clone(&pieces),
+                    have_rx,
+                    Arc::clone(&deregistered),
+                )
+                .instrument(span.clone()),
+            );
+        } else {
+            identity.sign_registration(&mut req)?;
+            client_api.register_server(req).await?;
+        }
 
         //Register on_peer_connection_state_change
 
         // Set the handler for Peer connection state
         // This will notify you when the peer has connected/disconnected
+        let span2 = span.clone();
+        let pc = Arc::downgrade(&peer_connection);
+        let timeouts = ice_config.timeouts;
+        let client_api2 = client_api.clone();
+        let identity = identity.clone();
         peer_connection
             .on_peer_connection_state_change(Box::new(move |s: RTCPeerConnectionState| {
-                println!("Peer Connection State has changed, datasource: {}", s);
+                span2.in_scope(|| {
+                    info!(state = %s, "Peer connection state changed");
+                    on_state_change(s, &pc, uuid, timeouts, &closed_tx);
+                });
+
+                // Nobody else can connect to this server, so sinks mustn't be sent to it
+                if s == RTCPeerConnectionState::Closed {
+                    tokio::spawn(
+                        deregister(
+                            client_api2.clone(),
+                            identity.clone(),
+                            uuid,
+                            Arc::clone(&deregistered),
+                        )
+                        .instrument(span2.clone()),
+                    );
+                }
 
                 Box::pin(async {})
             }))
@@ -121,27 +246,41 @@ impl DataSource {
         //    }))
         //    .await;
 
+        let span2 = span.clone();
         peer_connection
-            .on_ice_connection_state_change(Box::new(|connection_state: RTCIceConnectionState| {
-                println!(
-                    "ICE Connection State has changed, datasource: {}",
-                    connection_state
-                );
-                Box::pin(async {})
-            }))
+            .on_ice_connection_state_change(Box::new(
+                move |connection_state: RTCIceConnectionState| {
+                    span2.in_scope(
+                        || info!(state = %connection_state, "ICE connection state changed"),
+                    );
+                    Box::pin(async {})
+                },
+            ))
             .await;
 
         Ok(Self {
             id: uuid,
             // client_id: None,
+            file_id,
+            pieces,
+            passphrase,
             peer_connection,
-            logger,
+            api: client_api.clone(),
+            stats,
+            throttle: Throttle::new(limit, upload_limiter),
+            served: Arc::new(Mutex::new(vec![])),
+            paused: Arc::new(AtomicBool::new(false)),
+            handshaken: Arc::new(AtomicBool::new(false)),
+            activity: Arc::new(Activity::new()),
+            timeouts: ice_config.timeouts,
+            span,
         })
     }
 
     pub async fn accept_connection_req_of_client(
         &self,
         client_id: Uuid,
+        client_url: String,
         offer: RTCSessionDescription,
     ) -> Result<RTCSessionDescription, ClientError> {
         // self.client_id = Some(client_id);
@@ -150,37 +289,42 @@ impl DataSource {
             .set_remote_description(offer)
             .await
             .map_err(|err| {
-                self.logger.log_err(&err);
+                self.span
+                    .in_scope(|| warn!(?err, "Error setting the offer of a sink"));
                 ClientError::WebRTCError(err)
             })?;
 
         //Register listener for onIceCandidate
+        let api = self.api.clone();
+        let span = self.span.clone();
         self.peer_connection
             .on_ice_candidate(Box::new(move |c: Option<RTCIceCandidate>| {
-                println!("on_ice_candidate datasource {:?}", c);
+                span.in_scope(|| debug!(candidate = ?c, "Gathered candidate"));
 
                 let client_id = client_id.clone();
-                let client_api = Api::new();
-
-                Box::pin(async move {
-                    if let Some(ice_candidate) = c {
-                        match client_api
-                            .send_candidate(
-                                "http://127.0.0.1:8081".to_string(),
-                                CandidateReq {
-                                    id: client_id.to_string(),
-                                    candidate: ice_candidate,
-                                },
-                            )
-                            .await
-                        {
-                            Ok(_) => println!("Candidate sent from datasource"),
-                            Err(err) => {
-                                println!("Error sending candidate from datasource, err: {:?}", err)
+                let client_url = client_url.clone();
+                let client_api = api.clone();
+
+                Box::pin(
+                    async move {
+                        if let Some(ice_candidate) = c {
+                            match client_api
+                                .send_candidate(
+                                    client_url,
+                                    CandidateReq {
+                                        id: client_id.to_string(),
+                                        candidate: ice_candidate,
+                                    },
+                                )
+                                .await
+                            {
+                                Ok(_) => debug!("Candidate sent"),
+                                Err(err) => warn!(?err, "Error sending candidate"),
                             }
                         }
                     }
-                })
+                    .instrument(span.clone()),
+                )
             }))
             .await;
 
@@ -198,53 +342,107 @@ impl DataSource {
             .await
             .map_err(|err| ClientError::WebRTCError(err))?;
 
+        let pieces = Arc::clone(&self.pieces);
+        let stats = Arc::clone(&self.stats);
+        let throttle = self.throttle.clone();
+        let file_id = self.file_id;
+        let passphrase = self.passphrase.clone();
+        let served_channels = Arc::clone(&self.served);
+        let paused = Arc::clone(&self.paused);
+        let handshaken = Arc::clone(&self.handshaken);
+        let activity = Arc::clone(&self.activity);
+        let pc = Arc::downgrade(&self.peer_connection);
+        let span = self.span.clone();
         self.peer_connection
             .on_data_channel(Box::new(move |d: Arc<RTCDataChannel>| {
                 let d_label = d.label().to_owned();
                 let d_id = d.id();
-                println!("New DataChannel {} {}", d_label, d_id);
+                // Every data channel is a transfer of its own
+                let transfer_span = info_span!(parent: &span, "transfer", channel = %d_label);
+                transfer_span.in_scope(|| info!(id = d_id, "New data channel"));
+
+                if passphrase.is_some() && handshaken.swap(true, Ordering::SeqCst) {
+                    transfer_span.in_scope(|| {
+                        warn!("Another handshake attempted, no longer serving the file");
+                        stop_serving(&pc);
+                    });
+                    return Box::pin(async {});
+                }
+
+                let served = Served {
+                    channel: Channel::new(
+                        Arc::clone(&d),
+                        ChannelCrypto::new(passphrase.as_deref(), file_id, Role::Source),
+                    ),
+                    pieces: Arc::clone(&pieces),
+                    requests: Arc::new(RequestQueue::new()),
+                    // Pieces go out uncompressed until the sink says it can decode them
+                    compression: Arc::new(Mutex::new(Compression::None)),
+                    stats: Arc::clone(&stats),
+                    throttle: throttle.clone(),
+                    paused: Arc::clone(&paused),
+                    peer_connection: pc.clone(),
+                };
+                if paused.load(Ordering::SeqCst) {
+                    served.requests.pause();
+                }
+                if let Ok(mut served_channels) = served_channels.lock() {
+                    served_channels.push(served.clone());
+                }
 
                 //====
                 // Register channel opening handling
 
-                Box::pin(async move {
-                    let d2 = Arc::clone(&d);
-                    let d_label2 = d_label.clone();
-                    let d_id2 = d_id;
-
-                    d.on_open(Box::new(move || {
-                        println!("Data channel '{}'-'{}' open", d_label2, d_id2);
-                        Box::pin(async move {
-                            let file = File::new(
-                                "/Users/feniljain/Projects/rust-projects/turent".to_string(),
-                                "example_file.txt".to_string(),
-                            );
-                            match file.chunkify() {
-                                Ok(chunks) => {
-                                    for chunk in chunks {
-                                        match d2.send(&Bytes::copy_from_slice(&chunk)).await {
-                                            Ok(_) => {}
-                                            Err(err) => {
-                                                println!("Err sending chunk: {:?}", err);
-                                            }
-                                        }
-                                    }
-
-                                    match d2.close().await {
-                                        Ok(_) => {}
-                                        Err(err) => {
-                                            println!("Error while closing file: {:?}", err);
-                                        }
+                let activity = Arc::clone(&activity);
+                Box::pin(
+                    async move {
+                        let served2 = served.clone();
+                        let span2 = Span::current();
+                        d.on_message(Box::new(move |msg: DataChannelMessage| {
+                            activity.touch();
+                            let served2 = served2.clone();
+                            Box::pin(
+                                async move {
+                                    if let Err(err) = on_message(&served2, msg).await {
+                                        warn!(?err, "Error handling message");
                                     }
                                 }
-                                Err(err) => {
-                                    println!("Err chunkifying: {:?}", err);
+                                .instrument(span2.clone()),
+                            )
+                        }))
+                        .await;
+
+                        let requests2 = Arc::clone(&served.requests);
+                        let stats2 = Arc::clone(&served.stats);
+                        let span2 = Span::current();
+                        d.on_close(Box::new(move || {
+                            requests2.close();
+                            let report = stats2.report(file_id.to_string(), "source");
+                            span2.in_scope(|| info!(%report, "Data channel closed"));
+                            Box::pin(async {})
+                        }))
+                        .await;
+
+                        let span2 = Span::current();
+                        d.on_open(Box::new(move || {
+                            span2.in_scope(|| info!("Data channel open"));
+                            Box::pin(
+                                async move {
+                                    // With a passphrase, nothing is served until the sink
+                                    // proved it knows it
+                                    match served.channel.start().await {
+                                        Ok(true) => serve(served).await,
+                                        Ok(false) => {}
+                                        Err(err) => warn!(?err, "Error starting handshake"),
+                                    }
                                 }
-                            }
-                        })
-                    }))
-                    .await;
-                })
+                                .instrument(span2.clone()),
+                            )
+                        }))
+                        .await;
+                    }
+                    .instrument(transfer_span),
+                )
             }))
             .await;
 
@@ -254,6 +452,27 @@ impl DataSource {
         // let _ = gather_complete.recv().await;
         // self.logger.log_debug("ICE Gathering complete");
 
+        // A sink which asks for nothing that long has no use for this source
+        let served = Arc::clone(&self.served);
+        let paused = Arc::clone(&self.paused);
+        tokio::spawn(
+            watch(
+                Arc::downgrade(&self.peer_connection),
+                self.timeouts,
+                Arc::clone(&self.activity),
+                move || {
+                    !paused.load(Ordering::SeqCst)
+                        && match served.lock() {
+                            Ok(served) => served.iter().any(|served| {
+                                !served.requests.is_closed() && !served.requests.is_paused()
+                            }),
+                            Err(_) => false,
+                        }
+                },
+            )
+            .instrument(self.span.clone()),
+        );
+
         Ok(answer)
     }
 
@@ -269,7 +488,349 @@ impl DataSource {
         return Err(ClientError::ErrConvertingCandidateToJson);
     }
 
+    pub fn report(&self) -> TransferReport {
+        self.stats.report(self.file_id.to_string(), "source")
+    }
+
+    pub fn file_id(&self) -> Uuid {
+        self.file_id
+    }
+
+    /// Whether the pieces served are those of `pieces`
+    pub fn serves(&self, pieces: &SharedPieces) -> bool {
+        Arc::ptr_eq(&self.pieces, pieces)
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    pub fn limit(&self) -> Option<u64> {
+        self.throttle.limit()
+    }
+
+    /// Bytes per second this file is served at, at most
+    pub fn set_limit(&self, limit: Option<u64>) {
+        self.span.in_scope(|| info!(?limit, "Setting upload limit"));
+        self.throttle.set_limit(limit);
+    }
+
+    /// Stops answering requests, sinks hold theirs until `resume`
+    pub async fn pause(&self) -> Result<(), ClientError> {
+        self.span.in_scope(|| info!("Pausing transfer"));
+        self.paused.store(true, Ordering::SeqCst);
+        for served in self.served()? {
+            served.requests.pause();
+        }
+
+        self.send_all(Message::Pause).await
+    }
+
+    pub async fn resume(&self) -> Result<(), ClientError> {
+        self.span.in_scope(|| info!("Resuming transfer"));
+        self.paused.store(false, Ordering::SeqCst);
+        for served in self.served()? {
+            served.requests.resume();
+        }
+
+        self.send_all(Message::Resume).await
+    }
+
+    /// Lets the sink know the file isn't served anymore and closes the peer connection
+    pub async fn close(&self) -> Result<(), ClientError> {
+        self.span.in_scope(|| info!("Closing peer connection"));
+        for served in self.served()? {
+            served.requests.close();
+        }
+        self.send_all(Message::Stop).await?;
+
+        self.peer_connection
+            .close()
+            .await
+            .map_err(|err| ClientError::WebRTCError(err))
+    }
+
+    /// Channels which are gone or still in their handshake are skipped
+    async fn send_all(&self, message: Message) -> Result<(), ClientError> {
+        for served in self.served()? {
+            if let Err(err) = served.channel.send(&message).await {
+                self.span
+                    .in_scope(|| debug!(?err, ?message, "Error sending to a sink"));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn served(&self) -> Result<Vec<Served>, ClientError> {
+        Ok(self
+            .served
+            .lock()
+            .map_err(|_| ClientError::ErrAccessingChannel)?
+            .clone())
+    }
+
+    pub fn completeness(&self) -> Result<FileCompleteness, ClientError> {
+        Ok(self
+            .pieces
+            .lock()
+            .map_err(|_| ClientError::ErrAccessingPieces)?
+            .completeness())
+    }
+
     // pub fn send_file_to_client() {}
 
     // pub fn disconnect_from_client() {}
 }
+
+/// Everything needed to serve pieces over one data channel
+#[derive(Clone)]
+struct Served {
+    channel: Channel,
+    pieces: SharedPieces,
+    requests: Arc<RequestQueue>,
+    compression: Arc<Mutex<Compression>>,
+    stats: Arc<TransferStats>,
+    throttle: Throttle,
+    // Paused by this end, which a sink resuming its own requests doesn't override
+    paused: Arc<AtomicBool>,
+    peer_connection: Weak<RTCPeerConnection>,
+}
+
+/// Closes the peer connection, which deregisters the data source
+fn stop_serving(peer_connection: &Weak<RTCPeerConnection>) {
+    // Closing waits for the handlers of the data channels, which are still running
+    let peer_connection = peer_connection.clone();
+    tokio::spawn(
+        async move {
+            if let Some(peer_connection) = peer_connection.upgrade() {
+                if let Err(err) = peer_connection.close().await {
+                    warn!(?err, "Error closing peer connection");
+                }
+            }
+        }
+        .in_current_span(),
+    );
+}
+
+async fn on_message(served: &Served, msg: DataChannelMessage) -> Result<(), ClientError> {
+    let message = match served.channel.receive(msg.data).await {
+        Ok(Received::Message(message)) => message,
+        Ok(Received::Ready) => {
+            serve(served.clone()).await;
+            return Ok(());
+        }
+        Ok(Received::Nothing) => return Ok(()),
+        // Nothing is served to a sink which doesn't know the passphrase, nor to anyone
+        // else trying this peer connection
+        Err(err @ ClientError::ErrWrongPassphrase) | Err(err @ ClientError::ErrNotEncrypted) => {
+            warn!("Handshake failed, no longer serving the file");
+            stop_serving(&served.peer_connection);
+            return Err(err);
+        }
+        Err(err) => return Err(err),
+    };
+
+    match message {
+        Message::Hello { compression } => {
+            *served
+                .compression
+                .lock()
+                .map_err(|_| ClientError::ErrAccessingChannel)? = compression;
+        }
+        Message::Request { piece } => served.requests.push(piece),
+        Message::Cancel { piece } => served.requests.cancel(piece),
+        Message::Pause => served.requests.pause(),
+        Message::Resume => {
+            if !served.paused.load(Ordering::SeqCst) {
+                served.requests.resume();
+            }
+        }
+        Message::Stop => {
+            info!("Transfer stopped by the sink");
+            served.requests.close();
+            served.channel.close().await?;
+        }
+        // Nothing else is expected from a sink
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// Lets the sink know what the bundle is made of and which pieces can be requested,
+/// then keeps it posted on the ones which arrive later on and answers its requests
+async fn serve(served: Served) {
+    let (messages, have_rx) = match served.pieces.lock() {
+        Ok(pieces) => match (pieces.manifest(), pieces.file_size()) {
+            (Some(manifest), Some(file_size)) => (
+                vec![
+                    Message::Manifest {
+                        manifest: manifest.clone(),
+                    },
+                    Message::Bitfield {
+                        file_size,
+                        bitfield: pieces.bitfield().clone(),
+                    },
+                ],
+                pieces.subscribe(),
+            ),
+            _ => return,
+        },
+        Err(_) => return,
+    };
+
+    for message in messages {
+        if let Err(err) = served.channel.send(&message).await {
+            warn!(?err, "Error sending bundle info");
+            return;
+        }
+    }
+
+    tokio::spawn(send_haves(served.channel.clone(), have_rx).in_current_span());
+    tokio::spawn(send_pieces(served).in_current_span());
+}
+
+/// Answers the requests of the sink one after the other, until the channel closes
+async fn send_pieces(served: Served) {
+    while let Some(piece) = served.requests.next().await {
+        let data = match served.pieces.lock() {
+            Ok(pieces) => pieces.get(piece),
+            Err(_) => return,
+        };
+        let data = match data {
+            Ok(x) => x,
+            Err(err) => {
+                warn!(?err, piece, "Error reading piece");
+                continue;
+            }
+        };
+
+        // Sinks only request pieces which were announced, so a missing one is just a
+        // confused peer
+        if let Some(data) = data {
+            let compression = match served.compression.lock() {
+                Ok(compression) => *compression,
+                Err(_) => return,
+            };
+
+            let raw_bytes = data.len();
+            match served
+                .channel
+                .send_with(&Message::Piece { piece, data }, compression)
+                .await
+            {
+                Ok(wire_bytes) => {
+                    served.stats.record(raw_bytes, wire_bytes);
+                    served.throttle.acquire(wire_bytes).await;
+                }
+                Err(err) => {
+                    warn!(?err, "Error sending piece");
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Announces pieces to the sink as they get downloaded, until the channel closes
+async fn send_haves(channel: Channel, mut have_rx: Receiver<u32>) {
+    loop {
+        match have_rx.recv().await {
+            Ok(piece) => {
+                if let Err(err) = channel.send(&Message::Have { piece }).await {
+                    warn!(?err, "Error sending have");
+                    return;
+                }
+            }
+            Err(RecvError::Lagged(_)) => {}
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+/// Set once the server of a data source is deregistered, it's never refreshed after
+type Deregistered = Arc<tokio::sync::Mutex<bool>>;
+
+/// Removes the server of a data source from discovery once its peer connection closed
+async fn deregister(
+    client_api: Api,
+    identity: Identity,
+    server_id: Uuid,
+    deregistered: Deregistered,
+) {
+    let mut deregistered = deregistered.lock().await;
+    if *deregistered {
+        return;
+    }
+    *deregistered = true;
+
+    let mut req = DeregisterServerReq {
+        server_id: server_id.to_string(),
+        public_key: String::new(),
+        timestamp: 0,
+        signature: String::new(),
+    };
+    if let Err(err) = identity.sign_deregistration(&mut req) {
+        error!(?err, "Error signing deregistration");
+        return;
+    }
+
+    match client_api.deregister_server(req).await {
+        Ok(()) => debug!("Deregistered server"),
+        Err(err) => warn!(?err, "Error deregistering server"),
+    }
+}
+
+/// Keeps the completeness of a partially downloaded file up to date in discovery
+async fn refresh_registration(
+    client_api: Api,
+    mut req: RegisterOrRefreshServerReq,
+    identity: Identity,
+    file_id: Uuid,
+    pieces: SharedPieces,
+    mut have_rx: Receiver<u32>,
+    deregistered: Deregistered,
+) {
+    let mut last_step = 0;
+
+    loop {
+        match have_rx.recv().await {
+            Ok(_) | Err(RecvError::Lagged(_)) => {}
+            Err(RecvError::Closed) => return,
+        }
+
+        let completeness = match pieces.lock() {
+            Ok(pieces) => pieces.completeness(),
+            Err(_) => return,
+        };
+
+        // Refreshing on every piece would flood discovery, steps of 10% are enough
+        let step = completeness.have * 10 / completeness.total;
+        if step == last_step && !completeness.is_complete() {
+            continue;
+        }
+        last_step = step;
+
+        // Held while refreshing, so a refresh can't register the server again after it
+        // was deregistered
+        let deregistered = deregistered.lock().await;
+        if *deregistered {
+            return;
+        }
+
+        req.completeness = Some(HashMap::from([(file_id.to_string(), completeness)]));
+        // Discovery rejects refreshes older than the registration they replace
+        if let Err(err) = identity.sign_registration(&mut req) {
+            error!(?err, "Error signing registration");
+            return;
+        }
+        if let Err(err) = client_api.register_server(req.clone()).await {
+            warn!(?err, "Error refreshing registration");
+        }
+
+        if completeness.is_complete() {
+            return;
+        }
+    }
+}
@@ -1,3 +1,5 @@
 mod data_source_manager;
 mod datasource;
-pub use data_source_manager::DataSourceManager;
+mod request_queue;
+pub use data_source_manager::{DataSourceManager, SourceSetup};
+pub use datasource::{DataSource, SourceFile};
@@ -0,0 +1,90 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+use tokio::sync::Notify;
+
+/// Pieces requested by a data sink which are yet to be sent. Queueing them instead of
+/// answering right away lets the sink cancel requests it no longer needs.
+pub struct RequestQueue {
+    pending: Mutex<VecDeque<u32>>,
+    closed: AtomicBool,
+    // Requests keep queueing up while paused, they're answered once resumed
+    paused: AtomicBool,
+    notify: Notify,
+}
+
+impl Default for RequestQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RequestQueue {
+    pub fn new() -> Self {
+        Self {
+            pending: Mutex::new(VecDeque::new()),
+            closed: AtomicBool::new(false),
+            paused: AtomicBool::new(false),
+            notify: Notify::new(),
+        }
+    }
+
+    pub fn push(&self, piece: u32) {
+        if let Ok(mut pending) = self.pending.lock() {
+            if !pending.contains(&piece) {
+                pending.push_back(piece);
+            }
+        }
+        self.notify.notify_one();
+    }
+
+    pub fn cancel(&self, piece: u32) {
+        if let Ok(mut pending) = self.pending.lock() {
+            pending.retain(|x| *x != piece);
+        }
+    }
+
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.notify.notify_one();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::SeqCst);
+    }
+
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::SeqCst);
+        self.notify.notify_one();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Waits for the next requested piece, None once the queue is closed
+    pub async fn next(&self) -> Option<u32> {
+        loop {
+            if self.is_closed() {
+                return None;
+            }
+
+            if !self.is_paused() {
+                if let Some(piece) = self.pending.lock().ok()?.pop_front() {
+                    return Some(piece);
+                }
+            }
+
+            self.notify.notified().await;
+        }
+    }
+}
@@ -1,30 +1,109 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    path::PathBuf,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use actix_web::{get, post, web, App, HttpResponse, HttpServer, Responder};
+use actix_web::{dev::Service, get, post, web, App, HttpResponse, HttpServer, Responder};
 use common::{
     entities::ServerInfo,
-    logger::Logger,
-    models::{CandidateReq, FindServerForFileReq, OfferReq, OfferRes},
+    models::{CandidateReq, ClaimNameplateReq, FindServerForFileReq, OfferReq, OfferRes},
 };
+use serde::Serialize;
 use serde_json::json;
-use uuid::{uuid, Uuid};
+use tokio::sync::{
+    mpsc::{unbounded_channel, UnboundedReceiver},
+    Mutex,
+};
+use tracing::{debug, info, warn};
+use uuid::Uuid;
 
 use crate::{
     api::Api,
-    datasink::DataSinkManager,
-    datasource::DataSourceManager,
+    code::{generate_code, parse_code},
+    config::Config,
+    control::{self, ControlToken, Limits, TransferStatus},
+    datasink::{DataSinkManager, StartedDownload},
+    datasource::{DataSourceManager, SourceFile},
     errors::{ApiError, ClientError},
+    file::PieceStore,
+    identity::Identity,
 };
 
+const DATA_SOURCE_PORT: u16 = 8080;
+const DATA_SINK_PORT: u16 = 8081;
+const DEFAULT_CONTROL_PORT: u16 = 8082;
+const MAX_DATA_SOURCES_PER_FILE: usize = 4;
+pub const DEFAULT_SHARE_EXPIRY_SECS: u64 = 24 * 60 * 60;
+
 pub struct Engine {
     data_source_manager: Option<DataSourceManager>,
     data_sink_manager: Option<DataSinkManager>,
+    // Receives downloads started by data sinks, only present when seeding downloads
+    downloads_rx: Option<UnboundedReceiver<StartedDownload>>,
+    // Receives the ids of data sinks and data sources whose peer connection closed
+    closed_rx: Option<UnboundedReceiver<Uuid>>,
+    port: u16,
+    // Only listened on by daemons
+    control_port: u16,
+    control_token_file: PathBuf,
     api: Api,
-    logger: Logger,
+}
+
+/// What an engine does once started
+pub enum Task {
+    /// Serves the file or directory at `path`, encrypted if there is a passphrase. With
+    /// `with_code` a short code to fetch it with is printed, which is the passphrase
+    /// unless another one is given
+    Share {
+        file_id: Uuid,
+        path: String,
+        passphrase: Option<String>,
+        with_code: bool,
+        access: Option<ShareAccess>,
+    },
+    /// Downloads a bundle into `download_dir`, the passphrase has to match the sharer's
+    Fetch {
+        file_id: Uuid,
+        download_dir: String,
+        passphrase: Option<String>,
+        share_token: Option<String>,
+    },
+    /// Downloads the bundle shared under a short code
+    FetchCode {
+        code: String,
+        download_dir: String,
+        passphrase: Option<String>,
+        share_token: Option<String>,
+    },
+    /// Starts nothing, shares and downloads are added through the control API
+    Daemon,
+}
+
+/// How a file which is being shared can be fetched
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Shared {
+    #[serde(rename = "fileId")]
+    pub file_id: Uuid,
+    #[serde(rename = "code")]
+    pub code: Option<String>,
+    // The command fetching it, with the share token of a private share
+    #[serde(rename = "fetch")]
+    pub fetch: String,
+}
+
+/// Makes a share private, discovery only finds it for holders of a share token which is
+/// printed once sharing started
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ShareAccess {
+    pub expires_in_secs: u64,
+    pub max_downloads: Option<u32>,
 }
 
 pub struct AppState {
-    engine: Arc<Mutex<Engine>>,
+    // Only locked for as long as the managers are read or changed, never while discovery
+    // or a peer is waited on
+    pub(crate) engine: Arc<Mutex<Engine>>,
 }
 
 impl Engine {
@@ -32,120 +111,511 @@ impl Engine {
         server_uuid: Option<Uuid>,
         init_data_sink: bool,
         init_data_source: bool,
+        seed_after_download: bool,
     ) -> Result<Engine, ClientError> {
-        let logger = Logger::new(true);
+        Self::from_config(
+            Config::load()?,
+            Identity::load_or_generate()?,
+            server_uuid,
+            init_data_sink,
+            init_data_source,
+            seed_after_download,
+        )
+        .await
+    }
 
-        let api = Api::new();
+    /// An engine running with `config` and `identity` rather than the ones of
+    /// `~/.turent`
+    pub async fn from_config(
+        config: Config,
+        identity: Identity,
+        server_uuid: Option<Uuid>,
+        init_data_sink: bool,
+        init_data_source: bool,
+        seed_after_download: bool,
+    ) -> Result<Engine, ClientError> {
+        let api = Api::from_config(&config);
 
         if let Err(_) = api.discovery_hello().await {
             return Err(ClientError::DiscoveryServerNotUp);
         }
 
+        let port = match config.port {
+            Some(port) => port,
+            None if init_data_source => DATA_SOURCE_PORT,
+            None => DATA_SINK_PORT,
+        };
+        let control_port = config.control_port.unwrap_or(DEFAULT_CONTROL_PORT);
+        let control_token_file = config.control_token_file();
+        let url = format!("http://localhost:{}", port);
+
         let mut data_sink_manager = None;
         let mut data_source_manager = None;
+        let mut downloads_rx = None;
+        let (closed_tx, closed_rx) = unbounded_channel();
 
         if init_data_sink {
-            data_sink_manager = Some(DataSinkManager::new(logger.clone())?);
+            let mut downloads_tx = None;
+            if seed_after_download {
+                let (tx, rx) = unbounded_channel();
+                downloads_tx = Some(tx);
+                downloads_rx = Some(rx);
+            }
+
+            data_sink_manager = Some(DataSinkManager::new(
+                url.clone(),
+                downloads_tx,
+                identity.clone(),
+                config.clone(),
+                closed_tx.clone(),
+            )?);
         }
 
-        if init_data_source {
-            data_source_manager = Some(DataSourceManager::new(server_uuid, logger.clone())?);
+        // Completed downloads are served from the same engine, so a sink which seeds
+        // needs a data source manager as well
+        if init_data_source || downloads_rx.is_some() {
+            data_source_manager = Some(DataSourceManager::new(
+                server_uuid,
+                url,
+                identity,
+                config,
+                closed_tx,
+            )?);
         }
 
         Ok(Self {
             data_source_manager,
             data_sink_manager,
+            downloads_rx,
+            closed_rx: Some(closed_rx),
+            port,
+            control_port,
+            control_token_file,
             api,
-            logger,
         })
     }
 
     pub async fn new_data_sink(
         &mut self,
         file_id: Uuid,
+        download_dir: String,
+        passphrase: Option<String>,
+        limit: Option<u64>,
         server_info: ServerInfo,
     ) -> Result<(), ClientError> {
         if let Some(data_sink_manager) = &mut self.data_sink_manager {
             return data_sink_manager
-                .new_data_sink(file_id, server_info, &self.api)
+                .new_data_sink(
+                    file_id,
+                    download_dir,
+                    passphrase,
+                    limit,
+                    server_info,
+                    &self.api,
+                )
                 .await;
         }
         Err(ClientError::InvalidConfiguration)
     }
 
-    pub async fn new_data_source(&mut self) -> Result<(), ClientError> {
+    pub async fn new_data_source(
+        &mut self,
+        file_id: Uuid,
+        path: String,
+        passphrase: Option<String>,
+        private: bool,
+        limit: Option<u64>,
+    ) -> Result<(), ClientError> {
         if let Some(data_source_manager) = &mut self.data_source_manager {
-            return data_source_manager.new_data_source(&self.api).await;
+            let pieces = PieceStore::from_path(path)?.shared();
+            return data_source_manager
+                .new_data_source(file_id, pieces, passphrase, private, limit, &self.api)
+                .await;
         }
         Err(ClientError::InvalidConfiguration)
     }
 
-    //TODO: this method is only a temporary one, it should be remove later, and instead
-    //new_data_source and new_data_sink should only be the ones used
-    pub async fn start(mut self) -> Result<(), ClientError> {
-        if self.data_source_manager.is_some() {
-            self.new_data_source().await?;
+    /// Runs `task`, then serves the signaling endpoints peers connect through, along with
+    /// the control API when running as a daemon. Returns once the servers stop
+    pub async fn start(mut self, task: Task) -> Result<(), ClientError> {
+        let port = self.port;
+        let control_port = self.control_port;
+        let control_token_file = self.control_token_file.clone();
+        let downloads_rx = self.downloads_rx.take();
+        let closed_rx = self.closed_rx.take();
 
-            let app_state = web::Data::new(AppState {
-                engine: Arc::new(Mutex::new(self)),
-            });
+        let engine = Arc::new(Mutex::new(self));
 
-            HttpServer::new(move || {
-                App::new()
-                    .app_data(app_state.clone())
-                    .service(on_offer)
-                    .service(candidates)
-                    .service(hello)
-            })
-            .bind(("localhost", 8080))
-            .map_err(|_| ClientError::ApiError(ApiError::ErrorInitializingServer))?
-            .run()
-            .await
-            .map_err(|_| ClientError::ApiError(ApiError::ErrorRunningServer))
-        } else {
-            let file_id = uuid!("67e55044-10b1-426f-9247-bb680e5ff1b8");
+        if let Some(downloads_rx) = downloads_rx {
+            tokio::spawn(seed_downloads(Arc::clone(&engine), downloads_rx));
+        }
+        if let Some(closed_rx) = closed_rx {
+            tokio::spawn(drop_closed_peers(Arc::clone(&engine), closed_rx));
+        }
+
+        let task = match task {
+            Task::FetchCode {
+                code,
+                download_dir,
+                passphrase,
+                share_token,
+            } => Task::Fetch {
+                file_id: Engine::resolve_code(&engine, &code).await?,
+                download_dir,
+                passphrase: passphrase.or(Some(code)),
+                share_token,
+            },
+            task => task,
+        };
+
+        let mut daemon = false;
+        match task {
+            Task::Share {
+                file_id,
+                path,
+                passphrase,
+                with_code,
+                access,
+            } => {
+                let shared = Engine::share(
+                    &engine,
+                    file_id,
+                    path.clone(),
+                    passphrase,
+                    with_code,
+                    access,
+                    None,
+                )
+                .await?;
 
-            // self.api.discovery_hello().await?;
+                println!("Sharing {:?} with file id: {}", path, file_id);
+                println!("Fetch it with: {}", shared.fetch);
+            }
+            Task::Fetch {
+                file_id,
+                download_dir,
+                passphrase,
+                share_token,
+            } => {
+                Engine::fetch(
+                    &engine,
+                    file_id,
+                    download_dir,
+                    passphrase,
+                    share_token,
+                    None,
+                )
+                .await?
+            }
+            Task::FetchCode { .. } => return Err(ClientError::InvalidConfiguration),
+            Task::Daemon => daemon = true,
+        }
+
+        let app_state = web::Data::new(AppState { engine });
 
-            let res = self
-                .api
-                .find_servers(FindServerForFileReq {
+        let app_state2 = app_state.clone();
+        let signaling = HttpServer::new(move || {
+            App::new()
+                .app_data(app_state2.clone())
+                .service(on_offer)
+                .service(candidates)
+                .service(hello)
+        })
+        .bind(("localhost", port))
+        .map_err(|_| ClientError::ApiError(ApiError::ErrorInitializingServer))?
+        .run();
+
+        if !daemon {
+            return signaling
+                .await
+                .map_err(|_| ClientError::ApiError(ApiError::ErrorRunningServer));
+        }
+
+        // Only reachable from this host, whatever the signaling server is bound to, and
+        // only by whoever can read the token
+        let control_token = ControlToken::load_or_generate(&control_token_file)?;
+        let control = HttpServer::new(move || {
+            let control_token = control_token.clone();
+            App::new()
+                .app_data(app_state.clone())
+                .wrap_fn(move |req, srv| {
+                    let res = control_token
+                        .authorize(req.headers())
+                        .map(|_| srv.call(req));
+                    async move {
+                        match res {
+                            Ok(res) => res.await,
+                            Err(err) => Err(err.into()),
+                        }
+                    }
+                })
+                .service(control::transfers)
+                .service(control::transfer)
+                .service(control::add_share)
+                .service(control::add_download)
+                .service(control::pause_download)
+                .service(control::resume_download)
+                .service(control::cancel_download)
+                .service(control::pause_share)
+                .service(control::resume_share)
+                .service(control::cancel_share)
+                .service(control::limits)
+                .service(control::set_limits)
+                .service(control::set_download_limit)
+                .service(control::set_share_limit)
+        })
+        .bind(("127.0.0.1", control_port))
+        .map_err(|_| ClientError::ApiError(ApiError::ErrorInitializingServer))?
+        .run();
+        info!(port = control_port, token_file = ?control_token_file, "Control API listening");
+
+        tokio::try_join!(signaling, control)
+            .map(|_| ())
+            .map_err(|_| ClientError::ApiError(ApiError::ErrorRunningServer))
+    }
+
+    /// Starts serving the file or directory at `path`, with a short code to fetch it with
+    /// when `with_code`, which is the passphrase unless another one is given. `limit` is
+    /// in bytes per second, on top of the upload limit of the client. The engine is only
+    /// locked around setting up the share, not while discovery is called
+    pub async fn share(
+        engine: &Mutex<Engine>,
+        file_id: Uuid,
+        path: String,
+        passphrase: Option<String>,
+        with_code: bool,
+        access: Option<ShareAccess>,
+        limit: Option<u64>,
+    ) -> Result<Shared, ClientError> {
+        let (api, setup) = {
+            let engine = engine.lock().await;
+            match &engine.data_source_manager {
+                Some(data_source_manager) => (engine.api.clone(), data_source_manager.setup()),
+                None => return Err(ClientError::InvalidConfiguration),
+            }
+        };
+
+        let mut code = None;
+        if with_code {
+            let nameplate = api
+                .claim_nameplate(ClaimNameplateReq {
                     file_id: file_id.to_string(),
                 })
-                .await?;
+                .await?
+                .nameplate;
+            code = Some(generate_code(&nameplate));
+        }
 
-            //TODO: Add a retrying logic here which retries with next server in the list if the
-            self.new_data_sink(file_id, res.servers_info[0].clone())
-                .await?;
+        let data_source = setup
+            .data_source(
+                &api,
+                SourceFile {
+                    file_id,
+                    pieces: PieceStore::from_path(path)?.shared(),
+                    passphrase: passphrase.or_else(|| code.clone()),
+                    private: access.is_some(),
+                    limit,
+                },
+            )
+            .await?;
+        if let Some(data_source_manager) = &mut engine.lock().await.data_source_manager {
+            data_source_manager.add_data_source(data_source);
+        }
+
+        let mut fetch = format!(
+            "turent fetch {}",
+            code.clone().unwrap_or_else(|| file_id.to_string())
+        );
+        if let Some(access) = access {
+            fetch += &format!(
+                " token={}",
+                mint_share_token(setup.identity(), file_id, access)?
+            );
+        }
+
+        Ok(Shared {
+            file_id,
+            code,
+            fetch,
+        })
+    }
 
-            let app_state = web::Data::new(AppState {
-                engine: Arc::new(Mutex::new(self)),
-            });
+    /// Starts downloading `file_id` into `download_dir`, from several of the servers
+    /// which have it at once. The engine is only locked around setting up the download
+    /// and adding the data sinks which connected, not while they connect
+    pub async fn fetch(
+        engine: &Mutex<Engine>,
+        file_id: Uuid,
+        download_dir: String,
+        passphrase: Option<String>,
+        share_token: Option<String>,
+        limit: Option<u64>,
+    ) -> Result<(), ClientError> {
+        let api = engine.lock().await.api.clone();
+        let res = api
+            .find_servers(FindServerForFileReq {
+                file_id: file_id.to_string(),
+                share_token,
+            })
+            .await?;
 
-            HttpServer::new(move || {
-                App::new()
-                    .app_data(app_state.clone())
-                    .service(on_offer)
-                    .service(candidates)
-                    .service(hello)
+        // Seeders which are still downloading the file might not have all of it
+        let mut servers_info = res.servers_info;
+        servers_info.sort_by_key(|server_info| {
+            std::cmp::Reverse(match server_info.file_completeness(&file_id.to_string()) {
+                Some(completeness) => completeness.have,
+                None => u32::MAX,
             })
-            .bind(("localhost", 8081))
-            .map_err(|_| ClientError::ApiError(ApiError::ErrorInitializingServer))?
-            .run()
-            .await
-            .map_err(|_| ClientError::ApiError(ApiError::ErrorRunningServer))
+        });
+
+        let (setup, download) = match &mut engine.lock().await.data_sink_manager {
+            Some(data_sink_manager) => (
+                data_sink_manager.setup(),
+                data_sink_manager.start_download(file_id, download_dir, passphrase, limit),
+            ),
+            None => return Err(ClientError::InvalidConfiguration),
+        };
+
+        // Servers which can't be reached are skipped
+        let mut connected = 0;
+        for server_info in servers_info {
+            if connected == MAX_DATA_SOURCES_PER_FILE {
+                break;
+            }
+
+            let data_sink = match setup
+                .data_sink(&api, file_id, server_info, download.clone())
+                .await
+            {
+                Ok(x) => x,
+                Err(err) => {
+                    warn!(?err, %file_id, "Error connecting to a data source");
+                    continue;
+                }
+            };
+
+            match &mut engine.lock().await.data_sink_manager {
+                Some(data_sink_manager) => data_sink_manager.add_data_sink(data_sink).await?,
+                None => return Err(ClientError::InvalidConfiguration),
+            }
+            connected += 1;
+        }
+
+        if connected == 0 {
+            return Err(ClientError::ServerWithGivenIdNotFound);
+        }
+
+        Ok(())
+    }
+
+    pub async fn pause_download(&self, file_id: Uuid) -> Result<(), ClientError> {
+        match &self.data_sink_manager {
+            Some(data_sink_manager) => data_sink_manager.pause_download(file_id).await,
+            None => Err(ClientError::TransferNotFound),
+        }
+    }
 
-            //connection to current one fails
-            // let res = self.api.send_offer(OfferReq {
-            //     server_id: res.server_info.clone_into,
-            //     session_desc: ,
-            // });
+    pub async fn resume_download(&self, file_id: Uuid) -> Result<(), ClientError> {
+        match &self.data_sink_manager {
+            Some(data_sink_manager) => data_sink_manager.resume_download(file_id).await,
+            None => Err(ClientError::TransferNotFound),
+        }
+    }
+
+    /// Stops downloading `file_id`, and seeding what was downloaded of it
+    pub async fn cancel_download(&mut self, file_id: Uuid) -> Result<(), ClientError> {
+        let pieces = match &mut self.data_sink_manager {
+            Some(data_sink_manager) => data_sink_manager.cancel_download(file_id).await?,
+            None => return Err(ClientError::TransferNotFound),
+        };
+
+        match &mut self.data_source_manager {
+            Some(data_source_manager) => data_source_manager.cancel_seeding(&pieces).await,
+            None => Ok(()),
+        }
+    }
+
+    pub async fn pause_share(&self, file_id: Uuid) -> Result<(), ClientError> {
+        match &self.data_source_manager {
+            Some(data_source_manager) => data_source_manager.pause_share(file_id).await,
+            None => Err(ClientError::TransferNotFound),
+        }
+    }
 
-            //TODO:
-            // Ok(())
+    pub async fn resume_share(&self, file_id: Uuid) -> Result<(), ClientError> {
+        match &self.data_source_manager {
+            Some(data_source_manager) => data_source_manager.resume_share(file_id).await,
+            None => Err(ClientError::TransferNotFound),
         }
     }
 
+    pub async fn cancel_share(&mut self, file_id: Uuid) -> Result<(), ClientError> {
+        match &mut self.data_source_manager {
+            Some(data_source_manager) => data_source_manager.cancel_share(file_id).await,
+            None => Err(ClientError::TransferNotFound),
+        }
+    }
+
+    pub fn limits(&self) -> Limits {
+        Limits {
+            upload_limit: self
+                .data_source_manager
+                .as_ref()
+                .and_then(|data_source_manager| data_source_manager.upload_limit()),
+            download_limit: self
+                .data_sink_manager
+                .as_ref()
+                .and_then(|data_sink_manager| data_sink_manager.download_limit()),
+        }
+    }
+
+    /// Replaces the limits every upload and every download share
+    pub fn set_limits(&self, limits: Limits) {
+        info!(?limits, "Setting bandwidth limits");
+        if let Some(data_source_manager) = &self.data_source_manager {
+            data_source_manager.set_upload_limit(limits.upload_limit);
+        }
+        if let Some(data_sink_manager) = &self.data_sink_manager {
+            data_sink_manager.set_download_limit(limits.download_limit);
+        }
+    }
+
+    pub fn set_share_limit(&self, file_id: Uuid, limit: Option<u64>) -> Result<(), ClientError> {
+        match &self.data_source_manager {
+            Some(data_source_manager) => data_source_manager.set_transfer_limit(file_id, limit),
+            None => Err(ClientError::TransferNotFound),
+        }
+    }
+
+    pub fn set_download_limit(&self, file_id: Uuid, limit: Option<u64>) -> Result<(), ClientError> {
+        match &self.data_sink_manager {
+            Some(data_sink_manager) => data_sink_manager.set_transfer_limit(file_id, limit),
+            None => Err(ClientError::TransferNotFound),
+        }
+    }
+
+    /// Every file being served or downloaded
+    pub fn transfers(&self) -> Result<Vec<TransferStatus>, ClientError> {
+        let mut transfers = vec![];
+        if let Some(data_source_manager) = &self.data_source_manager {
+            transfers.extend(data_source_manager.transfers()?);
+        }
+        if let Some(data_sink_manager) = &self.data_sink_manager {
+            transfers.extend(data_sink_manager.transfers()?);
+        }
+
+        Ok(transfers)
+    }
+
+    pub async fn resolve_code(engine: &Mutex<Engine>, code: &str) -> Result<Uuid, ClientError> {
+        let nameplate = parse_code(code).ok_or(ClientError::InvalidConfiguration)?;
+
+        let api = engine.lock().await.api.clone();
+        let res = api.lookup_nameplate(nameplate).await?;
+
+        Uuid::parse_str(&res.file_id).map_err(|_| ClientError::ApiError(ApiError::InvalidIdFormat))
+    }
+
     // pub fn get_files_list(&self, server_uuid: Uuid) -> Option<&Vec<FileType>> {
     //     self.discovery.file_lookup(server_uuid)
     // }
@@ -153,39 +623,115 @@ impl Engine {
     // pub fn receive_file() {}
 }
 
+fn mint_share_token(
+    identity: &Identity,
+    file_id: Uuid,
+    access: ShareAccess,
+) -> Result<String, ClientError> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|_| ClientError::InvalidConfiguration)?
+        .as_secs();
+
+    Ok(identity
+        .mint_share_token(file_id, now + access.expires_in_secs, access.max_downloads)?
+        .encode())
+}
+
+/// Registers every download started by the data sinks of `engine` with discovery and
+/// serves its pieces through a new data source, so downloads spread across the swarm
+/// even before they complete.
+async fn seed_downloads(
+    engine: Arc<Mutex<Engine>>,
+    mut downloads_rx: UnboundedReceiver<StartedDownload>,
+) {
+    while let Some(started_download) = downloads_rx.recv().await {
+        // The engine can't stay locked while the data source registers itself
+        let (api, setup) = {
+            let engine = engine.lock().await;
+
+            match &engine.data_source_manager {
+                Some(data_source_manager) => (engine.api.clone(), data_source_manager.setup()),
+                None => return,
+            }
+        };
+
+        debug!(file_id = %started_download.file_id, "Seeding download");
+
+        let data_source = match setup
+            .data_source(
+                &api,
+                SourceFile {
+                    file_id: started_download.file_id,
+                    pieces: started_download.pieces,
+                    passphrase: started_download.passphrase,
+                    // Private files stay private to their owner, whoever seeds them
+                    private: false,
+                    limit: None,
+                },
+            )
+            .await
+        {
+            Ok(x) => x,
+            Err(err) => {
+                warn!(?err, "Error seeding download");
+                continue;
+            }
+        };
+
+        if let Some(data_source_manager) = &mut engine.lock().await.data_source_manager {
+            data_source_manager.add_data_source(data_source);
+        }
+    }
+}
+
+/// Drops the data sinks and data sources of `engine` whose peer connection closed, which
+/// failed, timed out or was cancelled
+async fn drop_closed_peers(engine: Arc<Mutex<Engine>>, mut closed_rx: UnboundedReceiver<Uuid>) {
+    while let Some(id) = closed_rx.recv().await {
+        debug!(%id, "Dropping closed peer");
+
+        let mut engine = engine.lock().await;
+        if let Some(data_sink_manager) = &mut engine.data_sink_manager {
+            data_sink_manager.remove_data_sink(id);
+        }
+        if let Some(data_source_manager) = &mut engine.data_source_manager {
+            data_source_manager.remove_data_source(id);
+        }
+    }
+}
+
 #[post("/on-offer")]
 pub async fn on_offer(
     req: web::Json<OfferReq>,
     data: web::Data<AppState>,
 ) -> Result<HttpResponse, ClientError> {
-    let engine = match data.engine.lock() {
-        Ok(x) => x,
-        Err(_) => return Err(ClientError::ApiError(ApiError::InternalServerError)),
-    };
-    engine.logger.log_debug("Received offer");
-
-    let data_source_manager = match &engine.data_source_manager {
-        Some(x) => x,
-        None => return Err(ClientError::InvalidConfiguration),
-    };
-    engine.logger.log_debug("Valid config");
-
     let server_id = match Uuid::parse_str(&req.server_id) {
         Ok(x) => x,
         Err(_) => return Err(ClientError::ApiError(ApiError::InvalidIdFormat)),
     };
-    engine.logger.log_debug("Valid server id");
 
     let client_id = match Uuid::parse_str(&req.client_info.id) {
         Ok(x) => x,
         Err(_) => return Err(ClientError::ApiError(ApiError::InvalidIdFormat)),
     };
-    engine.logger.log_debug("Valid client id");
+    debug!(%server_id, %client_id, "Received offer");
+
+    // The engine stays unlocked while the peer connection answers
+    let data_source = match &data.engine.lock().await.data_source_manager {
+        Some(data_source_manager) => data_source_manager
+            .data_source(server_id)
+            .ok_or(ClientError::ServerWithGivenIdNotFound)?,
+        None => return Err(ClientError::InvalidConfiguration),
+    };
 
-    let answer = data_source_manager
-        .connect_to_client(client_id, server_id, req.session_desc.clone())
+    let answer = data_source
+        .accept_connection_req_of_client(
+            client_id,
+            req.client_info.url.clone(),
+            req.session_desc.clone(),
+        )
         .await?;
-    engine.logger.log_debug("Answer received");
 
     Ok(HttpResponse::Ok().json(OfferRes {
         session_desc: answer,
@@ -197,40 +743,34 @@ pub async fn candidates(
     req: web::Json<CandidateReq>,
     data: web::Data<AppState>,
 ) -> Result<HttpResponse, ClientError> {
-    let engine = match data.engine.lock() {
-        Ok(x) => x,
-        Err(_) => return Err(ClientError::ApiError(ApiError::InternalServerError)),
-    };
-    engine
-        .logger
-        // .log_debug(&format!("Received candidate {:?}", req));
-        .log_debug("Received candidate");
-
     let id = match Uuid::parse_str(&req.id) {
         Ok(x) => x,
         Err(_) => return Err(ClientError::ApiError(ApiError::InvalidIdFormat)),
     };
-    // engine.logger.log_debug("Valid id");
-    engine.logger.log_debug(&format!("Valid id: {:?}", id));
-
-    if let Some(data_sink_manager) = &engine.data_sink_manager {
-        engine
-            .logger
-            .log_debug("Received req to add candidate in data sink");
-        data_sink_manager
-            .add_ice_candidate(id, req.candidate.clone())
-            .await?;
-    } else {
-        engine
-            .logger
-            .log_debug("Received req to add candidate in data source");
-        if let Some(data_source_manager) = &engine.data_source_manager {
-            data_source_manager
-                .add_ice_candidate(id, req.candidate.clone())
-                .await?;
-        }
+    debug!(%id, "Received candidate");
+
+    // A seeding sink runs both managers, so candidates for ids unknown to the data
+    // sinks are handed over to the data sources. The engine stays unlocked while they're
+    // added
+    let (data_sink, data_source) = {
+        let engine = data.engine.lock().await;
+        (
+            engine
+                .data_sink_manager
+                .as_ref()
+                .and_then(|data_sink_manager| data_sink_manager.data_sink(id)),
+            engine
+                .data_source_manager
+                .as_ref()
+                .and_then(|data_source_manager| data_source_manager.data_source(id)),
+        )
+    };
+
+    match (data_sink, data_source) {
+        (Some(data_sink), _) => data_sink.add_ice_candidate(req.candidate.clone()).await?,
+        (None, Some(data_source)) => data_source.add_ice_candidate(req.candidate.clone()).await?,
+        (None, None) => return Err(ClientError::ClientWithGivenIdNotFound),
     }
-    engine.logger.log_debug("Candidate Added");
 
     Ok(HttpResponse::Ok().json(json!({
         "success":  true,
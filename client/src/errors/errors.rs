@@ -11,6 +11,22 @@ pub enum ClientError {
     ErrConvertingCandidateToJson,
     ErrReadingFile(String),
     ErrWritingFile(String),
+    ErrInvalidMessage,
+    ErrInvalidPiece,
+    ErrFileSizeMismatch,
+    ErrAccessingPieces,
+    ErrInvalidManifest(String),
+    ErrHashMismatch(String),
+    ErrPieceHashMismatch(u32),
+    ErrNotEncrypted,
+    ErrWrongPassphrase,
+    ErrAccessingChannel,
+    ErrIdentity(String),
+    ErrFingerprintMismatch,
+    TransferNotFound,
+    ControlUnauthorized,
+    ControlHostRejected,
+    InvalidLimit,
 }
 
 impl std::error::Error for ClientError {}
@@ -32,6 +48,46 @@ impl std::fmt::Display for ClientError {
             }
             ClientError::ErrReadingFile(err) => write!(f, "Error reading file: {:?}", err),
             ClientError::ErrWritingFile(err) => write!(f, "Error writing file: {:?}", err),
+            ClientError::ErrInvalidMessage => write!(f, "Invalid message received from peer"),
+            ClientError::ErrInvalidPiece => write!(f, "Invalid piece received from peer"),
+            ClientError::ErrFileSizeMismatch => {
+                write!(f, "File size sent by peer doesn't match the known one")
+            }
+            ClientError::ErrAccessingPieces => write!(f, "Error accessing pieces of file"),
+            ClientError::ErrInvalidManifest(err) => write!(f, "Invalid manifest: {:?}", err),
+            ClientError::ErrHashMismatch(path) => {
+                write!(f, "Hash of received file doesn't match: {:?}", path)
+            }
+            ClientError::ErrPieceHashMismatch(piece) => {
+                write!(
+                    f,
+                    "Hash of piece {} received from peer doesn't match",
+                    piece
+                )
+            }
+            ClientError::ErrNotEncrypted => {
+                write!(f, "Message couldn't be authenticated with the passphrase")
+            }
+            ClientError::ErrWrongPassphrase => write!(f, "Peer used a different passphrase"),
+            ClientError::ErrAccessingChannel => write!(f, "Error accessing data channel"),
+            ClientError::ErrIdentity(err) => write!(f, "Error loading identity: {:?}", err),
+            ClientError::ErrFingerprintMismatch => write!(
+                f,
+                "Certificate fingerprint of peer doesn't match the registered one"
+            ),
+            ClientError::TransferNotFound => write!(f, "No transfer of the given file"),
+            ClientError::ControlUnauthorized => {
+                write!(
+                    f,
+                    "Missing or wrong control token, it's in controlTokenFile"
+                )
+            }
+            ClientError::ControlHostRejected => {
+                write!(f, "The control API only answers to localhost")
+            }
+            ClientError::InvalidLimit => {
+                write!(f, "A limit of 0 bytes per second isn't one, pause instead")
+            }
         }
     }
 }
@@ -44,6 +100,7 @@ pub enum ApiError {
     ErrorRunningServer,
     InternalServerError,
     ErrAddIceCandidateReq,
+    ErrUnauthorized,
 }
 
 impl std::error::Error for ApiError {}
@@ -57,6 +114,12 @@ impl std::fmt::Display for ApiError {
             ApiError::ErrorRunningServer => write!(f, "Error Running Server"),
             ApiError::InternalServerError => write!(f, "Internal Server Error"),
             ApiError::ErrAddIceCandidateReq => write!(f, "Error add ICE candidate request"),
+            ApiError::ErrUnauthorized => {
+                write!(
+                    f,
+                    "Discovery rejected the token, check discoveryToken in config"
+                )
+            }
         }
     }
 }
@@ -71,6 +134,7 @@ impl ResponseError for ClientError {
                 | ApiError::ErrAddIceCandidateReq
                 | ApiError::InternalServerError => reqwest::StatusCode::INTERNAL_SERVER_ERROR,
                 ApiError::InvalidIdFormat => reqwest::StatusCode::BAD_REQUEST,
+                ApiError::ErrUnauthorized => reqwest::StatusCode::UNAUTHORIZED,
             },
             ClientError::WebRTCError(_)
             | ClientError::DiscoveryServerNotUp
@@ -79,7 +143,23 @@ impl ResponseError for ClientError {
             | ClientError::ErrConvertingCandidateToJson
             | ClientError::ErrReadingFile(_)
             | ClientError::ErrWritingFile(_)
+            | ClientError::ErrInvalidMessage
+            | ClientError::ErrInvalidPiece
+            | ClientError::ErrFileSizeMismatch
+            | ClientError::ErrAccessingPieces
+            | ClientError::ErrInvalidManifest(_)
+            | ClientError::ErrHashMismatch(_)
+            | ClientError::ErrPieceHashMismatch(_)
+            | ClientError::ErrNotEncrypted
+            | ClientError::ErrWrongPassphrase
+            | ClientError::ErrAccessingChannel
+            | ClientError::ErrIdentity(_)
+            | ClientError::ErrFingerprintMismatch
             | ClientError::InvalidConfiguration => reqwest::StatusCode::INTERNAL_SERVER_ERROR,
+            ClientError::TransferNotFound => reqwest::StatusCode::NOT_FOUND,
+            ClientError::ControlUnauthorized => reqwest::StatusCode::UNAUTHORIZED,
+            ClientError::ControlHostRejected => reqwest::StatusCode::FORBIDDEN,
+            ClientError::InvalidLimit => reqwest::StatusCode::BAD_REQUEST,
         }
     }
 
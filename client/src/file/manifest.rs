@@ -0,0 +1,575 @@
+use std::{
+    collections::HashSet,
+    fs,
+    io::{self, Read, Seek, SeekFrom},
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::info;
+
+use crate::{
+    errors::ClientError,
+    protocol::{piece_count, PIECE_SIZE},
+};
+
+// Caps what a manifest received from a peer can make a sink allocate and download
+pub const MAX_BUNDLE_SIZE: u64 = 16 * 1024 * 1024 * 1024;
+pub const MAX_PIECE_COUNT: usize = (MAX_BUNDLE_SIZE / PIECE_SIZE as u64) as usize;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ManifestEntry {
+    // Relative to the directory the bundle is received in, components separated by `/`
+    #[serde(rename = "path")]
+    pub path: String,
+    #[serde(rename = "size")]
+    pub size: u64,
+    #[serde(rename = "mode")]
+    pub mode: u32,
+    // Hex encoded SHA-256 of the contents
+    #[serde(rename = "hash")]
+    pub hash: String,
+}
+
+/// Describes the files making up a shared bundle, which is either a single file or a
+/// whole directory tree. Pieces are cut from the contents of all the files one after
+/// the other, in the order of the entries.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Manifest {
+    #[serde(rename = "entries")]
+    pub entries: Vec<ManifestEntry>,
+    // Hex encoded SHA-256 of every piece, so a piece can be checked as soon as it
+    // arrives, whichever source it comes from
+    #[serde(rename = "pieces")]
+    pub pieces: Vec<String>,
+}
+
+impl Manifest {
+    /// Builds the manifest of the file or directory at `path`, along with the paths of
+    /// the files its entries were read from. Symlinks are skipped, so nothing outside of
+    /// `path` gets shared.
+    pub fn from_path(path: &str) -> Result<(Manifest, Vec<PathBuf>), ClientError> {
+        // Paths like `.` or `..` only have a name once resolved
+        let path =
+            fs::canonicalize(path).map_err(|err| ClientError::ErrReadingFile(err.to_string()))?;
+        let path = path.as_path();
+        let root_name = path
+            .file_name()
+            .and_then(|x| x.to_str())
+            .ok_or_else(|| ClientError::ErrReadingFile(format!("Invalid path: {:?}", path)))?;
+
+        let mut files = vec![];
+        collect_files(path, root_name.to_string(), &mut files)?;
+        files.sort_by(|a, b| a.1.cmp(&b.1));
+
+        let mut entries = vec![];
+        let mut paths = vec![];
+        let mut pieces = PieceHasher::new();
+        for (full_path, relative_path) in files {
+            let file = fs::File::open(&full_path)
+                .map_err(|err| ClientError::ErrReadingFile(err.to_string()))?;
+            let (size, hash) = hash_reader(file, Some(&mut pieces))
+                .map_err(|err| ClientError::ErrReadingFile(err.to_string()))?;
+
+            entries.push(ManifestEntry {
+                path: relative_path,
+                size,
+                mode: read_mode(&full_path)?,
+                hash,
+            });
+            paths.push(full_path);
+        }
+
+        let manifest = Manifest {
+            entries,
+            pieces: pieces.finish(),
+        };
+        manifest.validate()?;
+
+        Ok((manifest, paths))
+    }
+
+    pub fn total_size(&self) -> Result<u64, ClientError> {
+        self.entries
+            .iter()
+            .try_fold(0u64, |total, entry| total.checked_add(entry.size))
+            .ok_or_else(|| ClientError::ErrInvalidManifest(String::from("Total size overflows")))
+    }
+
+    /// The single file or directory every entry is written under
+    pub fn root(&self) -> Option<&str> {
+        self.entries
+            .first()
+            .and_then(|entry| entry.path.split('/').next())
+    }
+
+    /// Rejects manifests which would write outside of the directory they are received
+    /// in, like ones with `../` or absolute paths, or more than one file or directory
+    /// into it, and ones too large to be downloaded
+    pub fn validate(&self) -> Result<(), ClientError> {
+        let total_size = self.total_size()?;
+        if total_size > MAX_BUNDLE_SIZE || self.pieces.len() > MAX_PIECE_COUNT {
+            return Err(ClientError::ErrInvalidManifest(format!(
+                "Bundle too large: {} bytes in {} pieces",
+                total_size,
+                self.pieces.len()
+            )));
+        }
+        if self.pieces.len() != piece_count(total_size) {
+            return Err(ClientError::ErrInvalidManifest(format!(
+                "{} piece hashes for {} bytes",
+                self.pieces.len(),
+                total_size
+            )));
+        }
+
+        let mut paths = HashSet::new();
+        let root = self.root().unwrap_or_default();
+
+        for entry in &self.entries {
+            let is_safe = !entry.path.is_empty()
+                && !entry.path.contains(|c| c == '\\' || c == ':' || c == '\0')
+                && entry.path.split('/').all(|component| {
+                    !component.is_empty() && component != "." && component != ".."
+                });
+
+            if !is_safe {
+                return Err(ClientError::ErrInvalidManifest(format!(
+                    "Unsafe path: {:?}",
+                    entry.path
+                )));
+            }
+
+            // A single file is its own root, a directory holds everything else
+            let is_under_root = match entry.path.split_once('/') {
+                Some((first, _)) => first == root,
+                None => self.entries.len() == 1,
+            };
+            if !is_under_root {
+                return Err(ClientError::ErrInvalidManifest(format!(
+                    "Path outside of {:?}: {:?}",
+                    root, entry.path
+                )));
+            }
+
+            if !paths.insert(entry.path.as_str()) {
+                return Err(ClientError::ErrInvalidManifest(format!(
+                    "Duplicate path: {:?}",
+                    entry.path
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether `data` is what the piece at `piece` was hashed from
+    pub fn piece_matches(&self, piece: u32, data: &[u8]) -> bool {
+        self.pieces
+            .get(piece as usize)
+            .is_some_and(|hash| hex::encode(Sha256::digest(data)) == *hash)
+    }
+
+    /// Where the `len` bytes at `offset` in the bundle are, as the index of each entry
+    /// they span, the offset within that entry and the range they take up of the `len`
+    pub fn locate(&self, offset: u64, len: usize) -> Vec<(usize, u64, Range<usize>)> {
+        let end = offset + len as u64;
+        let mut spans = vec![];
+        let mut entry_start = 0;
+
+        for (index, entry) in self.entries.iter().enumerate() {
+            let entry_end = entry_start + entry.size;
+            let start = offset.max(entry_start);
+            let stop = end.min(entry_end);
+
+            if start < stop {
+                spans.push((
+                    index,
+                    start - entry_start,
+                    (start - offset) as usize..(stop - offset) as usize,
+                ));
+            }
+            if entry_end >= end {
+                break;
+            }
+            entry_start = entry_end;
+        }
+
+        spans
+    }
+
+    /// Recreates the bundle inside `dir` out of the file its contents were received
+    /// in, checking every file against its hash before writing it. Nothing already in
+    /// `dir` is overwritten or followed, so its root mustn't exist yet. Returns the paths
+    /// of the files written, in the order of the entries.
+    pub fn write(&self, dir: &str, bundle: &Path) -> Result<Vec<PathBuf>, ClientError> {
+        self.validate()?;
+
+        let mut bundle =
+            fs::File::open(bundle).map_err(|err| ClientError::ErrReadingFile(err.to_string()))?;
+        let bundle_size = bundle
+            .metadata()
+            .map_err(|err| ClientError::ErrReadingFile(err.to_string()))?
+            .len();
+        if bundle_size != self.total_size()? {
+            return Err(ClientError::ErrFileSizeMismatch);
+        }
+
+        // Nothing is written unless every file is intact
+        for entry in &self.entries {
+            let (_, hash) = hash_reader((&mut bundle).take(entry.size), None)
+                .map_err(|err| ClientError::ErrReadingFile(err.to_string()))?;
+            if hash != entry.hash {
+                return Err(ClientError::ErrHashMismatch(entry.path.clone()));
+            }
+        }
+
+        bundle
+            .seek(SeekFrom::Start(0))
+            .map_err(|err| ClientError::ErrReadingFile(err.to_string()))?;
+
+        let root = Path::new(dir).join(self.root().unwrap_or_default());
+        if fs::symlink_metadata(&root).is_ok() {
+            return Err(ClientError::ErrWritingFile(format!(
+                "Already exists: {:?}",
+                root
+            )));
+        }
+        fs::create_dir_all(dir).map_err(|err| ClientError::ErrWritingFile(err.to_string()))?;
+
+        let mut paths = vec![];
+        for entry in &self.entries {
+            let full_path = Path::new(dir).join(&entry.path);
+            let mut components: Vec<&str> = entry.path.split('/').collect();
+            components.pop();
+            create_dirs(Path::new(dir), &components)?;
+
+            let mut file = fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&full_path)
+                .map_err(|err| ClientError::ErrWritingFile(err.to_string()))?;
+            io::copy(&mut (&mut bundle).take(entry.size), &mut file)
+                .map_err(|err| ClientError::ErrWritingFile(err.to_string()))?;
+            info!(path = ?full_path, "File written");
+
+            write_mode(&full_path, entry.mode)?;
+            paths.push(full_path);
+        }
+
+        Ok(paths)
+    }
+}
+
+fn collect_files(
+    path: &Path,
+    relative_path: String,
+    files: &mut Vec<(std::path::PathBuf, String)>,
+) -> Result<(), ClientError> {
+    let metadata =
+        fs::symlink_metadata(path).map_err(|err| ClientError::ErrReadingFile(err.to_string()))?;
+
+    if metadata.is_file() {
+        files.push((path.to_path_buf(), relative_path));
+    } else if metadata.is_dir() {
+        let dir_entries =
+            fs::read_dir(path).map_err(|err| ClientError::ErrReadingFile(err.to_string()))?;
+
+        for dir_entry in dir_entries {
+            let dir_entry =
+                dir_entry.map_err(|err| ClientError::ErrReadingFile(err.to_string()))?;
+            let name = dir_entry.file_name();
+            let name = name.to_str().ok_or_else(|| {
+                ClientError::ErrReadingFile(format!("Invalid file name: {:?}", name))
+            })?;
+
+            collect_files(
+                &dir_entry.path(),
+                format!("{}/{}", relative_path, name),
+                files,
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Creates the directories `components` lead to inside `dir`, refusing to go through
+/// anything which isn't a directory, like a symlink
+fn create_dirs(dir: &Path, components: &[&str]) -> Result<(), ClientError> {
+    let mut path = dir.to_path_buf();
+    for component in components {
+        path.push(component);
+        match fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.is_dir() => {}
+            Ok(_) => {
+                return Err(ClientError::ErrWritingFile(format!(
+                    "Not a directory: {:?}",
+                    path
+                )))
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                fs::create_dir(&path).map_err(|err| ClientError::ErrWritingFile(err.to_string()))?
+            }
+            Err(err) => return Err(ClientError::ErrWritingFile(err.to_string())),
+        }
+    }
+
+    Ok(())
+}
+
+/// Hashes the pieces cut from the files of a bundle, which are read one after the other
+struct PieceHasher {
+    hasher: Sha256,
+    len: usize,
+    hashes: Vec<String>,
+}
+
+impl PieceHasher {
+    fn new() -> Self {
+        Self {
+            hasher: Sha256::new(),
+            len: 0,
+            hashes: vec![],
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let n = std::cmp::min(PIECE_SIZE - self.len, data.len());
+            self.hasher.update(&data[..n]);
+            self.len += n;
+            data = &data[n..];
+
+            if self.len == PIECE_SIZE {
+                self.finish_piece();
+            }
+        }
+    }
+
+    fn finish_piece(&mut self) {
+        let hasher = std::mem::replace(&mut self.hasher, Sha256::new());
+        self.hashes.push(hex::encode(hasher.finalize()));
+        self.len = 0;
+    }
+
+    fn finish(mut self) -> Vec<String> {
+        if self.len > 0 {
+            self.finish_piece();
+        }
+        self.hashes
+    }
+}
+
+/// Size and hex encoded SHA-256 of whatever `reader` reads, a buffer at a time, also
+/// fed to `pieces` if given
+fn hash_reader(
+    mut reader: impl Read,
+    mut pieces: Option<&mut PieceHasher>,
+) -> io::Result<(u64, String)> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; PIECE_SIZE];
+    let mut size = 0;
+
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => return Ok((size, hex::encode(hasher.finalize()))),
+            Ok(n) => {
+                hasher.update(&buf[..n]);
+                if let Some(pieces) = pieces.as_mut() {
+                    pieces.update(&buf[..n]);
+                }
+                size += n as u64;
+            }
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn read_mode(path: &Path) -> Result<u32, ClientError> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let metadata =
+        fs::metadata(path).map_err(|err| ClientError::ErrReadingFile(err.to_string()))?;
+    Ok(metadata.permissions().mode() & 0o777)
+}
+
+#[cfg(not(unix))]
+fn read_mode(_path: &Path) -> Result<u32, ClientError> {
+    Ok(0o644)
+}
+
+#[cfg(unix)]
+fn write_mode(path: &Path, mode: u32) -> Result<(), ClientError> {
+    use std::os::unix::fs::PermissionsExt;
+
+    // Never hand out setuid, setgid or sticky bits received from a peer
+    fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o777))
+        .map_err(|err| ClientError::ErrWritingFile(err.to_string()))
+}
+
+#[cfg(not(unix))]
+fn write_mode(_path: &Path, _mode: u32) -> Result<(), ClientError> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Manifest, ManifestEntry, MAX_BUNDLE_SIZE, MAX_PIECE_COUNT};
+    use crate::protocol::PIECE_SIZE;
+
+    fn manifest(paths: &[&str]) -> Manifest {
+        Manifest {
+            entries: paths
+                .iter()
+                .map(|path| ManifestEntry {
+                    path: path.to_string(),
+                    size: 0,
+                    mode: 0o644,
+                    hash: String::new(),
+                })
+                .collect(),
+            pieces: vec![],
+        }
+    }
+
+    #[test]
+    fn test_rejects_path_traversal() {
+        assert!(manifest(&["dir/a.txt", "dir/sub/b.txt"]).validate().is_ok());
+        assert!(manifest(&["a.txt"]).validate().is_ok());
+
+        assert!(manifest(&["../a.txt"]).validate().is_err());
+        assert!(manifest(&["dir/../../a.txt"]).validate().is_err());
+        assert!(manifest(&["/etc/passwd"]).validate().is_err());
+        assert!(manifest(&["dir//a.txt"]).validate().is_err());
+        assert!(manifest(&["dir/./a.txt"]).validate().is_err());
+        assert!(manifest(&["..\\a.txt"]).validate().is_err());
+        assert!(manifest(&["C:/a.txt"]).validate().is_err());
+        assert!(manifest(&[""]).validate().is_err());
+        assert!(manifest(&["a.txt", "a.txt"]).validate().is_err());
+    }
+
+    #[test]
+    fn test_rejects_several_roots() {
+        assert!(manifest(&["dir/a.txt", "other/b.txt"]).validate().is_err());
+        assert!(manifest(&["a.txt", "b.txt"]).validate().is_err());
+        assert!(manifest(&["dir", "dir/a.txt"]).validate().is_err());
+        assert!(manifest(&["dir/a.txt", "dir"]).validate().is_err());
+    }
+
+    #[test]
+    fn test_rejects_oversized_bundles() {
+        let mut oversized = manifest(&["dir/a.bin", "dir/b.bin"]);
+        oversized.entries[0].size = u64::MAX;
+        oversized.entries[1].size = 1;
+        assert!(oversized.total_size().is_err());
+        assert!(oversized.validate().is_err());
+
+        oversized.entries[0].size = MAX_BUNDLE_SIZE;
+        oversized.entries[1].size = 1;
+        oversized.pieces = vec![String::new(); MAX_PIECE_COUNT + 1];
+        assert!(oversized.validate().is_err());
+
+        oversized.entries[0].size = PIECE_SIZE as u64;
+        oversized.pieces = vec![String::new()];
+        assert!(oversized.validate().is_err());
+
+        oversized.pieces = vec![String::new(); 2];
+        assert!(oversized.validate().is_ok());
+    }
+
+    #[test]
+    fn test_locate() {
+        let mut bundle = manifest(&["a", "empty", "b"]);
+        bundle.entries[0].size = 10;
+        bundle.entries[2].size = 10;
+
+        assert_eq!(bundle.locate(0, 4), vec![(0, 0, 0..4)]);
+        assert_eq!(bundle.locate(8, 4), vec![(0, 8, 0..2), (2, 0, 2..4)]);
+        assert_eq!(bundle.locate(16, 4), vec![(2, 6, 0..4)]);
+    }
+
+    #[test]
+    fn test_bundle_round_trip() {
+        let root = std::env::temp_dir().join(format!("turent-{}", uuid::Uuid::new_v4()));
+        let shared = root.join("shared");
+        std::fs::create_dir_all(shared.join("sub")).unwrap();
+        std::fs::write(shared.join("a.txt"), b"SUPER_SECRET_PASSWORD").unwrap();
+        std::fs::write(shared.join("sub/b.txt"), b"SUPERLY DUPERLY SECRET").unwrap();
+
+        let (manifest, paths) = Manifest::from_path(shared.to_str().unwrap()).unwrap();
+        assert_eq!(manifest.entries[0].path, "shared/a.txt");
+        assert_eq!(manifest.entries[1].path, "shared/sub/b.txt");
+
+        let mut contents = vec![];
+        for path in paths {
+            contents.extend(std::fs::read(path).unwrap());
+        }
+        assert_eq!(manifest.total_size().unwrap(), contents.len() as u64);
+        let bundle = root.join("bundle");
+        std::fs::write(&bundle, &contents).unwrap();
+
+        let received = root.join("received");
+        manifest.write(received.to_str().unwrap(), &bundle).unwrap();
+        assert_eq!(
+            std::fs::read(received.join("shared/sub/b.txt")).unwrap(),
+            b"SUPERLY DUPERLY SECRET"
+        );
+
+        // Tampered contents never make it to disk
+        contents[0] ^= 1;
+        std::fs::write(&bundle, &contents).unwrap();
+        assert!(manifest
+            .write(root.join("tampered").to_str().unwrap(), &bundle)
+            .is_err());
+        assert!(!root.join("tampered/shared/a.txt").exists());
+
+        // Nothing already there is overwritten
+        contents[0] ^= 1;
+        std::fs::write(&bundle, &contents).unwrap();
+        assert!(manifest.write(received.to_str().unwrap(), &bundle).is_err());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_follows_no_symlinks() {
+        let root = std::env::temp_dir().join(format!("turent-{}", uuid::Uuid::new_v4()));
+        let shared = root.join("shared");
+        std::fs::create_dir_all(&shared).unwrap();
+        std::fs::write(shared.join("a.txt"), b"SUPER_SECRET_PASSWORD").unwrap();
+
+        let (manifest, paths) = Manifest::from_path(shared.to_str().unwrap()).unwrap();
+        let bundle = root.join("bundle");
+        std::fs::copy(&paths[0], &bundle).unwrap();
+
+        let target = root.join("target");
+        std::fs::create_dir_all(&target).unwrap();
+        let received = root.join("received");
+        std::fs::create_dir_all(&received).unwrap();
+        std::os::unix::fs::symlink(&target, received.join("shared")).unwrap();
+
+        assert!(manifest.write(received.to_str().unwrap(), &bundle).is_err());
+        assert!(!target.join("a.txt").exists());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_shares_current_directory() {
+        let cwd = std::env::current_dir().unwrap();
+        let name = cwd.file_name().unwrap().to_str().unwrap();
+
+        let (manifest, _) = Manifest::from_path(".").unwrap();
+        assert!(!manifest.entries.is_empty());
+        assert!(manifest
+            .entries
+            .iter()
+            .all(|entry| entry.path.starts_with(&format!("{}/", name))));
+    }
+}
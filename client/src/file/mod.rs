@@ -1,2 +1,6 @@
 pub use file::*;
+pub use manifest::*;
+pub use pieces::*;
 pub mod file;
+pub mod manifest;
+pub mod pieces;
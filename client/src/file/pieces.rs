@@ -0,0 +1,328 @@
+use std::{
+    fs,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use bytes::Bytes;
+use common::entities::FileCompleteness;
+use tokio::sync::broadcast;
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+use crate::{
+    errors::ClientError,
+    protocol::{piece_count, Bitfield, PIECE_SIZE},
+};
+
+use super::Manifest;
+
+pub type SharedPieces = Arc<Mutex<PieceStore>>;
+
+/// Where the pieces of a bundle are kept, only the ones being sent or received are ever
+/// held in memory
+enum Storage {
+    /// In the files the bundle is made of, one for each entry of the manifest
+    Files(Vec<PathBuf>),
+    /// At their offset in a single file, until the bundle is complete
+    Partial(PathBuf),
+}
+
+/// Pieces of a single bundle, shared between the data sink downloading it and the data
+/// sources serving it, so a bundle can be seeded before it is completely downloaded.
+pub struct PieceStore {
+    // Directory the bundle is received in
+    dir: String,
+    manifest: Option<Manifest>,
+    storage: Storage,
+    bitfield: Bitfield,
+    have_tx: broadcast::Sender<u32>,
+}
+
+impl PieceStore {
+    /// Store for the bundle of `file_id` yet to be downloaded into `dir`, its contents
+    /// are only known once a data source sends its manifest
+    pub fn new(dir: String, file_id: Uuid) -> Self {
+        let partial = Path::new(&dir).join(format!(".{}.part", file_id));
+
+        Self::with_storage(dir, Storage::Partial(partial))
+    }
+
+    /// Store serving the file or directory at `path`
+    pub fn from_path(path: String) -> Result<Self, ClientError> {
+        let (manifest, paths) = Manifest::from_path(&path)?;
+        let size = manifest.total_size()?;
+        debug!(%path, size, "Bundled");
+
+        let mut store = Self::with_storage(String::from("."), Storage::Files(paths));
+        store.bitfield = Bitfield::full(piece_count(size));
+        store.manifest = Some(manifest);
+
+        Ok(store)
+    }
+
+    fn with_storage(dir: String, storage: Storage) -> Self {
+        let (have_tx, _) = broadcast::channel(64);
+
+        Self {
+            dir,
+            manifest: None,
+            storage,
+            bitfield: Bitfield::new(0),
+            have_tx,
+        }
+    }
+
+    pub fn shared(self) -> SharedPieces {
+        Arc::new(Mutex::new(self))
+    }
+
+    pub fn manifest(&self) -> Option<&Manifest> {
+        self.manifest.as_ref()
+    }
+
+    pub fn file_size(&self) -> Option<u64> {
+        self.manifest.as_ref()?.total_size().ok()
+    }
+
+    /// Sets the manifest of the bundle, returns true if it wasn't known before
+    pub fn set_manifest(&mut self, manifest: Manifest) -> Result<bool, ClientError> {
+        match &self.manifest {
+            Some(x) if *x == manifest => Ok(false),
+            Some(_) => Err(ClientError::ErrInvalidManifest(String::from(
+                "Manifest doesn't match the known one",
+            ))),
+            None => {
+                manifest.validate()?;
+
+                let size = manifest.total_size()?;
+                if let Storage::Partial(path) = &self.storage {
+                    fs::create_dir_all(&self.dir)
+                        .map_err(|err| ClientError::ErrWritingFile(err.to_string()))?;
+                    let file = fs::File::create(path)
+                        .map_err(|err| ClientError::ErrWritingFile(err.to_string()))?;
+                    file.set_len(size)
+                        .map_err(|err| ClientError::ErrWritingFile(err.to_string()))?;
+                }
+
+                self.manifest = Some(manifest);
+                self.bitfield = Bitfield::new(piece_count(size));
+                Ok(true)
+            }
+        }
+    }
+
+    pub fn bitfield(&self) -> &Bitfield {
+        &self.bitfield
+    }
+
+    /// Reads a piece the store has, None if it doesn't have it (yet)
+    pub fn get(&self, piece: u32) -> Result<Option<Bytes>, ClientError> {
+        if !self.bitfield.has(piece as usize) {
+            return Ok(None);
+        }
+
+        let (offset, len) = self.piece_range(piece)?;
+        let mut data = vec![0; len];
+        match (&self.storage, &self.manifest) {
+            (Storage::Partial(path), _) => read_at(path, offset, &mut data)
+                .map_err(|err| ClientError::ErrReadingFile(err.to_string()))?,
+            (Storage::Files(paths), Some(manifest)) => {
+                for (entry, entry_offset, range) in manifest.locate(offset, len) {
+                    read_at(&paths[entry], entry_offset, &mut data[range])
+                        .map_err(|err| ClientError::ErrReadingFile(err.to_string()))?;
+                }
+            }
+            (Storage::Files(_), None) => return Ok(None),
+        }
+
+        Ok(Some(Bytes::from(data)))
+    }
+
+    /// Stores a received piece, returns true if it wasn't already present
+    pub fn put(&mut self, piece: u32, data: Bytes) -> Result<bool, ClientError> {
+        let (offset, len) = self.piece_range(piece)?;
+        if data.len() != len {
+            return Err(ClientError::ErrInvalidPiece);
+        }
+
+        let index = piece as usize;
+        if self.bitfield.has(index) {
+            return Ok(false);
+        }
+
+        // Checked before it gets stored, let alone served to anyone else
+        match &self.manifest {
+            Some(manifest) if manifest.piece_matches(piece, &data) => {}
+            _ => return Err(ClientError::ErrPieceHashMismatch(piece)),
+        }
+
+        match &self.storage {
+            Storage::Partial(path) => write_at(path, offset, &data)
+                .map_err(|err| ClientError::ErrWritingFile(err.to_string()))?,
+            // Bundles served from their own files are complete
+            Storage::Files(_) => return Err(ClientError::ErrInvalidPiece),
+        }
+        self.bitfield.set(index);
+        // Nobody listening for new pieces is fine
+        let _ = self.have_tx.send(piece);
+
+        Ok(true)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.manifest.is_some() && self.bitfield.is_complete()
+    }
+
+    pub fn completeness(&self) -> FileCompleteness {
+        FileCompleteness {
+            have: self.bitfield.count() as u32,
+            total: self.bitfield.len() as u32,
+        }
+    }
+
+    /// Notifies about every piece put into the store from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<u32> {
+        self.have_tx.subscribe()
+    }
+
+    /// Deletes the pieces of a download which was cancelled, and lets whatever
+    /// subscribed to them know there won't be any more
+    pub fn discard(&mut self) {
+        if let Storage::Partial(path) = &self.storage {
+            remove_partial(path);
+        }
+        self.bitfield = Bitfield::new(self.bitfield.len());
+        self.have_tx = broadcast::channel(1).0;
+    }
+
+    /// What writing out the files of a completely downloaded bundle takes: its manifest,
+    /// the directory and the partial download they're written from
+    fn complete_bundle(&self) -> Result<(Manifest, String, PathBuf), ClientError> {
+        match (&self.manifest, &self.storage) {
+            (Some(manifest), Storage::Partial(path)) if self.is_complete() => {
+                Ok((manifest.clone(), self.dir.clone(), path.clone()))
+            }
+            _ => Err(ClientError::ErrWritingFile(String::from(
+                "bundle is not completely downloaded",
+            ))),
+        }
+    }
+
+    /// Offset and length of a piece within the bundle
+    fn piece_range(&self, piece: u32) -> Result<(u64, usize), ClientError> {
+        let file_size = self.file_size().ok_or(ClientError::ErrInvalidPiece)?;
+        if piece as usize >= self.bitfield.len() {
+            return Err(ClientError::ErrInvalidPiece);
+        }
+
+        let offset = piece as u64 * PIECE_SIZE as u64;
+        let len = std::cmp::min(PIECE_SIZE as u64, file_size - offset);
+
+        Ok((offset, len as usize))
+    }
+}
+
+/// Writes out the files of a completely downloaded bundle, which are served from then
+/// on. The store stays unlocked while they're written, its pieces are served from the
+/// partial download until then
+pub async fn build_file(pieces: &SharedPieces) -> Result<(), ClientError> {
+    let (manifest, dir, partial) = pieces
+        .lock()
+        .map_err(|_| ClientError::ErrAccessingPieces)?
+        .complete_bundle()?;
+
+    let paths = {
+        let partial = partial.clone();
+        tokio::task::spawn_blocking(move || manifest.write(&dir, &partial))
+            .await
+            .map_err(|err| ClientError::ErrWritingFile(err.to_string()))??
+    };
+
+    pieces
+        .lock()
+        .map_err(|_| ClientError::ErrAccessingPieces)?
+        .storage = Storage::Files(paths);
+    remove_partial(&partial);
+
+    Ok(())
+}
+
+fn read_at(path: &Path, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+    let mut file = fs::File::open(path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(buf)
+}
+
+fn write_at(path: &Path, offset: u64, data: &[u8]) -> io::Result<()> {
+    let mut file = fs::OpenOptions::new().write(true).create(true).open(path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(data)
+}
+
+fn remove_partial(path: &Path) {
+    match fs::remove_file(path) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => warn!(?err, path = ?path, "Error removing partial download"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use uuid::Uuid;
+
+    use super::{build_file, PieceStore};
+    use crate::errors::ClientError;
+
+    #[tokio::test]
+    async fn test_pieces_round_trip_through_disk() {
+        let root = std::env::temp_dir().join(format!("turent-{}", Uuid::new_v4()));
+        let shared = root.join("shared");
+        std::fs::create_dir_all(shared.join("sub")).unwrap();
+        let a: Vec<u8> = (0..50_000).map(|i| i as u8).collect();
+        let b: Vec<u8> = (0..30_000).map(|i| (i * 7) as u8).collect();
+        std::fs::write(shared.join("a.bin"), &a).unwrap();
+        std::fs::write(shared.join("sub/b.bin"), &b).unwrap();
+
+        let source = PieceStore::from_path(shared.to_str().unwrap().to_string()).unwrap();
+        let received = root.join("received");
+        std::fs::create_dir_all(&received).unwrap();
+        let file_id = Uuid::new_v4();
+        let mut sink = PieceStore::new(received.to_str().unwrap().to_string(), file_id);
+        sink.set_manifest(source.manifest().unwrap().clone())
+            .unwrap();
+
+        // The second piece spans both files
+        let count = source.bitfield().len() as u32;
+        assert_eq!(count, 3);
+        for piece in (0..count).rev() {
+            assert_eq!(sink.get(piece).unwrap(), None);
+            let data = source.get(piece).unwrap().unwrap();
+
+            let mut corrupt = data.to_vec();
+            corrupt[0] ^= 1;
+            assert!(matches!(
+                sink.put(piece, Bytes::from(corrupt)),
+                Err(ClientError::ErrPieceHashMismatch(x)) if x == piece
+            ));
+            assert_eq!(sink.get(piece).unwrap(), None);
+
+            assert!(sink.put(piece, data.clone()).unwrap());
+            assert_eq!(sink.get(piece).unwrap(), Some(data));
+        }
+
+        let sink = sink.shared();
+        build_file(&sink).await.unwrap();
+        assert_eq!(std::fs::read(received.join("shared/a.bin")).unwrap(), a);
+        assert_eq!(std::fs::read(received.join("shared/sub/b.bin")).unwrap(), b);
+        assert!(!received.join(format!(".{}.part", file_id)).exists());
+
+        // Built files are served from then on
+        assert_eq!(sink.lock().unwrap().get(1).unwrap(), source.get(1).unwrap());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,296 @@
+use std::{fmt, sync::Arc};
+
+use tracing::{info, warn, Span};
+use webrtc::{
+    api::setting_engine::SettingEngine,
+    ice::mdns::MulticastDnsMode,
+    ice_transport::{
+        ice_candidate_pair::RTCIceCandidatePair, ice_candidate_type::RTCIceCandidateType,
+        ice_server::RTCIceServer,
+    },
+    peer_connection::{
+        certificate::RTCCertificate, configuration::RTCConfiguration,
+        policy::ice_transport_policy::RTCIceTransportPolicy, RTCPeerConnection,
+    },
+    util::vnet::net::Net,
+};
+
+use crate::{
+    api::Api,
+    config::Config,
+    protocol::{CandidatePair, TransferStats},
+};
+
+use super::Timeouts;
+
+const DEFAULT_STUN_SERVER: &str = "stun:stun.l.google.com:19302";
+
+/// A network simulated by `webrtc::util::vnet`
+#[derive(Clone)]
+pub struct VirtualNet(pub Arc<Net>);
+
+impl fmt::Debug for VirtualNet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "VirtualNet")
+    }
+}
+
+/// How a peer connection gathers and picks candidates
+#[derive(Debug, Clone)]
+pub struct IceConfig {
+    pub ice_servers: Vec<RTCIceServer>,
+    // Only relayed candidates are used, for networks which forbid direct UDP
+    pub relay_only: bool,
+    pub vnet: Option<VirtualNet>,
+    pub timeouts: Timeouts,
+}
+
+impl IceConfig {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            ice_servers: configured_servers(config),
+            relay_only: config.relay_only,
+            vnet: config.vnet.clone(),
+            timeouts: Timeouts::from_config(config),
+        }
+    }
+
+    pub fn setting_engine(&self) -> SettingEngine {
+        let mut setting_engine = SettingEngine::default();
+        if let Some(vnet) = &self.vnet {
+            setting_engine.set_vnet(Some(Arc::clone(&vnet.0)));
+            // mDNS would still go out on the host's network
+            setting_engine.set_ice_multicast_dns_mode(MulticastDnsMode::Disabled);
+        }
+
+        setting_engine
+    }
+
+    pub fn rtc_configuration(&self, certificate: RTCCertificate) -> RTCConfiguration {
+        let ice_transport_policy = if self.relay_only {
+            RTCIceTransportPolicy::Relay
+        } else {
+            RTCIceTransportPolicy::All
+        };
+
+        RTCConfiguration {
+            ice_servers: self.ice_servers.clone(),
+            ice_transport_policy,
+            certificates: vec![certificate],
+            ..Default::default()
+        }
+    }
+
+    /// Relaying needs a TURN server, otherwise there is nothing to connect through
+    pub fn can_relay(&self) -> bool {
+        self.ice_servers.iter().any(|ice_server| {
+            ice_server
+                .urls
+                .iter()
+                .any(|url| url.starts_with("turn:") || url.starts_with("turns:"))
+        })
+    }
+}
+
+/// The ICE servers of the config, Google's public STUN server when there are none. An
+/// empty list means only host candidates, for networks where every peer is reachable.
+pub fn configured_servers(config: &Config) -> Vec<RTCIceServer> {
+    match &config.ice_servers {
+        Some(ice_servers) => ice_servers
+            .iter()
+            .cloned()
+            .map(|ice_server| ice_server.to_rtc_ice_server())
+            .collect(),
+        None => vec![RTCIceServer {
+            urls: vec![DEFAULT_STUN_SERVER.to_owned()],
+            ..Default::default()
+        }],
+    }
+}
+
+/// Adds the servers missing from `ice_servers`, like the ones a data source registered
+pub fn merge_servers(
+    mut ice_servers: Vec<RTCIceServer>,
+    others: Vec<RTCIceServer>,
+) -> Vec<RTCIceServer> {
+    for other in others {
+        if !ice_servers
+            .iter()
+            .any(|ice_server| ice_server.urls == other.urls)
+        {
+            ice_servers.push(other);
+        }
+    }
+
+    ice_servers
+}
+
+/// The STUN and TURN servers discovery hands out, with short-lived TURN credentials.
+/// None when the deployment has no TURN server or discovery can't be asked.
+pub async fn deployment_servers(api: &Api) -> Vec<RTCIceServer> {
+    match api.turn_credentials().await {
+        Ok(res) => res
+            .ice_servers
+            .into_iter()
+            .map(|ice_server| ice_server.to_rtc_ice_server())
+            .collect(),
+        Err(err) => {
+            warn!(?err, "Error fetching the ICE servers of discovery");
+            vec![]
+        }
+    }
+}
+
+/// Logs which kind of candidates a connection ended up using once they are picked, to
+/// tell direct connections from relayed ones, in the `span` of the peer. The transfer's
+/// `stats` keep them for its report.
+pub async fn report_candidate_pair(
+    peer_connection: &RTCPeerConnection,
+    span: Span,
+    stats: Arc<TransferStats>,
+) {
+    peer_connection
+        .sctp()
+        .transport()
+        .ice_transport()
+        .on_selected_candidate_pair_change(Box::new(move |pair: RTCIceCandidatePair| {
+            let (local, remote) = candidate_pair_types(&pair.to_string());
+            let path = path_type(local, remote);
+            span.in_scope(|| info!(%path, %local, %remote, "Selected candidate pair"));
+            stats.record_candidate_pair(CandidatePair {
+                path: path.to_string(),
+                local: local.to_string(),
+                remote: remote.to_string(),
+            });
+
+            Box::pin(async {})
+        }))
+        .await;
+}
+
+/// The candidate types of a pair, from how it's displayed since its fields are private:
+/// `(local) udp relay 1.2.3.4:5000 <-> (remote) udp host 10.0.0.2:6000`
+pub fn candidate_pair_types(pair: &str) -> (RTCIceCandidateType, RTCIceCandidateType) {
+    let mut candidates = pair.splitn(2, " <-> ");
+    let candidate_type = |candidate: Option<&str>| {
+        candidate
+            .and_then(|candidate| candidate.split_whitespace().nth(2))
+            .map(RTCIceCandidateType::from)
+            .unwrap_or_default()
+    };
+
+    (
+        candidate_type(candidates.next()),
+        candidate_type(candidates.next()),
+    )
+}
+
+/// The least direct of the two candidates, which is how the traffic actually flows
+pub fn path_type(local: RTCIceCandidateType, remote: RTCIceCandidateType) -> RTCIceCandidateType {
+    let rank = |candidate_type: RTCIceCandidateType| match candidate_type {
+        RTCIceCandidateType::Relay => 3,
+        RTCIceCandidateType::Srflx | RTCIceCandidateType::Prflx => 2,
+        RTCIceCandidateType::Host => 1,
+        RTCIceCandidateType::Unspecified => 0,
+    };
+
+    if rank(local) >= rank(remote) {
+        local
+    } else {
+        remote
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use webrtc::ice_transport::{
+        ice_candidate_type::RTCIceCandidateType, ice_server::RTCIceServer,
+    };
+
+    use super::{
+        candidate_pair_types, configured_servers, merge_servers, path_type, IceConfig, Timeouts,
+        DEFAULT_STUN_SERVER,
+    };
+    use crate::config::Config;
+
+    fn ice_server(url: &str) -> RTCIceServer {
+        RTCIceServer {
+            urls: vec![url.to_string()],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_configured_servers() {
+        let servers = configured_servers(&Config::default());
+        assert_eq!(servers[0].urls, vec![DEFAULT_STUN_SERVER.to_string()]);
+
+        let config =
+            Config::from_json(r#"{"iceServers": [{"urls": ["stun:10.0.0.1:3478"]}]}"#).unwrap();
+        let servers = configured_servers(&config);
+        assert_eq!(servers.len(), 1);
+        assert_eq!(servers[0].urls, vec!["stun:10.0.0.1:3478".to_string()]);
+
+        let config = Config::from_json(r#"{"iceServers": []}"#).unwrap();
+        assert!(configured_servers(&config).is_empty());
+    }
+
+    #[test]
+    fn test_merge_servers() {
+        let servers = merge_servers(
+            vec![ice_server("stun:a"), ice_server("stun:b")],
+            vec![ice_server("stun:b"), ice_server("stun:c")],
+        );
+
+        let urls: Vec<&str> = servers
+            .iter()
+            .map(|server| server.urls[0].as_str())
+            .collect();
+        assert_eq!(urls, vec!["stun:a", "stun:b", "stun:c"]);
+    }
+
+    #[test]
+    fn test_candidate_pair_types() {
+        let (local, remote) = candidate_pair_types(
+            "(local) udp relay 203.0.113.7:50000 <-> (remote) udp host 10.0.0.2:6000",
+        );
+        assert_eq!(local, RTCIceCandidateType::Relay);
+        assert_eq!(remote, RTCIceCandidateType::Host);
+        assert_eq!(path_type(local, remote), RTCIceCandidateType::Relay);
+
+        let (local, remote) = candidate_pair_types(
+            "(local) udp host 10.0.0.1:5000 <-> (remote) udp srflx 198.51.100.2:6000",
+        );
+        assert_eq!(path_type(local, remote), RTCIceCandidateType::Srflx);
+
+        assert_eq!(
+            candidate_pair_types("garbage"),
+            (
+                RTCIceCandidateType::Unspecified,
+                RTCIceCandidateType::Unspecified
+            )
+        );
+    }
+
+    #[test]
+    fn test_can_relay() {
+        let ice_config = IceConfig {
+            ice_servers: vec![ice_server("stun:a")],
+            relay_only: true,
+            vnet: None,
+            timeouts: Timeouts::default(),
+        };
+        assert!(!ice_config.can_relay());
+
+        let ice_config = IceConfig {
+            ice_servers: vec![
+                ice_server("stun:a"),
+                ice_server("turn:b:3478?transport=udp"),
+            ],
+            relay_only: true,
+            vnet: None,
+            timeouts: Timeouts::default(),
+        };
+        assert!(ice_config.can_relay());
+    }
+}
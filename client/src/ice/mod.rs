@@ -0,0 +1,4 @@
+pub use ice::*;
+pub use watchdog::*;
+mod ice;
+mod watchdog;
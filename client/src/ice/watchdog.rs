@@ -0,0 +1,189 @@
+use std::{
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::{debug, warn, Instrument};
+use uuid::Uuid;
+use webrtc::peer_connection::{peer_connection_state::RTCPeerConnectionState, RTCPeerConnection};
+
+use crate::config::Config;
+
+const DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_INACTIVITY_TIMEOUT_SECS: u64 = 60;
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Ids of peer connections which closed, for their manager to drop them
+pub type ClosedTx = UnboundedSender<Uuid>;
+
+/// How long a peer connection gets to connect, and how long a connected peer may stay
+/// quiet while something is expected from it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timeouts {
+    pub connect: Duration,
+    pub inactivity: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            connect: Duration::from_secs(DEFAULT_CONNECT_TIMEOUT_SECS),
+            inactivity: Duration::from_secs(DEFAULT_INACTIVITY_TIMEOUT_SECS),
+        }
+    }
+}
+
+impl Timeouts {
+    pub fn from_config(config: &Config) -> Self {
+        let default = Self::default();
+
+        Self {
+            connect: config
+                .connect_timeout_secs
+                .map_or(default.connect, Duration::from_secs),
+            inactivity: config
+                .inactivity_timeout_secs
+                .map_or(default.inactivity, Duration::from_secs),
+        }
+    }
+}
+
+/// When a peer was last heard from
+#[derive(Debug)]
+pub struct Activity {
+    last: Mutex<Instant>,
+}
+
+impl Default for Activity {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Activity {
+    pub fn new() -> Self {
+        Self {
+            last: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub fn touch(&self) {
+        if let Ok(mut last) = self.last.lock() {
+            *last = Instant::now();
+        }
+    }
+
+    pub fn idle(&self) -> Duration {
+        self.last
+            .lock()
+            .map(|last| last.elapsed())
+            .unwrap_or_default()
+    }
+}
+
+/// Closes a peer connection which doesn't connect in time, or goes quiet for longer than
+/// the inactivity timeout while `expecting` something from the peer. Returns once the
+/// peer connection is closed or dropped.
+pub async fn watch<F>(
+    peer_connection: Weak<RTCPeerConnection>,
+    timeouts: Timeouts,
+    activity: Arc<Activity>,
+    expecting: F,
+) where
+    F: Fn() -> bool,
+{
+    let started = Instant::now();
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let peer_connection = match peer_connection.upgrade() {
+            Some(x) => x,
+            None => return,
+        };
+
+        let timed_out = match peer_connection.connection_state() {
+            RTCPeerConnectionState::Closed => return,
+            RTCPeerConnectionState::New | RTCPeerConnectionState::Connecting => {
+                started.elapsed() > timeouts.connect
+            }
+            RTCPeerConnectionState::Connected if expecting() => {
+                activity.idle() > timeouts.inactivity
+            }
+            // Silence which isn't waited on doesn't count
+            RTCPeerConnectionState::Connected => {
+                activity.touch();
+                false
+            }
+            // Failures are taken care of as they're reported
+            _ => false,
+        };
+
+        if timed_out {
+            warn!(state = %peer_connection.connection_state(), "Peer connection timed out");
+            if let Err(err) = peer_connection.close().await {
+                warn!(?err, "Error closing peer connection");
+            }
+            return;
+        }
+    }
+}
+
+/// Closes a peer connection which failed, or which got disconnected and doesn't
+/// reconnect within the connect timeout, and reports it once it's closed, however that
+/// came about
+pub fn on_state_change(
+    state: RTCPeerConnectionState,
+    peer_connection: &Weak<RTCPeerConnection>,
+    id: Uuid,
+    timeouts: Timeouts,
+    closed_tx: &ClosedTx,
+) {
+    let grace = match state {
+        RTCPeerConnectionState::Failed => Duration::ZERO,
+        RTCPeerConnectionState::Disconnected => timeouts.connect,
+        RTCPeerConnectionState::Closed => {
+            // Nothing is left to drop it from once the engine stopped
+            if closed_tx.send(id).is_err() {
+                debug!("Error reporting closed peer connection");
+            }
+            return;
+        }
+        _ => return,
+    };
+
+    // Closing waits for the state change handler, which is still running
+    let peer_connection = peer_connection.clone();
+    tokio::spawn(
+        async move {
+            tokio::time::sleep(grace).await;
+            let peer_connection = match peer_connection.upgrade() {
+                Some(x) if x.connection_state() == state => x,
+                _ => return,
+            };
+
+            if let Err(err) = peer_connection.close().await {
+                warn!(?err, "Error closing peer connection");
+            }
+        }
+        .in_current_span(),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Timeouts;
+    use crate::config::Config;
+
+    #[test]
+    fn test_timeouts_from_config() {
+        let config = Config::from_json(r#"{"connectTimeoutSecs": 5}"#).unwrap();
+        let timeouts = Timeouts::from_config(&config);
+        assert_eq!(timeouts.connect, Duration::from_secs(5));
+        assert_eq!(timeouts.inactivity, Timeouts::default().inactivity);
+    }
+}
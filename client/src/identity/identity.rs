@@ -0,0 +1,251 @@
+use std::{
+    fs,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use common::models::{DeregisterServerReq, RegisterOrRefreshServerReq, ShareToken};
+use rcgen::{CertificateParams, KeyPair, PKCS_ED25519};
+use ring::signature::{Ed25519KeyPair, KeyPair as _};
+use tracing::info;
+use uuid::Uuid;
+use webrtc::peer_connection::certificate::RTCCertificate;
+
+use crate::{config::turent_dir, errors::ClientError};
+
+const IDENTITY_FILE: &str = "identity.pem";
+
+/// The keypair a client is known by, its DTLS certificate is derived from it so the
+/// fingerprint published in discovery stays the same across restarts.
+#[derive(Clone)]
+pub struct Identity {
+    key_pair_pem: String,
+}
+
+impl Identity {
+    /// Loads the keypair from `~/.turent/identity.pem`, generating it on first use
+    pub fn load_or_generate() -> Result<Self, ClientError> {
+        let dir = turent_dir();
+        let path = dir.join(IDENTITY_FILE);
+
+        if let Ok(key_pair_pem) = fs::read_to_string(&path) {
+            return Self::from_pem(key_pair_pem);
+        }
+
+        let key_pair_pem = Self::generate()?.key_pair_pem;
+
+        fs::create_dir_all(&dir).map_err(|err| ClientError::ErrIdentity(err.to_string()))?;
+        fs::write(&path, &key_pair_pem).map_err(|err| ClientError::ErrIdentity(err.to_string()))?;
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o600))
+                .map_err(|err| ClientError::ErrIdentity(err.to_string()))?;
+        }
+
+        info!(?path, "Generated new identity");
+
+        Ok(Self { key_pair_pem })
+    }
+
+    /// A new keypair which only lives in memory
+    pub fn generate() -> Result<Self, ClientError> {
+        let key_pair = KeyPair::generate(&PKCS_ED25519)
+            .map_err(|err| ClientError::ErrIdentity(err.to_string()))?;
+
+        Ok(Self {
+            key_pair_pem: key_pair.serialize_pem(),
+        })
+    }
+
+    pub fn from_pem(key_pair_pem: String) -> Result<Self, ClientError> {
+        let identity = Self { key_pair_pem };
+        // Fail early on a corrupted file rather than on the first connection
+        identity.key_pair()?;
+        Ok(identity)
+    }
+
+    /// A certificate for a new peer connection, every one of them is the same since
+    /// Ed25519 signatures are deterministic and the parameters are fixed
+    pub fn certificate(&self) -> Result<RTCCertificate, ClientError> {
+        let mut params = CertificateParams::new(vec!["turent".to_string()]);
+        params.alg = &PKCS_ED25519;
+        params.key_pair = Some(self.key_pair()?);
+
+        RTCCertificate::from_params(params).map_err(|err| ClientError::WebRTCError(err))
+    }
+
+    /// The fingerprint of the certificate as it appears in SDP, `sha-256 ab:cd:..`
+    pub fn fingerprint(&self) -> Result<String, ClientError> {
+        let fingerprints = self
+            .certificate()?
+            .get_fingerprints()
+            .map_err(|err| ClientError::WebRTCError(err))?;
+
+        let fingerprint = fingerprints.first().ok_or_else(|| {
+            ClientError::ErrIdentity("Certificate has no fingerprint".to_string())
+        })?;
+
+        Ok(format!("{} {}", fingerprint.algorithm, fingerprint.value))
+    }
+
+    /// The hex encoded public key discovery binds server ids to
+    pub fn public_key(&self) -> Result<String, ClientError> {
+        Ok(hex::encode(self.signing_key()?.public_key().as_ref()))
+    }
+
+    /// Signs a registration so that discovery only lets this identity refresh it
+    pub fn sign_registration(
+        &self,
+        req: &mut RegisterOrRefreshServerReq,
+    ) -> Result<(), ClientError> {
+        let signing_key = self.signing_key()?;
+
+        req.public_key = hex::encode(signing_key.public_key().as_ref());
+        req.timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|err| ClientError::ErrIdentity(err.to_string()))?
+            .as_secs();
+        req.signature = hex::encode(signing_key.sign(&req.signing_payload()).as_ref());
+
+        Ok(())
+    }
+
+    /// Signs a deregistration, discovery only accepts it from the key which registered
+    pub fn sign_deregistration(&self, req: &mut DeregisterServerReq) -> Result<(), ClientError> {
+        let signing_key = self.signing_key()?;
+
+        req.public_key = hex::encode(signing_key.public_key().as_ref());
+        req.timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|err| ClientError::ErrIdentity(err.to_string()))?
+            .as_secs();
+        req.signature = hex::encode(signing_key.sign(&req.signing_payload()).as_ref());
+
+        Ok(())
+    }
+
+    /// A token letting its bearer find a file registered as private by this identity
+    pub fn mint_share_token(
+        &self,
+        file_id: Uuid,
+        expires_at: u64,
+        max_downloads: Option<u32>,
+    ) -> Result<ShareToken, ClientError> {
+        let mut token = ShareToken {
+            token_id: Uuid::new_v4().to_string(),
+            file_id: file_id.to_string(),
+            expires_at,
+            max_downloads,
+            signature: String::new(),
+        };
+        token.signature = hex::encode(self.signing_key()?.sign(&token.signing_payload()).as_ref());
+
+        Ok(token)
+    }
+
+    fn signing_key(&self) -> Result<Ed25519KeyPair, ClientError> {
+        Ed25519KeyPair::from_pkcs8_maybe_unchecked(&self.key_pair()?.serialize_der())
+            .map_err(|err| ClientError::ErrIdentity(err.to_string()))
+    }
+
+    fn key_pair(&self) -> Result<KeyPair, ClientError> {
+        KeyPair::from_pem(&self.key_pair_pem)
+            .map_err(|err| ClientError::ErrIdentity(err.to_string()))
+    }
+}
+
+/// Makes sure every fingerprint in a remote session description is the registered one,
+/// otherwise the DTLS handshake could be completed by anyone able to tamper with
+/// signalling
+pub fn check_fingerprint(sdp: &str, registered: &str) -> Result<(), ClientError> {
+    let registered = registered.trim();
+    if registered.is_empty() {
+        return Err(ClientError::ErrFingerprintMismatch);
+    }
+
+    let mut found = false;
+    for line in sdp.lines() {
+        if let Some(fingerprint) = line.trim().strip_prefix("a=fingerprint:") {
+            if !fingerprint.trim().eq_ignore_ascii_case(registered) {
+                return Err(ClientError::ErrFingerprintMismatch);
+            }
+            found = true;
+        }
+    }
+
+    if !found {
+        return Err(ClientError::ErrFingerprintMismatch);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use common::models::RegisterOrRefreshServerReq;
+    use rcgen::{KeyPair, PKCS_ED25519};
+    use ring::signature::{UnparsedPublicKey, ED25519};
+
+    use super::{check_fingerprint, Identity};
+
+    #[test]
+    fn test_fingerprint_is_stable() {
+        let key_pair = KeyPair::generate(&PKCS_ED25519).unwrap();
+        let identity = Identity::from_pem(key_pair.serialize_pem()).unwrap();
+
+        let fingerprint = identity.fingerprint().unwrap();
+        assert!(fingerprint.starts_with("sha-256 "));
+        assert_eq!(
+            Identity::from_pem(key_pair.serialize_pem())
+                .unwrap()
+                .fingerprint()
+                .unwrap(),
+            fingerprint
+        );
+
+        let other =
+            Identity::from_pem(KeyPair::generate(&PKCS_ED25519).unwrap().serialize_pem()).unwrap();
+        assert_ne!(other.fingerprint().unwrap(), fingerprint);
+    }
+
+    #[test]
+    fn test_check_fingerprint() {
+        let registered = "sha-256 ab:cd:ef";
+        let sdp = "v=0\r\na=fingerprint:sha-256 AB:CD:EF\r\nm=application 9\r\na=fingerprint:sha-256 ab:cd:ef\r\n";
+        assert!(check_fingerprint(sdp, registered).is_ok());
+
+        let sdp = "v=0\r\na=fingerprint:sha-256 ab:cd:ef\r\na=fingerprint:sha-256 12:34:56\r\n";
+        assert!(check_fingerprint(sdp, registered).is_err());
+
+        assert!(check_fingerprint("v=0\r\n", registered).is_err());
+        assert!(check_fingerprint("a=fingerprint:sha-256 ab:cd:ef", "").is_err());
+    }
+
+    #[test]
+    fn test_sign_registration() {
+        let key_pair = KeyPair::generate(&PKCS_ED25519).unwrap();
+        let identity = Identity::from_pem(key_pair.serialize_pem()).unwrap();
+
+        let mut req = RegisterOrRefreshServerReq {
+            server_id: String::from("67e55044-10b1-426f-9247-bb680e5fe0c8"),
+            files: None,
+            ice_candidates: None,
+            url: String::from("http://localhost:8080"),
+            completeness: None,
+            fingerprint: None,
+            private_files: None,
+            public_key: String::new(),
+            timestamp: 0,
+            signature: String::new(),
+        };
+        identity.sign_registration(&mut req).unwrap();
+
+        assert_eq!(req.public_key, identity.public_key().unwrap());
+        let public_key = hex::decode(&req.public_key).unwrap();
+        let signature = hex::decode(&req.signature).unwrap();
+        assert!(UnparsedPublicKey::new(&ED25519, public_key)
+            .verify(&req.signing_payload(), &signature)
+            .is_ok());
+    }
+}
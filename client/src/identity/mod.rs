@@ -0,0 +1,2 @@
+pub use identity::*;
+mod identity;
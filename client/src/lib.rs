@@ -0,0 +1,14 @@
+#![feature(proc_macro_hygiene, decl_macro, async_closure)]
+
+pub mod api;
+pub mod code;
+pub mod config;
+pub mod control;
+pub mod datasink;
+pub mod datasource;
+pub mod engine;
+pub mod errors;
+pub mod file;
+pub mod ice;
+pub mod identity;
+pub mod protocol;
@@ -1,20 +1,15 @@
-#![feature(proc_macro_hygiene, decl_macro, async_closure)]
-
-mod api;
-mod datasink;
-mod datasource;
-mod engine;
-mod errors;
-mod file;
-// #[cfg(test)]
-// mod tests;
-
 use std::env::args;
 
-use engine::Engine;
+use client::{
+    code::parse_code,
+    engine::{Engine, ShareAccess, Task, DEFAULT_SHARE_EXPIRY_SECS},
+    errors::ClientError,
+};
+use common::logger::{self, LogConfig};
+use uuid::{uuid, Uuid};
 
-use errors::ClientError;
-use uuid::uuid;
+const DEFAULT_FILE_ID: Uuid = uuid!("67e55044-10b1-426f-9247-bb680e5ff1b8");
+const DEFAULT_DOWNLOAD_DIR: &str = "received";
 
 // use webrtc::{
 //     self, data_channel::RTCDataChannel, ice_transport::ice_server::RTCIceServer,
@@ -46,6 +41,128 @@ fn parse_arg(argument: String) -> (Option<bool>, Option<bool>) {
     return (init_client, init_server);
 }
 
+fn parse_seed_arg(arguments: &[String]) -> bool {
+    let mut seed = false;
+
+    for arg in arguments {
+        let arg_str_vec: Vec<&str> = arg.split("=").collect();
+        if arg_str_vec[0] == "seed" && arg_str_vec.len() > 1 {
+            seed = parse_value(arg_str_vec[1]);
+        }
+    }
+
+    seed
+}
+
+fn parse_value_arg(arguments: &[String], name: &str) -> Option<String> {
+    let mut value = None;
+
+    for arg in arguments {
+        // Values may contain `=` themselves
+        let arg_str_vec: Vec<&str> = arg.splitn(2, "=").collect();
+        if arg_str_vec[0] == name && arg_str_vec.len() > 1 {
+            value = Some(arg_str_vec[1].to_string());
+        }
+    }
+
+    value
+}
+
+fn parse_passphrase_arg(arguments: &[String]) -> Option<String> {
+    parse_value_arg(arguments, "passphrase")
+}
+
+/// `private=true` along with `expires=<seconds>` and `downloads=<count>`
+fn parse_access_args(arguments: &[String]) -> Result<Option<ShareAccess>, ClientError> {
+    let private = parse_value_arg(arguments, "private").map(|value| parse_value(&value));
+    if private != Some(true) {
+        return Ok(None);
+    }
+
+    let expires_in_secs = match parse_value_arg(arguments, "expires") {
+        Some(expires) => expires
+            .parse()
+            .map_err(|_| ClientError::InvalidConfiguration)?,
+        None => DEFAULT_SHARE_EXPIRY_SECS,
+    };
+
+    let max_downloads = match parse_value_arg(arguments, "downloads") {
+        Some(downloads) => Some(
+            downloads
+                .parse()
+                .map_err(|_| ClientError::InvalidConfiguration)?,
+        ),
+        None => None,
+    };
+
+    Ok(Some(ShareAccess {
+        expires_in_secs,
+        max_downloads,
+    }))
+}
+
+/// Parses `share <path>`, `fetch <file_id or code> [dir]` and `daemon`, along with a
+/// `passphrase=` flag, the access flags of private shares and the `token=` to fetch
+/// them with
+fn parse_task(arguments: &[String]) -> Result<Option<Task>, ClientError> {
+    let position = arguments
+        .iter()
+        .position(|arg| arg == "share" || arg == "fetch" || arg == "daemon");
+
+    let position = match position {
+        Some(position) => position,
+        None => return Ok(None),
+    };
+
+    if arguments[position] == "daemon" {
+        return Ok(Some(Task::Daemon));
+    }
+
+    let passphrase = parse_passphrase_arg(arguments);
+    let share_token = parse_value_arg(arguments, "token");
+    let rest: Vec<&String> = arguments[position + 1..]
+        .iter()
+        .filter(|arg| !arg.contains('='))
+        .collect();
+    if arguments[position] == "share" {
+        let path = rest.get(0).ok_or(ClientError::InvalidConfiguration)?;
+
+        return Ok(Some(Task::Share {
+            file_id: Uuid::new_v4(),
+            path: path.to_string(),
+            passphrase,
+            with_code: true,
+            access: parse_access_args(arguments)?,
+        }));
+    }
+
+    let file = rest.get(0).ok_or(ClientError::InvalidConfiguration)?;
+    let download_dir = rest
+        .get(1)
+        .map(|download_dir| download_dir.to_string())
+        .unwrap_or_else(|| DEFAULT_DOWNLOAD_DIR.to_string());
+
+    if let Ok(file_id) = Uuid::parse_str(file) {
+        return Ok(Some(Task::Fetch {
+            file_id,
+            download_dir,
+            passphrase,
+            share_token,
+        }));
+    }
+
+    if parse_code(file).is_some() {
+        return Ok(Some(Task::FetchCode {
+            code: file.to_string(),
+            download_dir,
+            passphrase,
+            share_token,
+        }));
+    }
+
+    Err(ClientError::InvalidConfiguration)
+}
+
 fn parse_args(arguments: Vec<String>) -> (bool, bool) {
     let mut init_client = false;
     let mut init_server = false;
@@ -64,19 +181,57 @@ fn parse_args(arguments: Vec<String>) -> (bool, bool) {
 
 #[tokio::main]
 async fn main() -> Result<(), ClientError> {
+    logger::init(&LogConfig::from_env("info"))
+        .map_err(|err| ClientError::ErrWritingFile(err.to_string()))?;
+
     let arguments: Vec<String> = args().collect();
     let init_client;
     let init_server;
 
+    let seed = parse_seed_arg(&arguments);
+    let passphrase = parse_passphrase_arg(&arguments);
+    let task = parse_task(&arguments)?;
     (init_client, init_server) = parse_args(arguments);
 
+    // Without a task the old flags share the example file, or fetch it
+    let (init_client, init_server, task) = match task {
+        Some(task @ Task::Share { .. }) => (false, true, task),
+        Some(task @ Task::Fetch { .. }) | Some(task @ Task::FetchCode { .. }) => {
+            (true, false, task)
+        }
+        // Shares and downloads alike
+        Some(task @ Task::Daemon) => (true, true, task),
+        None if init_server => (
+            init_client,
+            init_server,
+            Task::Share {
+                file_id: DEFAULT_FILE_ID,
+                path: "example_file.txt".to_string(),
+                passphrase,
+                with_code: false,
+                access: None,
+            },
+        ),
+        None => (
+            init_client,
+            init_server,
+            Task::Fetch {
+                file_id: DEFAULT_FILE_ID,
+                download_dir: DEFAULT_DOWNLOAD_DIR.to_string(),
+                passphrase,
+                share_token: None,
+            },
+        ),
+    };
+
     Engine::new(
         Some(uuid!("67e55044-10b1-426f-9247-bb680e5fe0c8")),
         init_client,
         init_server,
+        seed,
     )
     .await?
-    .start()
+    .start(task)
     .await?;
     //
 
@@ -156,9 +311,99 @@ mod tests {
         assert!(res.0 && !res.1);
     }
 
+    #[test]
+    fn test_seed_flag_parsing() {
+        assert!(parse_seed_arg(&[
+            String::from("init_client=true"),
+            String::from("seed=true"),
+        ]));
+
+        assert!(!parse_seed_arg(&[String::from("seed=false")]));
+
+        assert!(!parse_seed_arg(&[String::from("init_client=true")]));
+    }
+
+    #[test]
+    fn test_task_parsing() {
+        let res = parse_task(&[String::from("share"), String::from("photos")]).unwrap();
+        assert!(matches!(res, Some(Task::Share { path, .. }) if path == "photos"));
+
+        let res = parse_task(&[
+            String::from("fetch"),
+            String::from("67e55044-10b1-426f-9247-bb680e5ff1b8"),
+        ])
+        .unwrap();
+        assert!(matches!(
+            res,
+            Some(Task::Fetch { file_id, download_dir, passphrase: None, share_token: None })
+                if file_id == DEFAULT_FILE_ID && download_dir == DEFAULT_DOWNLOAD_DIR
+        ));
+
+        let res = parse_task(&[
+            String::from("fetch"),
+            String::from("passphrase=purple=monkey"),
+            String::from("67e55044-10b1-426f-9247-bb680e5ff1b8"),
+            String::from("downloads"),
+        ])
+        .unwrap();
+        assert!(matches!(
+            res,
+            Some(Task::Fetch { download_dir, passphrase: Some(passphrase), .. })
+                if download_dir == "downloads" && passphrase == "purple=monkey"
+        ));
+
+        let res = parse_task(&[String::from("fetch"), String::from("7-purple-sausages")]).unwrap();
+        assert!(matches!(
+            res,
+            Some(Task::FetchCode { code, passphrase: None, .. }) if code == "7-purple-sausages"
+        ));
+
+        let res = parse_task(&[
+            String::from("share"),
+            String::from("photos"),
+            String::from("private=true"),
+            String::from("downloads=3"),
+        ])
+        .unwrap();
+        assert!(matches!(
+            res,
+            Some(Task::Share { access: Some(access), .. }) if access == ShareAccess {
+                expires_in_secs: DEFAULT_SHARE_EXPIRY_SECS,
+                max_downloads: Some(3),
+            }
+        ));
+
+        let res = parse_task(&[
+            String::from("fetch"),
+            String::from("67e55044-10b1-426f-9247-bb680e5ff1b8"),
+            String::from("token=abcd"),
+        ])
+        .unwrap();
+        assert!(matches!(
+            res,
+            Some(Task::Fetch { share_token: Some(share_token), .. }) if share_token == "abcd"
+        ));
+
+        assert!(parse_task(&[
+            String::from("share"),
+            String::from("photos"),
+            String::from("private=true"),
+            String::from("expires=soon"),
+        ])
+        .is_err());
+        assert!(parse_task(&[String::from("fetch"), String::from("foo")]).is_err());
+        assert!(parse_task(&[String::from("share")]).is_err());
+        assert!(parse_task(&[String::from("init_client=true")])
+            .unwrap()
+            .is_none());
+    }
+
     // fn test_creation_of_server_client_acc_to_flags() {
 
     // }
 
-    use crate::parse_args;
+    use crate::{
+        parse_args, parse_seed_arg, parse_task, ShareAccess, Task, DEFAULT_DOWNLOAD_DIR,
+        DEFAULT_FILE_ID, DEFAULT_SHARE_EXPIRY_SECS,
+    };
 }
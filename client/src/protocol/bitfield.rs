@@ -0,0 +1,80 @@
+use crate::errors::ClientError;
+
+/// Tracks which pieces of a file a peer has, one bit per piece, most significant bit
+/// of the first byte being piece 0.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bitfield {
+    bits: Vec<u8>,
+    len: usize,
+}
+
+impl Bitfield {
+    pub fn new(len: usize) -> Self {
+        Self {
+            bits: vec![0; (len + 7) / 8],
+            len,
+        }
+    }
+
+    pub fn full(len: usize) -> Self {
+        let mut bitfield = Self::new(len);
+        for index in 0..len {
+            bitfield.set(index);
+        }
+        bitfield
+    }
+
+    pub fn from_bytes(len: usize, bytes: &[u8]) -> Result<Self, ClientError> {
+        if bytes.len() != (len + 7) / 8 {
+            return Err(ClientError::ErrInvalidMessage);
+        }
+
+        let bitfield = Self {
+            bits: bytes.to_vec(),
+            len,
+        };
+
+        // Spare bits at the end of the last byte must not be set
+        if (len..bytes.len() * 8).any(|index| bitfield.get_bit(index)) {
+            return Err(ClientError::ErrInvalidMessage);
+        }
+
+        Ok(bitfield)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn has(&self, index: usize) -> bool {
+        index < self.len && self.get_bit(index)
+    }
+
+    pub fn set(&mut self, index: usize) {
+        if index < self.len {
+            self.bits[index / 8] |= 0x80 >> (index % 8);
+        }
+    }
+
+    pub fn clear(&mut self, index: usize) {
+        if index < self.len {
+            self.bits[index / 8] &= !(0x80 >> (index % 8));
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.bits.iter().map(|x| x.count_ones() as usize).sum()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.count() == self.len
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bits
+    }
+
+    fn get_bit(&self, index: usize) -> bool {
+        self.bits[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+}
@@ -0,0 +1,317 @@
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use bytes::{Bytes, BytesMut};
+use webrtc::data_channel::RTCDataChannel;
+
+use crate::errors::ClientError;
+
+use super::{ChannelCrypto, Compression, Message, Opened};
+
+// Larger messages, like the manifest of a large bundle, are sent in fragments which stay
+// under the 64KiB SCTP message size limit once sealed
+const MAX_FRAGMENT_SIZE: usize = 48 * 1024;
+// Caps what a peer can make a channel buffer to put a message back together
+const MAX_MESSAGE_SIZE: usize = 64 * 1024 * 1024;
+
+/// What a received message amounted to
+pub enum Received {
+    Message(Message),
+    /// The passphrase handshake is done, application messages can be sent now
+    Ready,
+    Nothing,
+}
+
+/// A data channel along with the encryption of its messages.
+#[derive(Clone)]
+pub struct Channel {
+    wire: Wire,
+    crypto: Arc<Mutex<ChannelCrypto>>,
+    fragments: Arc<Mutex<Fragments>>,
+    // Held from sealing a message until it's on the wire, sealed messages are numbered
+    // and the peer only opens them in that order. Keeps fragments of different messages
+    // from interleaving as well
+    sending: Arc<tokio::sync::Mutex<()>>,
+}
+
+/// Where the messages of a channel go
+#[derive(Clone)]
+enum Wire {
+    DataChannel(Arc<RTCDataChannel>),
+    #[cfg(test)]
+    Recorded(Arc<tests::Recorded>),
+}
+
+impl Channel {
+    pub fn new(data_channel: Arc<RTCDataChannel>, crypto: ChannelCrypto) -> Self {
+        Self::with_wire(Wire::DataChannel(data_channel), crypto)
+    }
+
+    fn with_wire(wire: Wire, crypto: ChannelCrypto) -> Self {
+        Self {
+            wire,
+            crypto: Arc::new(Mutex::new(crypto)),
+            fragments: Arc::new(Mutex::new(Fragments::default())),
+            sending: Arc::new(tokio::sync::Mutex::new(())),
+        }
+    }
+
+    /// Starts the passphrase handshake once the data channel opened, returns whether the
+    /// channel is ready right away
+    pub async fn start(&self) -> Result<bool, ClientError> {
+        let pake = {
+            let mut crypto = self.lock()?;
+            if crypto.is_plain() {
+                return Ok(true);
+            }
+            crypto.start()
+        };
+
+        if let Some(pake) = pake {
+            let _sending = self.sending.lock().await;
+            self.send_raw(pake.encode()).await?;
+        }
+
+        Ok(false)
+    }
+
+    pub async fn send(&self, message: &Message) -> Result<usize, ClientError> {
+        self.send_with(message, Compression::None).await
+    }
+
+    /// Sends a message, returns the number of bytes which went on the wire
+    pub async fn send_with(
+        &self,
+        message: &Message,
+        compression: Compression,
+    ) -> Result<usize, ClientError> {
+        let encoded = message.encode_with(compression);
+
+        let _sending = self.sending.lock().await;
+        if encoded.len() <= MAX_FRAGMENT_SIZE {
+            return self.send_encoded(encoded).await;
+        }
+
+        let mut wire_bytes = 0;
+        for fragment in fragment(encoded) {
+            wire_bytes += self.send_encoded(fragment.encode()).await?;
+        }
+
+        Ok(wire_bytes)
+    }
+
+    pub async fn receive(&self, data: Bytes) -> Result<Received, ClientError> {
+        let opened = self.lock()?.open(data)?;
+
+        match opened {
+            Opened::Message(Message::Fragment { last, data }) => {
+                let message = self
+                    .fragments
+                    .lock()
+                    .map_err(|_| ClientError::ErrAccessingChannel)?
+                    .push(last, &data)?;
+                match message {
+                    Some(message) => Ok(Received::Message(message)),
+                    None => Ok(Received::Nothing),
+                }
+            }
+            Opened::Message(message) => Ok(Received::Message(message)),
+            Opened::Reply(message) => {
+                let _sending = self.sending.lock().await;
+                self.send_raw(message.encode()).await?;
+                Ok(Received::Nothing)
+            }
+            Opened::Confirmed => Ok(Received::Ready),
+        }
+    }
+
+    pub async fn close(&self) -> Result<(), ClientError> {
+        match &self.wire {
+            Wire::DataChannel(data_channel) => data_channel
+                .close()
+                .await
+                .map_err(|err| ClientError::WebRTCError(err)),
+            #[cfg(test)]
+            Wire::Recorded(_) => Ok(()),
+        }
+    }
+
+    // Callers hold `sending`
+    async fn send_encoded(&self, encoded: Bytes) -> Result<usize, ClientError> {
+        let sealed = self.lock()?.seal(encoded)?;
+        let wire_bytes = sealed.len();
+
+        self.send_raw(sealed).await?;
+
+        Ok(wire_bytes)
+    }
+
+    async fn send_raw(&self, data: Bytes) -> Result<(), ClientError> {
+        match &self.wire {
+            Wire::DataChannel(data_channel) => {
+                data_channel
+                    .send(&data)
+                    .await
+                    .map_err(|err| ClientError::WebRTCError(err))?;
+            }
+            #[cfg(test)]
+            Wire::Recorded(recorded) => recorded.send(data).await,
+        }
+
+        Ok(())
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, ChannelCrypto>, ClientError> {
+        self.crypto
+            .lock()
+            .map_err(|_| ClientError::ErrAccessingChannel)
+    }
+}
+
+/// Fragments of the message being received
+#[derive(Default)]
+struct Fragments {
+    buf: BytesMut,
+}
+
+impl Fragments {
+    /// Adds a fragment, returns the message once its last fragment arrived
+    fn push(&mut self, last: bool, data: &[u8]) -> Result<Option<Message>, ClientError> {
+        if self.buf.len() + data.len() > MAX_MESSAGE_SIZE {
+            self.buf = BytesMut::new();
+            return Err(ClientError::ErrInvalidMessage);
+        }
+
+        self.buf.extend_from_slice(data);
+        if !last {
+            return Ok(None);
+        }
+
+        match Message::decode(self.buf.split().freeze())? {
+            Message::Fragment { .. } => Err(ClientError::ErrInvalidMessage),
+            message => Ok(Some(message)),
+        }
+    }
+}
+
+fn fragment(encoded: Bytes) -> Vec<Message> {
+    let count = (encoded.len() + MAX_FRAGMENT_SIZE - 1) / MAX_FRAGMENT_SIZE;
+
+    (0..count)
+        .map(|index| {
+            let start = index * MAX_FRAGMENT_SIZE;
+            let end = std::cmp::min(start + MAX_FRAGMENT_SIZE, encoded.len());
+            Message::Fragment {
+                last: index + 1 == count,
+                data: encoded.slice(start..end),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use bytes::Bytes;
+    use uuid::Uuid;
+
+    use super::{fragment, Channel, Fragments, Wire, MAX_FRAGMENT_SIZE};
+    use crate::{
+        file::{Manifest, ManifestEntry},
+        protocol::{ChannelCrypto, Message, Opened, Role},
+    };
+
+    /// Keeps what is sent, larger messages take longer to go out like on a real wire
+    #[derive(Default)]
+    pub(super) struct Recorded(Mutex<Vec<Bytes>>);
+
+    impl Recorded {
+        pub(super) async fn send(&self, data: Bytes) {
+            for _ in 0..data.len() / 100 {
+                tokio::task::yield_now().await;
+            }
+            self.0.lock().unwrap().push(data);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_senders_keep_counter_order() {
+        let file_id = Uuid::new_v4();
+        let mut source = ChannelCrypto::new(Some("7-purple-sausages"), file_id, Role::Source);
+        let mut sink = ChannelCrypto::new(Some("7-purple-sausages"), file_id, Role::Sink);
+
+        let source_pake = source.start().unwrap().encode();
+        let sink_pake = sink.start().unwrap().encode();
+        let source_confirm = match source.open(sink_pake).unwrap() {
+            Opened::Reply(message) => message.encode(),
+            _ => panic!("no confirmation"),
+        };
+        let sink_confirm = match sink.open(source_pake).unwrap() {
+            Opened::Reply(message) => message.encode(),
+            _ => panic!("no confirmation"),
+        };
+        assert_eq!(source.open(sink_confirm).unwrap(), Opened::Confirmed);
+        assert_eq!(sink.open(source_confirm).unwrap(), Opened::Confirmed);
+
+        let recorded = Arc::new(Recorded::default());
+        let channel = Channel::with_wire(Wire::Recorded(Arc::clone(&recorded)), source);
+
+        // Pieces take longer to go out than haves, which mustn't overtake them
+        let pieces = async {
+            for piece in 0..10 {
+                let message = Message::Piece {
+                    piece,
+                    data: Bytes::from(vec![0; 1000]),
+                };
+                channel.send(&message).await.unwrap();
+            }
+        };
+        let haves = async {
+            for piece in 0..10 {
+                channel.send(&Message::Have { piece }).await.unwrap();
+            }
+        };
+        tokio::join!(pieces, haves);
+
+        let recorded = recorded.0.lock().unwrap().clone();
+        assert_eq!(recorded.len(), 20);
+        for data in recorded {
+            assert!(matches!(sink.open(data), Ok(Opened::Message(_))));
+        }
+    }
+
+    #[test]
+    fn test_fragments() {
+        let manifest = Message::Manifest {
+            manifest: Manifest {
+                entries: vec![ManifestEntry {
+                    path: String::from("large.bin"),
+                    size: 0,
+                    mode: 0o644,
+                    hash: String::new(),
+                }],
+                pieces: vec!["ab".repeat(32); 4096],
+            },
+        };
+        let fragments = fragment(manifest.encode());
+        assert!(fragments.len() > 1);
+
+        let mut received = Fragments::default();
+        let mut decoded = None;
+        for fragment in fragments {
+            assert!(fragment.encode().len() <= MAX_FRAGMENT_SIZE + 2);
+            assert!(decoded.is_none());
+            if let Message::Fragment { last, data } = Message::decode(fragment.encode()).unwrap() {
+                decoded = received.push(last, &data).unwrap();
+            }
+        }
+        assert_eq!(decoded, Some(manifest));
+
+        // Fragments can't nest
+        let nested = Message::Fragment {
+            last: true,
+            data: Default::default(),
+        };
+        assert!(received.push(true, &nested.encode()).is_err());
+    }
+}
@@ -0,0 +1,55 @@
+use bytes::Bytes;
+
+use crate::errors::ClientError;
+
+use super::PIECE_SIZE;
+
+// Fast enough to keep up with a data channel, while still shrinking text a lot
+const ZSTD_LEVEL: i32 = 3;
+
+// Chunks which shrink by less than this are sent as they are, decompressing them isn't
+// worth the few bytes saved
+const MIN_SAVED_BYTES: usize = 256;
+
+/// Compression a data sink is able to decode, advertised to a data source when their
+/// data channel opens
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    None,
+    Zstd,
+}
+
+impl Compression {
+    pub fn to_u8(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Zstd => 1,
+        }
+    }
+
+    /// Unknown values come from newer peers, these just don't get compressed chunks
+    pub fn from_u8(value: u8) -> Self {
+        match value {
+            1 => Compression::Zstd,
+            _ => Compression::None,
+        }
+    }
+}
+
+/// Compresses a chunk, returns `None` if it is not worth it
+pub fn compress(data: &[u8]) -> Option<Bytes> {
+    let compressed = zstd::bulk::compress(data, ZSTD_LEVEL).ok()?;
+
+    if compressed.len() + MIN_SAVED_BYTES > data.len() {
+        return None;
+    }
+
+    Some(Bytes::from(compressed))
+}
+
+/// Decompresses a chunk, which can never be bigger than a piece
+pub fn decompress(data: &[u8]) -> Result<Bytes, ClientError> {
+    zstd::bulk::decompress(data, PIECE_SIZE)
+        .map(Bytes::from)
+        .map_err(|_| ClientError::ErrInvalidMessage)
+}
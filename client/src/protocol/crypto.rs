@@ -0,0 +1,347 @@
+use aes_gcm::{
+    aead::{Aead, NewAead},
+    Aes256Gcm, Key, Nonce,
+};
+use bytes::Bytes;
+use hkdf::Hkdf;
+use sha2::Sha256;
+use spake2::{Ed25519Group, Identity, Password, Spake2};
+use uuid::Uuid;
+
+use crate::errors::ClientError;
+
+use super::Message;
+
+const KEY_LEN: usize = 32;
+
+/// Which end of a data channel this is, each direction is encrypted with a key of its own
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    Source,
+    Sink,
+}
+
+impl Role {
+    fn peer(self) -> Role {
+        match self {
+            Role::Source => Role::Sink,
+            Role::Sink => Role::Source,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Role::Source => "source",
+            Role::Sink => "sink",
+        }
+    }
+}
+
+/// What came out of a message received on an encrypted data channel
+#[derive(Debug, PartialEq)]
+pub enum Opened {
+    Message(Message),
+    /// The handshake finished on this end, the message has to be sent back to the peer
+    Reply(Message),
+    /// The peer proved it knows the passphrase, the channel can be used from now on
+    Confirmed,
+}
+
+struct Session {
+    sealing_key: Aes256Gcm,
+    opening_key: Aes256Gcm,
+    sealing_counter: u64,
+    opening_counter: u64,
+    peer_confirmation: [u8; KEY_LEN],
+    confirmed: bool,
+}
+
+enum State {
+    Plain,
+    Handshaking(Spake2<Ed25519Group>),
+    Established(Session),
+    Failed,
+}
+
+/// Application layer encryption of the messages of a data channel, keyed from a
+/// passphrase through SPAKE2, so neither discovery nor anything relaying the signalling
+/// can read or tamper with a transfer without knowing it.
+pub struct ChannelCrypto {
+    role: Role,
+    state: State,
+    pake_message: Option<Vec<u8>>,
+}
+
+impl ChannelCrypto {
+    /// Messages are sent as they are when there is no passphrase
+    pub fn new(passphrase: Option<&str>, file_id: Uuid, role: Role) -> Self {
+        let passphrase = match passphrase {
+            Some(x) => x,
+            None => {
+                return Self {
+                    role,
+                    state: State::Plain,
+                    pake_message: None,
+                }
+            }
+        };
+
+        // Binding the file id means a handshake can't be replayed for another file
+        let (spake, pake_message) = Spake2::<Ed25519Group>::start_symmetric(
+            &Password::new(passphrase.as_bytes()),
+            &Identity::new(format!("turent:{}", file_id).as_bytes()),
+        );
+
+        Self {
+            role,
+            state: State::Handshaking(spake),
+            pake_message: Some(pake_message),
+        }
+    }
+
+    pub fn is_plain(&self) -> bool {
+        matches!(self.state, State::Plain)
+    }
+
+    /// The first message to send on the channel, if there is a handshake to be done
+    pub fn start(&mut self) -> Option<Message> {
+        self.pake_message.take().map(|message| Message::Pake {
+            message: Bytes::from(message),
+        })
+    }
+
+    /// Encrypts an encoded message, fails until the handshake finished
+    pub fn seal(&mut self, encoded: Bytes) -> Result<Bytes, ClientError> {
+        let session = match &mut self.state {
+            State::Plain => return Ok(encoded),
+            State::Established(session) => session,
+            _ => return Err(ClientError::ErrNotEncrypted),
+        };
+
+        let counter = session.sealing_counter;
+        session.sealing_counter += 1;
+
+        let ciphertext = session
+            .sealing_key
+            .encrypt(&nonce(counter), encoded.as_ref())
+            .map_err(|_| ClientError::ErrNotEncrypted)?;
+
+        Ok(Message::Encrypted {
+            counter,
+            ciphertext: Bytes::from(ciphertext),
+        }
+        .encode())
+    }
+
+    /// Decrypts and decodes a received message, taking care of the handshake
+    pub fn open(&mut self, data: Bytes) -> Result<Opened, ClientError> {
+        let message = Message::decode(data)?;
+
+        match &mut self.state {
+            State::Plain if is_handshake(&message) => Err(ClientError::ErrInvalidMessage),
+            State::Plain => Ok(Opened::Message(message)),
+            State::Handshaking(_) => self.finish_handshake(message),
+            State::Established(session) => {
+                let opened = session.open(message);
+                if let Err(ClientError::ErrWrongPassphrase) = opened {
+                    self.state = State::Failed;
+                }
+                opened
+            }
+            State::Failed => Err(ClientError::ErrNotEncrypted),
+        }
+    }
+
+    fn finish_handshake(&mut self, message: Message) -> Result<Opened, ClientError> {
+        let pake_message = match message {
+            Message::Pake { message } => message,
+            _ => return Err(ClientError::ErrNotEncrypted),
+        };
+
+        let spake = match std::mem::replace(&mut self.state, State::Failed) {
+            State::Handshaking(x) => x,
+            _ => return Err(ClientError::ErrNotEncrypted),
+        };
+        let key = spake
+            .finish(&pake_message)
+            .map_err(|_| ClientError::ErrInvalidMessage)?;
+
+        let (session, confirmation) = Session::new(&key, self.role)?;
+        self.state = State::Established(session);
+
+        Ok(Opened::Reply(Message::Confirm {
+            tag: Bytes::copy_from_slice(&confirmation),
+        }))
+    }
+}
+
+impl Session {
+    /// Returns the session along with the confirmation to send to the peer
+    fn new(key: &[u8], role: Role) -> Result<(Self, [u8; KEY_LEN]), ClientError> {
+        let hkdf = Hkdf::<Sha256>::new(None, key);
+        let expand = |info: String| -> Result<[u8; KEY_LEN], ClientError> {
+            let mut okm = [0; KEY_LEN];
+            hkdf.expand(info.as_bytes(), &mut okm)
+                .map_err(|_| ClientError::ErrNotEncrypted)?;
+            Ok(okm)
+        };
+
+        let sealing_key = expand(format!("turent {} key", role.name()))?;
+        let opening_key = expand(format!("turent {} key", role.peer().name()))?;
+        let confirmation = expand(format!("turent {} confirmation", role.name()))?;
+        let peer_confirmation = expand(format!("turent {} confirmation", role.peer().name()))?;
+
+        Ok((
+            Self {
+                sealing_key: Aes256Gcm::new(Key::from_slice(&sealing_key)),
+                opening_key: Aes256Gcm::new(Key::from_slice(&opening_key)),
+                sealing_counter: 0,
+                opening_counter: 0,
+                peer_confirmation,
+                confirmed: false,
+            },
+            confirmation,
+        ))
+    }
+
+    fn open(&mut self, message: Message) -> Result<Opened, ClientError> {
+        match message {
+            Message::Confirm { tag } => {
+                if self.confirmed || !constant_time_eq(&tag, &self.peer_confirmation) {
+                    return Err(ClientError::ErrWrongPassphrase);
+                }
+                self.confirmed = true;
+
+                Ok(Opened::Confirmed)
+            }
+            Message::Encrypted {
+                counter,
+                ciphertext,
+            } if self.confirmed => {
+                // Data channels are ordered, anything else is a replay or a drop
+                if counter != self.opening_counter {
+                    return Err(ClientError::ErrNotEncrypted);
+                }
+
+                let encoded = self
+                    .opening_key
+                    .decrypt(&nonce(counter), ciphertext.as_ref())
+                    .map_err(|_| ClientError::ErrNotEncrypted)?;
+                self.opening_counter += 1;
+
+                let message = Message::decode(Bytes::from(encoded))?;
+                // Handshake messages are never nested
+                if is_handshake(&message) {
+                    return Err(ClientError::ErrInvalidMessage);
+                }
+
+                Ok(Opened::Message(message))
+            }
+            _ => Err(ClientError::ErrNotEncrypted),
+        }
+    }
+}
+
+fn is_handshake(message: &Message) -> bool {
+    matches!(
+        message,
+        Message::Pake { .. } | Message::Confirm { .. } | Message::Encrypted { .. }
+    )
+}
+
+fn nonce(counter: u64) -> Nonce<aes_gcm::aead::consts::U12> {
+    let mut nonce = [0; 12];
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    *Nonce::from_slice(&nonce)
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use uuid::Uuid;
+
+    use super::{ChannelCrypto, Opened, Role};
+    use crate::protocol::Message;
+
+    fn handshake(
+        source_passphrase: &str,
+        sink_passphrase: &str,
+    ) -> (ChannelCrypto, ChannelCrypto, Bytes, Bytes) {
+        let file_id = Uuid::new_v4();
+        let mut source = ChannelCrypto::new(Some(source_passphrase), file_id, Role::Source);
+        let mut sink = ChannelCrypto::new(Some(sink_passphrase), file_id, Role::Sink);
+
+        let source_pake = source.start().unwrap().encode();
+        let sink_pake = sink.start().unwrap().encode();
+
+        let source_confirm = match source.open(sink_pake).unwrap() {
+            Opened::Reply(message) => message.encode(),
+            _ => panic!("expected a confirmation"),
+        };
+        let sink_confirm = match sink.open(source_pake).unwrap() {
+            Opened::Reply(message) => message.encode(),
+            _ => panic!("expected a confirmation"),
+        };
+
+        (source, sink, source_confirm, sink_confirm)
+    }
+
+    fn established() -> (ChannelCrypto, ChannelCrypto) {
+        let (mut source, mut sink, source_confirm, sink_confirm) =
+            handshake("purple-monkey", "purple-monkey");
+
+        assert_eq!(sink.open(source_confirm).unwrap(), Opened::Confirmed);
+        assert_eq!(source.open(sink_confirm).unwrap(), Opened::Confirmed);
+
+        (source, sink)
+    }
+
+    #[test]
+    fn test_encrypted_round_trip() {
+        let (mut source, mut sink) = established();
+
+        let message = Message::Piece {
+            piece: 2,
+            data: Bytes::from_static(b"SUPERLY DUPERLY SECRET"),
+        };
+        let sealed = source.seal(message.encode()).unwrap();
+        assert!(!sealed
+            .windows(b"SECRET".len())
+            .any(|window| window == b"SECRET"));
+        assert_eq!(sink.open(sealed.clone()).unwrap(), Opened::Message(message));
+
+        // Replays are rejected
+        assert!(sink.open(sealed).is_err());
+
+        // So is anything sent in the clear once encryption was agreed on
+        assert!(sink.open(Message::Have { piece: 1 }.encode()).is_err());
+    }
+
+    #[test]
+    fn test_wrong_passphrase() {
+        let (mut source, mut sink, source_confirm, sink_confirm) =
+            handshake("purple-monkey", "purple-donkey");
+
+        assert!(sink.open(source_confirm).is_err());
+        assert!(source.open(sink_confirm).is_err());
+
+        // Nothing goes through a channel which failed its handshake
+        assert!(source.seal(Message::Have { piece: 1 }.encode()).is_err());
+    }
+
+    #[test]
+    fn test_tampered_message() {
+        let (mut source, mut sink) = established();
+
+        let sealed = source.seal(Message::Have { piece: 1 }.encode()).unwrap();
+        let mut tampered = sealed.to_vec();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+
+        assert!(sink.open(Bytes::from(tampered)).is_err());
+    }
+}
@@ -0,0 +1,384 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+use crate::{
+    errors::ClientError,
+    file::{Manifest, MAX_BUNDLE_SIZE},
+};
+
+use super::{compress, decompress, Bitfield, Compression};
+
+// Stays well under the 64KiB SCTP message size limit, header included
+pub const PIECE_SIZE: usize = 32 * 1024;
+
+const TAG_BITFIELD: u8 = 0;
+const TAG_HAVE: u8 = 1;
+const TAG_REQUEST: u8 = 2;
+const TAG_PIECE: u8 = 3;
+const TAG_CANCEL: u8 = 4;
+const TAG_MANIFEST: u8 = 5;
+const TAG_HELLO: u8 = 6;
+// A piece whose data is zstd compressed, decodes to a plain `Message::Piece`
+const TAG_COMPRESSED_PIECE: u8 = 7;
+const TAG_PAKE: u8 = 8;
+const TAG_CONFIRM: u8 = 9;
+const TAG_ENCRYPTED: u8 = 10;
+const TAG_PAUSE: u8 = 11;
+const TAG_RESUME: u8 = 12;
+const TAG_STOP: u8 = 13;
+const TAG_FRAGMENT: u8 = 14;
+
+pub fn piece_count(file_size: u64) -> usize {
+    file_size.div_ceil(PIECE_SIZE as u64) as usize
+}
+
+/// Messages exchanged between a data source and a data sink over their data channel.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    /// Sent by a data sink as soon as the channel opens, before any request
+    Hello {
+        compression: Compression,
+    },
+    /// Sent by a data source as soon as the channel opens, describes the bundle the
+    /// pieces are cut from
+    Manifest {
+        manifest: Manifest,
+    },
+    /// Sent by a data source right after the manifest
+    Bitfield {
+        file_size: u64,
+        bitfield: Bitfield,
+    },
+    /// Sent by a data source whenever it gets hold of a new piece
+    Have {
+        piece: u32,
+    },
+    Request {
+        piece: u32,
+    },
+    Piece {
+        piece: u32,
+        data: Bytes,
+    },
+    /// Withdraws a request, sent when a piece requested from several sources arrived
+    Cancel {
+        piece: u32,
+    },
+    /// Starts the passphrase handshake, sent by both ends as soon as the channel opens
+    Pake {
+        message: Bytes,
+    },
+    /// Proves the sender derived the same key from the handshake
+    Confirm {
+        tag: Bytes,
+    },
+    /// Any other message, once the handshake is done
+    Encrypted {
+        counter: u64,
+        ciphertext: Bytes,
+    },
+    /// Holds the transfer until `Resume`, a paused sink requests nothing and a paused
+    /// source answers no requests
+    Pause,
+    Resume,
+    /// Ends the transfer for good, the other end closes the data channel
+    Stop,
+    /// Part of a message too large to be sent at once, which is decoded once its last
+    /// fragment arrives
+    Fragment {
+        last: bool,
+        data: Bytes,
+    },
+}
+
+impl Message {
+    /// Encodes the message, compressing piece data if `compression` was negotiated and
+    /// the piece actually shrinks
+    pub fn encode_with(&self, compression: Compression) -> Bytes {
+        if let (Compression::Zstd, Message::Piece { piece, data }) = (compression, self) {
+            if let Some(compressed) = compress(data) {
+                let mut buf = BytesMut::with_capacity(5 + compressed.len());
+                buf.put_u8(TAG_COMPRESSED_PIECE);
+                buf.put_u32(*piece);
+                buf.put_slice(&compressed);
+                return buf.freeze();
+            }
+        }
+
+        self.encode()
+    }
+
+    pub fn encode(&self) -> Bytes {
+        let mut buf = BytesMut::new();
+
+        match self {
+            Message::Hello { compression } => {
+                buf.put_u8(TAG_HELLO);
+                buf.put_u8(compression.to_u8());
+            }
+            Message::Manifest { manifest } => {
+                buf.put_u8(TAG_MANIFEST);
+                // Serializing plain data into a Vec can't fail
+                buf.put_slice(&serde_json::to_vec(manifest).unwrap_or_default());
+            }
+            Message::Bitfield {
+                file_size,
+                bitfield,
+            } => {
+                buf.put_u8(TAG_BITFIELD);
+                buf.put_u64(*file_size);
+                buf.put_slice(bitfield.as_bytes());
+            }
+            Message::Have { piece } => {
+                buf.put_u8(TAG_HAVE);
+                buf.put_u32(*piece);
+            }
+            Message::Request { piece } => {
+                buf.put_u8(TAG_REQUEST);
+                buf.put_u32(*piece);
+            }
+            Message::Piece { piece, data } => {
+                buf.put_u8(TAG_PIECE);
+                buf.put_u32(*piece);
+                buf.put_slice(data);
+            }
+            Message::Cancel { piece } => {
+                buf.put_u8(TAG_CANCEL);
+                buf.put_u32(*piece);
+            }
+            Message::Pake { message } => {
+                buf.put_u8(TAG_PAKE);
+                buf.put_slice(message);
+            }
+            Message::Confirm { tag } => {
+                buf.put_u8(TAG_CONFIRM);
+                buf.put_slice(tag);
+            }
+            Message::Encrypted {
+                counter,
+                ciphertext,
+            } => {
+                buf.put_u8(TAG_ENCRYPTED);
+                buf.put_u64(*counter);
+                buf.put_slice(ciphertext);
+            }
+            Message::Pause => buf.put_u8(TAG_PAUSE),
+            Message::Resume => buf.put_u8(TAG_RESUME),
+            Message::Stop => buf.put_u8(TAG_STOP),
+            Message::Fragment { last, data } => {
+                buf.put_u8(TAG_FRAGMENT);
+                buf.put_u8(*last as u8);
+                buf.put_slice(data);
+            }
+        }
+
+        buf.freeze()
+    }
+
+    pub fn decode(mut data: Bytes) -> Result<Message, ClientError> {
+        if data.remaining() < 1 {
+            return Err(ClientError::ErrInvalidMessage);
+        }
+
+        match data.get_u8() {
+            TAG_HELLO => {
+                if data.remaining() < 1 {
+                    return Err(ClientError::ErrInvalidMessage);
+                }
+                Ok(Message::Hello {
+                    compression: Compression::from_u8(data.get_u8()),
+                })
+            }
+            TAG_MANIFEST => Ok(Message::Manifest {
+                manifest: serde_json::from_slice(&data)
+                    .map_err(|_| ClientError::ErrInvalidMessage)?,
+            }),
+            TAG_BITFIELD => {
+                if data.remaining() < 8 {
+                    return Err(ClientError::ErrInvalidMessage);
+                }
+                let file_size = data.get_u64();
+                // Nothing larger is ever downloaded, so no bitfield is that large either
+                if file_size > MAX_BUNDLE_SIZE {
+                    return Err(ClientError::ErrInvalidMessage);
+                }
+                let bitfield = Bitfield::from_bytes(piece_count(file_size), &data)?;
+                Ok(Message::Bitfield {
+                    file_size,
+                    bitfield,
+                })
+            }
+            TAG_HAVE => Ok(Message::Have {
+                piece: get_piece(&mut data)?,
+            }),
+            TAG_REQUEST => Ok(Message::Request {
+                piece: get_piece(&mut data)?,
+            }),
+            TAG_PIECE => {
+                let piece = get_piece(&mut data)?;
+                Ok(Message::Piece { piece, data })
+            }
+            TAG_COMPRESSED_PIECE => {
+                let piece = get_piece(&mut data)?;
+                Ok(Message::Piece {
+                    piece,
+                    data: decompress(&data)?,
+                })
+            }
+            TAG_CANCEL => Ok(Message::Cancel {
+                piece: get_piece(&mut data)?,
+            }),
+            TAG_PAKE => Ok(Message::Pake { message: data }),
+            TAG_CONFIRM => Ok(Message::Confirm { tag: data }),
+            TAG_ENCRYPTED => {
+                if data.remaining() < 8 {
+                    return Err(ClientError::ErrInvalidMessage);
+                }
+                let counter = data.get_u64();
+                Ok(Message::Encrypted {
+                    counter,
+                    ciphertext: data,
+                })
+            }
+            TAG_PAUSE => Ok(Message::Pause),
+            TAG_RESUME => Ok(Message::Resume),
+            TAG_STOP => Ok(Message::Stop),
+            TAG_FRAGMENT => {
+                if data.remaining() < 1 {
+                    return Err(ClientError::ErrInvalidMessage);
+                }
+                let last = data.get_u8() != 0;
+                Ok(Message::Fragment { last, data })
+            }
+            _ => Err(ClientError::ErrInvalidMessage),
+        }
+    }
+}
+
+fn get_piece(data: &mut Bytes) -> Result<u32, ClientError> {
+    if data.remaining() < 4 {
+        return Err(ClientError::ErrInvalidMessage);
+    }
+    Ok(data.get_u32())
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::{piece_count, Message, PIECE_SIZE};
+    use crate::{
+        file::{Manifest, ManifestEntry},
+        protocol::{Bitfield, Compression},
+    };
+
+    #[test]
+    fn test_message_round_trip() {
+        let mut bitfield = Bitfield::new(piece_count(3 * PIECE_SIZE as u64 + 1));
+        bitfield.set(0);
+        bitfield.set(3);
+
+        let messages = vec![
+            Message::Hello {
+                compression: Compression::Zstd,
+            },
+            Message::Manifest {
+                manifest: Manifest {
+                    entries: vec![ManifestEntry {
+                        path: String::from("dir/example_file.txt"),
+                        size: 47,
+                        mode: 0o644,
+                        hash: String::from("00"),
+                    }],
+                    pieces: vec![String::from("01")],
+                },
+            },
+            Message::Bitfield {
+                file_size: 3 * PIECE_SIZE as u64 + 1,
+                bitfield,
+            },
+            Message::Have { piece: 7 },
+            Message::Request { piece: 42 },
+            Message::Piece {
+                piece: 1,
+                data: Bytes::from_static(b"SUPERLY DUPERLY SECRET"),
+            },
+            Message::Cancel { piece: 1 },
+            Message::Pake {
+                message: Bytes::from_static(&[1, 2, 3]),
+            },
+            Message::Confirm {
+                tag: Bytes::from_static(&[4, 5, 6]),
+            },
+            Message::Encrypted {
+                counter: 9,
+                ciphertext: Bytes::from_static(&[7, 8]),
+            },
+            Message::Pause,
+            Message::Resume,
+            Message::Stop,
+            Message::Fragment {
+                last: true,
+                data: Bytes::from_static(&[9]),
+            },
+        ];
+
+        for message in messages {
+            assert_eq!(Message::decode(message.encode()).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn test_compressed_pieces() {
+        let text = Message::Piece {
+            piece: 3,
+            data: Bytes::from("a line of some log file\n".repeat(1000)),
+        };
+        let encoded = text.encode_with(Compression::Zstd);
+        assert!(encoded.len() < text.encode().len() / 10);
+        assert_eq!(Message::decode(encoded).unwrap(), text);
+
+        // Incompressible chunks go out as they are
+        let noise = Message::Piece {
+            piece: 4,
+            data: Bytes::from(
+                (0..PIECE_SIZE)
+                    .map(|_| rand::random::<u8>())
+                    .collect::<Vec<_>>(),
+            ),
+        };
+        assert_eq!(noise.encode_with(Compression::Zstd), noise.encode());
+
+        assert_eq!(text.encode_with(Compression::None), text.encode());
+
+        // Compressed data decoding to more than a piece is rejected
+        let mut bomb = vec![7, 0, 0, 0, 0];
+        bomb.extend(zstd::bulk::compress(&vec![0; 2 * PIECE_SIZE], 3).unwrap());
+        assert!(Message::decode(Bytes::from(bomb)).is_err());
+    }
+
+    #[test]
+    fn test_invalid_messages() {
+        assert!(Message::decode(Bytes::new()).is_err());
+        assert!(Message::decode(Bytes::from_static(&[0xff])).is_err());
+        assert!(Message::decode(Bytes::from_static(&[1, 0, 0])).is_err());
+
+        // 9 pieces need two bytes, and spare bits have to be zero
+        let file_size = (9 * PIECE_SIZE as u64).to_be_bytes();
+        let mut bitfield_msg = vec![0];
+        bitfield_msg.extend_from_slice(&file_size);
+        bitfield_msg.extend_from_slice(&[0xff, 0xff]);
+        assert!(Message::decode(Bytes::from(bitfield_msg.clone())).is_err());
+
+        bitfield_msg[10] = 0x80;
+        assert!(Message::decode(Bytes::from(bitfield_msg)).is_ok());
+
+        // Sizes a manifest could never have don't overflow the piece count
+        let mut oversized = vec![0];
+        oversized.extend_from_slice(&u64::MAX.to_be_bytes());
+        assert!(Message::decode(Bytes::from(oversized)).is_err());
+        assert_eq!(
+            piece_count(u64::MAX),
+            (u64::MAX / PIECE_SIZE as u64 + 1) as usize
+        );
+    }
+}
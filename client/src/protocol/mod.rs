@@ -0,0 +1,14 @@
+pub use bitfield::*;
+pub use channel::*;
+pub use compression::*;
+pub use crypto::*;
+pub use message::*;
+pub use rate_limit::*;
+pub use stats::*;
+mod bitfield;
+mod channel;
+mod compression;
+mod crypto;
+mod message;
+mod rate_limit;
+mod stats;
@@ -0,0 +1,154 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Token bucket letting through `rate` bytes per second on average, in bursts of up to
+/// a second's worth. Whatever goes through while the bucket is empty is owed, and paid
+/// for by waiting.
+#[derive(Debug)]
+pub struct RateLimiter {
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    // Bytes per second, unlimited when None
+    rate: Option<u64>,
+    // Negative when owing
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: Option<u64>) -> Self {
+        Self {
+            bucket: Mutex::new(Bucket {
+                rate,
+                tokens: rate.unwrap_or(0) as f64,
+                refilled: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn shared(self) -> Arc<RateLimiter> {
+        Arc::new(self)
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        self.bucket.lock().ok().and_then(|bucket| bucket.rate)
+    }
+
+    /// Takes effect for whatever goes through next, waits which already started stay
+    /// as they are
+    pub fn set_rate(&self, rate: Option<u64>) {
+        if let Ok(mut bucket) = self.bucket.lock() {
+            bucket.refill(Instant::now());
+            bucket.rate = rate;
+            if let Some(rate) = rate {
+                bucket.tokens = bucket.tokens.min(rate as f64);
+            }
+        }
+    }
+
+    /// How long to wait for `bytes` to go through
+    pub fn take(&self, bytes: usize) -> Duration {
+        match self.bucket.lock() {
+            Ok(mut bucket) => bucket.take(bytes, Instant::now()),
+            Err(_) => Duration::ZERO,
+        }
+    }
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant) {
+        if let Some(rate) = self.rate {
+            let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * rate as f64).min(rate as f64);
+        }
+        self.refilled = now;
+    }
+
+    fn take(&mut self, bytes: usize, now: Instant) -> Duration {
+        let rate = match self.rate {
+            Some(rate) if rate > 0 => rate,
+            _ => return Duration::ZERO,
+        };
+
+        self.refill(now);
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 {
+            return Duration::ZERO;
+        }
+
+        Duration::from_secs_f64(-self.tokens / rate as f64)
+    }
+}
+
+/// Paces the data of a transfer by a limit of its own and the one every transfer of the
+/// client shares, whichever is stricter
+#[derive(Debug, Clone)]
+pub struct Throttle {
+    transfer: Arc<RateLimiter>,
+    global: Arc<RateLimiter>,
+}
+
+impl Throttle {
+    pub fn new(limit: Option<u64>, global: Arc<RateLimiter>) -> Self {
+        Self {
+            transfer: RateLimiter::new(limit).shared(),
+            global,
+        }
+    }
+
+    pub fn limit(&self) -> Option<u64> {
+        self.transfer.rate()
+    }
+
+    pub fn set_limit(&self, limit: Option<u64>) {
+        self.transfer.set_rate(limit);
+    }
+
+    /// Waits until `bytes` more may go through
+    pub async fn acquire(&self, bytes: usize) {
+        let wait = self.transfer.take(bytes).max(self.global.take(bytes));
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::Bucket;
+
+    #[test]
+    fn test_bucket() {
+        let start = Instant::now();
+        let mut bucket = Bucket {
+            rate: Some(1000),
+            tokens: 1000.0,
+            refilled: start,
+        };
+
+        // A second's worth goes through right away, the rest is paid for by waiting
+        assert_eq!(bucket.take(1000, start), Duration::ZERO);
+        assert_eq!(bucket.take(500, start), Duration::from_millis(500));
+        assert_eq!(
+            bucket.take(500, start + Duration::from_millis(500)),
+            Duration::from_millis(500)
+        );
+
+        // Idle time doesn't add up to more than a second's worth
+        assert_eq!(
+            bucket.take(1000, start + Duration::from_secs(10)),
+            Duration::ZERO
+        );
+        assert!(bucket.take(1, start + Duration::from_secs(10)) > Duration::ZERO);
+
+        bucket.rate = None;
+        assert_eq!(bucket.take(1 << 30, start), Duration::ZERO);
+    }
+}
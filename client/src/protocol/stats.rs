@@ -0,0 +1,238 @@
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+// Weight of the latest sample in the smoothed piece latency, as in TCP's SRTT
+const LATENCY_GAIN: f64 = 0.125;
+// webrtc 0.4 has no stats API, what the transport knows stays inside it
+const TRANSPORT_STATS: &str = "transport RTT and retransmissions unavailable on webrtc 0.4";
+
+/// The candidates a peer connection ended up using, `path` tells direct connections
+/// from relayed ones
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct CandidatePair {
+    #[serde(rename = "path")]
+    pub path: String,
+    #[serde(rename = "local")]
+    pub local: String,
+    #[serde(rename = "remote")]
+    pub remote: String,
+}
+
+/// Counts the piece data going over the data channels of a transfer, before and after
+/// compression, along with how long it took
+#[derive(Debug, Default)]
+pub struct TransferStats {
+    raw_bytes: AtomicU64,
+    wire_bytes: AtomicU64,
+    pieces: AtomicU64,
+    // Pieces received which were already there, requested from several sources in
+    // endgame or again from another source after one went away
+    duplicate_pieces: AtomicU64,
+    // First and latest piece
+    window: Mutex<Option<(Instant, Instant)>>,
+    // Smoothed time from requesting a piece until it arrives, only known to sinks. It
+    // includes queueing and throttling at the source, it isn't the transport's RTT
+    piece_latency: Mutex<Option<Duration>>,
+    candidate_pairs: Mutex<Vec<CandidatePair>>,
+}
+
+impl TransferStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&self, raw_bytes: usize, wire_bytes: usize) {
+        self.raw_bytes
+            .fetch_add(raw_bytes as u64, Ordering::Relaxed);
+        self.wire_bytes
+            .fetch_add(wire_bytes as u64, Ordering::Relaxed);
+        self.pieces.fetch_add(1, Ordering::Relaxed);
+
+        let now = Instant::now();
+        if let Ok(mut window) = self.window.lock() {
+            let first = window.map_or(now, |(first, _)| first);
+            *window = Some((first, now));
+        }
+    }
+
+    pub fn record_duplicate(&self) {
+        self.duplicate_pieces.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_piece_latency(&self, sample: Duration) {
+        if let Ok(mut piece_latency) = self.piece_latency.lock() {
+            *piece_latency = Some(match *piece_latency {
+                Some(latency) => latency.mul_f64(1.0 - LATENCY_GAIN) + sample.mul_f64(LATENCY_GAIN),
+                None => sample,
+            });
+        }
+    }
+
+    pub fn record_candidate_pair(&self, pair: CandidatePair) {
+        if let Ok(mut candidate_pairs) = self.candidate_pairs.lock() {
+            candidate_pairs.push(pair);
+        }
+    }
+
+    pub fn raw_bytes(&self) -> u64 {
+        self.raw_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn wire_bytes(&self) -> u64 {
+        self.wire_bytes.load(Ordering::Relaxed)
+    }
+
+    /// How many times smaller the data got on the wire, 1.0 until something is sent
+    pub fn compression_ratio(&self) -> f64 {
+        let wire_bytes = self.wire_bytes();
+        if wire_bytes == 0 {
+            return 1.0;
+        }
+
+        self.raw_bytes() as f64 / wire_bytes as f64
+    }
+
+    /// Everything known about the transfer of `file_id` so far, `role` being which end
+    /// of it this is
+    pub fn report(&self, file_id: String, role: &str) -> TransferReport {
+        let duration = match self.window.lock() {
+            Ok(window) => window.map_or(Duration::ZERO, |(first, last)| last - first),
+            Err(_) => Duration::ZERO,
+        };
+        let throughput = match duration.as_secs_f64() {
+            secs if secs > 0.0 => self.raw_bytes() as f64 / secs,
+            _ => 0.0,
+        };
+
+        TransferReport {
+            file_id,
+            role: role.to_string(),
+            raw_bytes: self.raw_bytes(),
+            wire_bytes: self.wire_bytes(),
+            compression_ratio: self.compression_ratio(),
+            pieces: self.pieces.load(Ordering::Relaxed),
+            duplicate_pieces: self.duplicate_pieces.load(Ordering::Relaxed),
+            duration_ms: duration.as_millis() as u64,
+            throughput,
+            piece_latency_ms: self
+                .piece_latency
+                .lock()
+                .ok()
+                .and_then(|latency| latency.map(|latency| latency.as_secs_f64() * 1000.0)),
+            transport_stats: TRANSPORT_STATS,
+            candidate_pairs: self
+                .candidate_pairs
+                .lock()
+                .map(|candidate_pairs| candidate_pairs.clone())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// A snapshot of `TransferStats`
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct TransferReport {
+    #[serde(rename = "fileId")]
+    pub file_id: String,
+    #[serde(rename = "role")]
+    pub role: String,
+    #[serde(rename = "rawBytes")]
+    pub raw_bytes: u64,
+    #[serde(rename = "wireBytes")]
+    pub wire_bytes: u64,
+    #[serde(rename = "compressionRatio")]
+    pub compression_ratio: f64,
+    #[serde(rename = "pieces")]
+    pub pieces: u64,
+    #[serde(rename = "duplicatePieces")]
+    pub duplicate_pieces: u64,
+    // From the first piece to the latest
+    #[serde(rename = "durationMs")]
+    pub duration_ms: u64,
+    // Bytes of pieces per second
+    #[serde(rename = "throughput")]
+    pub throughput: f64,
+    // Smoothed time from requesting a piece until it arrives
+    #[serde(rename = "pieceLatencyMs")]
+    pub piece_latency_ms: Option<f64>,
+    // Why nothing the transport measures is in the report
+    #[serde(rename = "transportStats")]
+    pub transport_stats: &'static str,
+    // One for every peer connection of the transfer
+    #[serde(rename = "candidatePairs")]
+    pub candidate_pairs: Vec<CandidatePair>,
+}
+
+impl fmt::Display for TransferReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} bytes in {} pieces over {:.1}s ( {:.1} KiB/s ), {} bytes on the wire ( compression ratio {:.2} ), {} duplicate pieces",
+            self.role,
+            self.raw_bytes,
+            self.pieces,
+            self.duration_ms as f64 / 1000.0,
+            self.throughput / 1024.0,
+            self.wire_bytes,
+            self.compression_ratio,
+            self.duplicate_pieces
+        )?;
+        if let Some(piece_latency_ms) = self.piece_latency_ms {
+            write!(f, ", piece latency {:.0}ms", piece_latency_ms)?;
+        }
+        write!(f, ", {}", self.transport_stats)?;
+        for pair in &self.candidate_pairs {
+            write!(
+                f,
+                ", {} ( local {}, remote {} )",
+                pair.path, pair.local, pair.remote
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::TransferStats;
+
+    #[test]
+    fn test_report() {
+        let stats = TransferStats::new();
+        assert_eq!(
+            stats.report("file".to_string(), "sink").piece_latency_ms,
+            None
+        );
+
+        stats.record(1000, 400);
+        stats.record(1000, 600);
+        stats.record_duplicate();
+        stats.record_piece_latency(Duration::from_millis(80));
+        stats.record_piece_latency(Duration::from_millis(160));
+
+        let report = stats.report("file".to_string(), "sink");
+        assert_eq!(report.raw_bytes, 2000);
+        assert_eq!(report.wire_bytes, 1000);
+        assert_eq!(report.compression_ratio, 2.0);
+        assert_eq!(report.pieces, 2);
+        assert_eq!(report.duplicate_pieces, 1);
+        assert_eq!(
+            report.piece_latency_ms.map(|latency_ms| latency_ms.round()),
+            Some(90.0)
+        );
+        assert!(report
+            .to_string()
+            .contains("transport RTT and retransmissions unavailable"));
+    }
+}
@@ -0,0 +1,193 @@
+//! Driving a daemon through its control API
+
+mod harness;
+
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+use harness::{random_bytes, wait_for_file, Harness};
+
+const FILE_SIZE: usize = 256 * 1024;
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_download_through_control_api() {
+    let harness = Harness::loopback().await;
+    let contents = random_bytes(FILE_SIZE);
+    let path = harness.write_file("payload.bin", &contents);
+    let file_id = harness.share(None, &path, false).await;
+
+    let control_url = harness.daemon().await;
+    let client = harness.control_client();
+    let download_dir = harness.path("received");
+    let res = client
+        .post(format!("{}/downloads", control_url))
+        .json(&json!({
+            "file": file_id.to_string(),
+            "downloadDir": download_dir.to_string_lossy(),
+        }))
+        .send()
+        .await
+        .expect("download added");
+    assert!(res.status().is_success());
+
+    let received = wait_for_file(&download_dir.join("payload.bin"), FILE_SIZE, TIMEOUT).await;
+    assert!(received == Some(contents), "file wasn't received intact");
+
+    let transfers: Value = client
+        .get(format!("{}/transfers/{}", control_url, file_id))
+        .send()
+        .await
+        .expect("transfers listed")
+        .json()
+        .await
+        .expect("transfers as json");
+    let transfer = &transfers[0];
+    assert_eq!(transfer["report"]["role"], "sink");
+    assert_eq!(transfer["progress"]["have"], transfer["progress"]["total"]);
+    assert_eq!(transfer["report"]["rawBytes"], FILE_SIZE as u64);
+
+    let res = client
+        .delete(format!("{}/downloads/{}", control_url, file_id))
+        .send()
+        .await
+        .expect("download cancelled");
+    assert!(res.status().is_success());
+    let res = client
+        .get(format!("{}/transfers/{}", control_url, file_id))
+        .send()
+        .await
+        .expect("transfers listed");
+    assert_eq!(res.status(), reqwest::StatusCode::NOT_FOUND);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_share_through_control_api() {
+    let harness = Harness::loopback().await;
+    let contents = random_bytes(FILE_SIZE);
+    let path = harness.write_file("payload.bin", &contents);
+
+    let control_url = harness.daemon().await;
+    let client = harness.control_client();
+    let limits: Value = client
+        .put(format!("{}/limits", control_url))
+        .json(&json!({ "uploadLimit": 4 * FILE_SIZE }))
+        .send()
+        .await
+        .expect("limits set")
+        .json()
+        .await
+        .expect("limits as json");
+    assert_eq!(limits["uploadLimit"], 4 * FILE_SIZE);
+    assert_eq!(limits["downloadLimit"], Value::Null);
+
+    let shared: Value = client
+        .post(format!("{}/shares", control_url))
+        .json(&json!({ "path": path.to_string_lossy() }))
+        .send()
+        .await
+        .expect("share added")
+        .json()
+        .await
+        .expect("share as json");
+    let file_id = shared["fileId"]
+        .as_str()
+        .and_then(|file_id| file_id.parse().ok())
+        .expect("file id");
+
+    let download_dir = harness.path("received");
+    harness.fetch(None, file_id, &download_dir, false);
+
+    let received = wait_for_file(&download_dir.join("payload.bin"), FILE_SIZE, TIMEOUT).await;
+    assert!(received == Some(contents), "file wasn't received intact");
+
+    let res = client
+        .delete(format!("{}/shares/{}", control_url, file_id))
+        .send()
+        .await
+        .expect("share cancelled");
+    assert!(res.status().is_success());
+    let res = client
+        .get(format!("{}/transfers/{}", control_url, file_id))
+        .send()
+        .await
+        .expect("transfers listed");
+    assert_eq!(res.status(), reqwest::StatusCode::NOT_FOUND);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_control_api_needs_token() {
+    let harness = Harness::loopback().await;
+    let control_url = harness.daemon().await;
+
+    let res = reqwest::get(format!("{}/transfers", control_url))
+        .await
+        .expect("transfers listed");
+    assert_eq!(res.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+    // Pages rebinding a domain of theirs to this host are turned away, token or not
+    let res = harness
+        .control_client()
+        .get(format!("{}/transfers", control_url))
+        .header("Host", "evil.example")
+        .send()
+        .await
+        .expect("transfers listed");
+    assert_eq!(res.status(), reqwest::StatusCode::FORBIDDEN);
+
+    let res = harness
+        .control_client()
+        .get(format!("{}/transfers", control_url))
+        .send()
+        .await
+        .expect("transfers listed");
+    assert!(res.status().is_success());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_pause_and_resume_through_control_api() {
+    let harness = Harness::loopback().await;
+    let contents = random_bytes(FILE_SIZE);
+    let path = harness.write_file("payload.bin", &contents);
+    let file_id = harness.share(None, &path, false).await;
+
+    let control_url = harness.daemon().await;
+    let client = harness.control_client();
+    let download_dir = harness.path("received");
+    client
+        .post(format!("{}/downloads", control_url))
+        .json(&json!({
+            "file": file_id.to_string(),
+            "downloadDir": download_dir.to_string_lossy(),
+        }))
+        .send()
+        .await
+        .expect("download added");
+
+    let res = client
+        .post(format!("{}/downloads/{}/pause", control_url, file_id))
+        .send()
+        .await
+        .expect("download paused");
+    assert!(res.status().is_success());
+    let transfers: Value = client
+        .get(format!("{}/transfers/{}", control_url, file_id))
+        .send()
+        .await
+        .expect("transfers listed")
+        .json()
+        .await
+        .expect("transfers as json");
+    assert_eq!(transfers[0]["paused"], true);
+
+    let res = client
+        .post(format!("{}/downloads/{}/resume", control_url, file_id))
+        .send()
+        .await
+        .expect("download resumed");
+    assert!(res.status().is_success());
+
+    let received = wait_for_file(&download_dir.join("payload.bin"), FILE_SIZE, TIMEOUT).await;
+    assert!(received == Some(contents), "file wasn't received intact");
+}
@@ -0,0 +1,159 @@
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use tokio::time::Instant;
+use webrtc::util::{Conn, Result};
+
+// Packets queued for longer than this behind a bandwidth cap are dropped, like a
+// router's buffer overflowing
+const MAX_QUEUEING: Duration = Duration::from_millis(500);
+// How much later than its successors a reordered packet goes out
+const REORDER_DELAY: Duration = Duration::from_millis(20);
+
+/// What a link does to the packets crossing it
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LinkConditions {
+    pub latency: Duration,
+    // Up to this much is added to the latency of every packet
+    pub jitter: Duration,
+    pub loss: f64,
+    pub reorder: f64,
+    // Bytes per second, unlimited when there is none
+    pub bandwidth: Option<u64>,
+}
+
+impl LinkConditions {
+    pub fn broadband() -> Self {
+        Self {
+            latency: Duration::from_millis(20),
+            jitter: Duration::from_millis(5),
+            loss: 0.005,
+            ..Default::default()
+        }
+    }
+
+    pub fn mobile() -> Self {
+        Self {
+            latency: Duration::from_millis(80),
+            jitter: Duration::from_millis(30),
+            loss: 0.02,
+            reorder: 0.05,
+            bandwidth: Some(1024 * 1024),
+        }
+    }
+
+    pub fn congested() -> Self {
+        Self {
+            latency: Duration::from_millis(150),
+            jitter: Duration::from_millis(50),
+            loss: 0.05,
+            reorder: 0.1,
+            bandwidth: Some(256 * 1024),
+        }
+    }
+
+    pub fn satellite() -> Self {
+        Self {
+            latency: Duration::from_millis(300),
+            jitter: Duration::from_millis(10),
+            loss: 0.01,
+            ..Default::default()
+        }
+    }
+}
+
+/// A conn whose outgoing packets cross a link with `conditions`
+pub struct ImpairedConn {
+    conn: Arc<dyn Conn + Send + Sync>,
+    conditions: LinkConditions,
+    // When the link is done sending what was queued so far
+    busy_until: Mutex<Instant>,
+}
+
+impl ImpairedConn {
+    pub fn new(conn: Arc<dyn Conn + Send + Sync>, conditions: LinkConditions) -> Self {
+        Self {
+            conn,
+            conditions,
+            busy_until: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// How long a packet of `size` bytes takes to arrive, `None` when it's lost
+    fn delay(&self, size: usize) -> Option<Duration> {
+        if rand::random::<f64>() < self.conditions.loss {
+            return None;
+        }
+
+        let mut delay = self.conditions.latency + self.conditions.jitter.mul_f64(rand::random());
+        if rand::random::<f64>() < self.conditions.reorder {
+            delay += REORDER_DELAY;
+        }
+
+        if let Some(bandwidth) = self.conditions.bandwidth {
+            let now = Instant::now();
+            let mut busy_until = self.busy_until.lock().unwrap();
+            let start = (*busy_until).max(now);
+            if start - now > MAX_QUEUEING {
+                return None;
+            }
+
+            *busy_until = start + Duration::from_secs_f64(size as f64 / bandwidth as f64);
+            delay += *busy_until - now;
+        }
+
+        Some(delay)
+    }
+}
+
+#[async_trait]
+impl Conn for ImpairedConn {
+    async fn connect(&self, addr: SocketAddr) -> Result<()> {
+        self.conn.connect(addr).await
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> Result<usize> {
+        self.conn.recv(buf).await
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        self.conn.recv_from(buf).await
+    }
+
+    async fn send(&self, buf: &[u8]) -> Result<usize> {
+        self.conn.send(buf).await
+    }
+
+    /// Reports the packet as sent right away, it goes out once its delay is over
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> Result<usize> {
+        let delay = match self.delay(buf.len()) {
+            Some(x) => x,
+            None => return Ok(buf.len()),
+        };
+
+        let conn = Arc::clone(&self.conn);
+        let packet = buf.to_vec();
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            let _ = conn.send_to(&packet, target).await;
+        });
+
+        Ok(buf.len())
+    }
+
+    async fn local_addr(&self) -> Result<SocketAddr> {
+        self.conn.local_addr().await
+    }
+
+    async fn remote_addr(&self) -> Option<SocketAddr> {
+        self.conn.remote_addr().await
+    }
+
+    async fn close(&self) -> Result<()> {
+        self.conn.close().await
+    }
+}
@@ -0,0 +1,473 @@
+// Every test binary uses a part of the harness
+#![allow(dead_code)]
+
+pub use link::*;
+mod link;
+
+use std::{
+    fs,
+    net::{IpAddr, SocketAddr, TcpListener},
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use client::{
+    api::Api,
+    config::Config,
+    engine::{Engine, Task},
+    ice::VirtualNet,
+    identity::Identity,
+};
+use common::models::FindServerForFileReq;
+use discovery::{
+    auth::TokenStore,
+    turn::{EmbeddedTurn, TurnConfig},
+};
+use rocket::config::{Environment, LoggingLevel};
+use tokio::sync::Mutex;
+use uuid::Uuid;
+use webrtc::{
+    turn::server::Server,
+    util::vnet::{
+        nat::{EndpointDependencyType, NatType},
+        net::{Net, NetConfig},
+        router::{Router, RouterConfig},
+    },
+};
+
+// Discovery's STUN and TURN server sits on the WAN
+const TURN_IP: &str = "1.2.3.4";
+const TURN_PORT: u16 = 3478;
+const TURN_SECRET: &str = "north";
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Where a peer sits on the virtual network
+pub enum Host {
+    /// Directly on the WAN
+    Public(&'static str),
+    /// Behind a NAT of its own, `global` is the address of the NAT on the WAN
+    Natted {
+        global: &'static str,
+        local: &'static str,
+        nat_type: NatType,
+    },
+}
+
+/// A NAT which lets anyone through a mapping once it exists
+pub fn full_cone() -> NatType {
+    NatType {
+        mapping_behavior: EndpointDependencyType::EndpointIndependent,
+        filtering_behavior: EndpointDependencyType::EndpointIndependent,
+        ..Default::default()
+    }
+}
+
+/// A NAT with a mapping per destination, which only a relay gets through
+pub fn symmetric() -> NatType {
+    NatType {
+        mapping_behavior: EndpointDependencyType::EndpointAddrPortDependent,
+        filtering_behavior: EndpointDependencyType::EndpointAddrPortDependent,
+        ..Default::default()
+    }
+}
+
+/// A WAN with discovery's TURN server on it, which hosts are added to before it starts
+pub struct VirtualNetwork {
+    wan: Arc<Mutex<Router>>,
+    turn_net: Arc<Net>,
+    relay_conditions: Option<LinkConditions>,
+}
+
+impl VirtualNetwork {
+    pub async fn new() -> Self {
+        let wan = Arc::new(Mutex::new(
+            Router::new(RouterConfig {
+                cidr: "0.0.0.0/0".to_owned(),
+                ..Default::default()
+            })
+            .expect("valid WAN"),
+        ));
+
+        let turn_net = Arc::new(Net::new(Some(NetConfig {
+            static_ips: vec![TURN_IP.to_owned()],
+            ..Default::default()
+        })));
+        connect_net(&turn_net, &wan).await;
+
+        Self {
+            wan,
+            turn_net,
+            relay_conditions: None,
+        }
+    }
+
+    pub async fn add_host(&self, host: Host) -> Arc<Net> {
+        match host {
+            Host::Public(ip) => {
+                let net = Arc::new(Net::new(Some(NetConfig {
+                    static_ips: vec![ip.to_owned()],
+                    ..Default::default()
+                })));
+                connect_net(&net, &self.wan).await;
+
+                net
+            }
+            Host::Natted {
+                global,
+                local,
+                nat_type,
+            } => {
+                let lan = Arc::new(Mutex::new(
+                    Router::new(RouterConfig {
+                        static_ips: vec![global.to_owned()],
+                        cidr: format!("{}/24", local),
+                        nat_type: Some(nat_type),
+                        ..Default::default()
+                    })
+                    .expect("valid LAN"),
+                ));
+                self.wan
+                    .lock()
+                    .await
+                    .add_router(Arc::clone(&lan))
+                    .await
+                    .expect("LAN added to WAN");
+                lan.lock()
+                    .await
+                    .set_router(Arc::clone(&self.wan))
+                    .await
+                    .expect("WAN set on LAN");
+
+                let net = Arc::new(Net::new(Some(NetConfig {
+                    static_ips: vec![local.to_owned()],
+                    ..Default::default()
+                })));
+                connect_net(&net, &lan).await;
+
+                net
+            }
+        }
+    }
+
+    /// Drops `ratio` of the packets crossing the WAN, whichever way they go
+    pub async fn drop_packets(&self, ratio: f64) {
+        self.wan
+            .lock()
+            .await
+            .add_chunk_filter(Box::new(move |_| rand::random::<f64>() >= ratio))
+            .await;
+    }
+
+    /// Every packet the TURN server relays crosses a link with `conditions` on its way
+    /// out, so relay only peers go through it both ways
+    pub fn impair_relay(&mut self, conditions: LinkConditions) {
+        self.relay_conditions = Some(conditions);
+    }
+
+    /// Starts routing, the TURN server and discovery
+    pub async fn start(self) -> Harness {
+        self.wan.lock().await.start().await.expect("WAN started");
+
+        let embedded_turn = EmbeddedTurn {
+            public_ip: TURN_IP.parse::<IpAddr>().expect("valid TURN ip"),
+            port: TURN_PORT,
+        };
+        let turn_server = match self.relay_conditions {
+            Some(conditions) => {
+                let conn = self
+                    .turn_net
+                    .bind(SocketAddr::new(embedded_turn.public_ip, TURN_PORT))
+                    .await
+                    .expect("TURN conn bound");
+                embedded_turn
+                    .serve_on(
+                        TURN_SECRET.to_string(),
+                        Arc::new(ImpairedConn::new(conn, conditions)),
+                        Arc::clone(&self.turn_net),
+                    )
+                    .await
+            }
+            None => {
+                embedded_turn
+                    .serve(TURN_SECRET.to_string(), Arc::clone(&self.turn_net))
+                    .await
+            }
+        }
+        .expect("TURN server started");
+
+        let dir = test_dir();
+        let discovery_url = start_discovery(
+            &dir,
+            Some(TurnConfig {
+                urls: vec![embedded_turn.turn_url()],
+                stun_urls: vec![embedded_turn.stun_url()],
+                secret: TURN_SECRET.to_string(),
+                ttl_secs: 3600,
+            }),
+        )
+        .await;
+
+        Harness {
+            discovery_url,
+            dir,
+            _wan: Some(self.wan),
+            _turn_server: Some(turn_server),
+        }
+    }
+}
+
+/// Discovery and the network peers transfer files across
+pub struct Harness {
+    discovery_url: String,
+    dir: PathBuf,
+    _wan: Option<Arc<Mutex<Router>>>,
+    _turn_server: Option<Server>,
+}
+
+impl Harness {
+    /// Peers on the host's network, for measuring what a transfer costs rather than
+    /// how it copes with one
+    pub async fn loopback() -> Self {
+        let dir = test_dir();
+        let discovery_url = start_discovery(&dir, None).await;
+
+        Self {
+            discovery_url,
+            dir,
+            _wan: None,
+            _turn_server: None,
+        }
+    }
+
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    pub fn write_file(&self, name: &str, contents: &[u8]) -> PathBuf {
+        let path = self.path(name);
+        fs::write(&path, contents).expect("file written");
+        path
+    }
+
+    /// Shares the file at `path` from `net`, the host's network without one, once
+    /// discovery finds it
+    pub async fn share(&self, net: Option<Arc<Net>>, path: &Path, relay_only: bool) -> Uuid {
+        let file_id = Uuid::new_v4();
+        let config = self.peer_config(net, relay_only);
+        let api = Api::from_config(&config);
+
+        run_peer(
+            config,
+            Task::Share {
+                file_id,
+                path: path.to_string_lossy().to_string(),
+                passphrase: None,
+                with_code: false,
+                access: None,
+            },
+        );
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while Instant::now() < deadline {
+            let req = FindServerForFileReq {
+                file_id: file_id.to_string(),
+                share_token: None,
+            };
+            if let Ok(res) = api.find_servers(req).await {
+                if !res.servers_info.is_empty() {
+                    return file_id;
+                }
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+        panic!("datasource never registered");
+    }
+
+    /// Fetches `file_id` from `net` into `download_dir`
+    pub fn fetch(
+        &self,
+        net: Option<Arc<Net>>,
+        file_id: Uuid,
+        download_dir: &Path,
+        relay_only: bool,
+    ) {
+        run_peer(
+            self.peer_config(net, relay_only),
+            Task::Fetch {
+                file_id,
+                download_dir: download_dir.to_string_lossy().to_string(),
+                passphrase: None,
+                share_token: None,
+            },
+        );
+    }
+
+    /// Runs a daemon on the host's network, returns the url of its control API once it
+    /// answers
+    pub async fn daemon(&self) -> String {
+        let control_port = free_port();
+        let config = Config {
+            control_port: Some(control_port),
+            control_token_file: Some(self.path("control_token")),
+            ..self.peer_config(None, false)
+        };
+        run_peer(config, Task::Daemon);
+
+        let control_url = format!("http://127.0.0.1:{}", control_port);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while reqwest::get(format!("{}/transfers", control_url))
+            .await
+            .is_err()
+        {
+            assert!(Instant::now() < deadline, "daemon never started");
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+
+        control_url
+    }
+
+    /// A client sending the token of the daemon, which has to be running
+    pub fn control_client(&self) -> reqwest::Client {
+        let token = fs::read_to_string(self.path("control_token")).expect("control token");
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert(
+            reqwest::header::AUTHORIZATION,
+            format!("Bearer {}", token.trim())
+                .parse()
+                .expect("valid header"),
+        );
+
+        reqwest::Client::builder()
+            .default_headers(headers)
+            .build()
+            .expect("control client")
+    }
+
+    // No public STUN server, only the servers of discovery
+    fn peer_config(&self, net: Option<Arc<Net>>, relay_only: bool) -> Config {
+        Config {
+            discovery_url: Some(self.discovery_url.clone()),
+            port: Some(free_port()),
+            ice_servers: Some(vec![]),
+            relay_only,
+            vnet: net.map(VirtualNet),
+            ..Default::default()
+        }
+    }
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// The contents of the file at `path` once it is `size` bytes long
+pub async fn wait_for_file(path: &Path, size: usize, timeout: Duration) -> Option<Vec<u8>> {
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        if let Ok(contents) = fs::read(path) {
+            if contents.len() == size {
+                return Some(contents);
+            }
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+
+    None
+}
+
+pub fn random_bytes(size: usize) -> Vec<u8> {
+    (0..size).map(|_| rand::random::<u8>()).collect()
+}
+
+async fn connect_net(net: &Arc<Net>, router: &Arc<Mutex<Router>>) {
+    let nic = net.get_nic().expect("virtual nic");
+    router
+        .lock()
+        .await
+        .add_net(Arc::clone(&nic))
+        .await
+        .expect("net added to router");
+    nic.lock()
+        .await
+        .set_router(Arc::clone(router))
+        .await
+        .expect("router set on net");
+}
+
+fn test_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("turent-test-{}", Uuid::new_v4()));
+    fs::create_dir_all(&dir).expect("test dir created");
+    dir
+}
+
+/// Discovery listens on loopback, signaling goes through it and the peers' own servers
+/// whichever network their peer connections are on
+async fn start_discovery(dir: &Path, turn: Option<TurnConfig>) -> String {
+    let port = free_port();
+    let config = rocket::Config::build(Environment::Development)
+        .address("127.0.0.1")
+        .port(port)
+        .log_level(LoggingLevel::Critical)
+        .finalize()
+        .expect("valid rocket config");
+    // No tokens, so the API is open
+    let tokens = TokenStore::new(dir.join("discovery_tokens.json"));
+
+    thread::spawn(move || {
+        let err = discovery::mount(rocket::custom(config), tokens, turn).launch();
+        println!("Discovery stopped, err: {:?}", err);
+    });
+
+    let discovery_url = format!("http://127.0.0.1:{}", port);
+    let api = Api::from_config(&Config {
+        discovery_url: Some(discovery_url.clone()),
+        ..Default::default()
+    });
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while api.discovery_hello().await.is_err() {
+        assert!(Instant::now() < deadline, "discovery never started");
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+
+    discovery_url
+}
+
+/// Runs an engine on a thread of its own, like a separate process would
+fn run_peer(config: Config, task: Task) {
+    let (init_data_sink, init_data_source) = match task {
+        Task::Share { .. } => (false, true),
+        Task::Fetch { .. } | Task::FetchCode { .. } => (true, false),
+        Task::Daemon => (true, true),
+    };
+
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().expect("tokio runtime");
+        let res = runtime.block_on(async move {
+            Engine::from_config(
+                config,
+                Identity::generate()?,
+                Some(Uuid::new_v4()),
+                init_data_sink,
+                init_data_source,
+                false,
+            )
+            .await?
+            .start(task)
+            .await
+        });
+        if let Err(err) = res {
+            println!("Peer stopped, err: {:?}", err);
+        }
+    });
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .map(|addr| addr.port())
+        .expect("free port")
+}
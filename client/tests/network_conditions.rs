@@ -0,0 +1,62 @@
+//! Relayed transfers across links with latency, jitter, loss, reordering and capped
+//! bandwidth, each of which has to end with the file intact
+
+mod harness;
+
+use std::time::Duration;
+
+use harness::{random_bytes, wait_for_file, Host, LinkConditions, VirtualNetwork};
+
+const FILE_SIZE: usize = 256 * 1024;
+const TIMEOUT: Duration = Duration::from_secs(120);
+
+async fn transfer_across(conditions: LinkConditions) {
+    let mut network = VirtualNetwork::new().await;
+    let source_net = network.add_host(Host::Public("1.2.3.5")).await;
+    let sink_net = network.add_host(Host::Public("1.2.3.6")).await;
+    network.impair_relay(conditions);
+    let harness = network.start().await;
+
+    let contents = random_bytes(FILE_SIZE);
+    let path = harness.write_file("payload.bin", &contents);
+    let file_id = harness.share(Some(source_net), &path, true).await;
+
+    let download_dir = harness.path("received");
+    harness.fetch(Some(sink_net), file_id, &download_dir, true);
+
+    let received = wait_for_file(&download_dir.join("payload.bin"), FILE_SIZE, TIMEOUT).await;
+    assert!(
+        received == Some(contents),
+        "file wasn't received intact across {:?}",
+        conditions
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_transfer_across_broadband() {
+    transfer_across(LinkConditions::broadband()).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_transfer_across_mobile() {
+    transfer_across(LinkConditions::mobile()).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_transfer_across_congested_link() {
+    transfer_across(LinkConditions::congested()).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_transfer_across_satellite() {
+    transfer_across(LinkConditions::satellite()).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_transfer_with_reordering_only() {
+    transfer_across(LinkConditions {
+        reorder: 0.25,
+        ..Default::default()
+    })
+    .await;
+}
@@ -0,0 +1,84 @@
+//! Transfers across webrtc's virtual network, with discovery running in process
+
+mod harness;
+
+use std::time::Duration;
+
+use harness::{full_cone, random_bytes, symmetric, wait_for_file, Host, VirtualNetwork};
+
+const FILE_SIZE: usize = 512 * 1024;
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+async fn transfer(source: Host, sink: Host, relay_only: bool, loss: f64) {
+    let network = VirtualNetwork::new().await;
+    let source_net = network.add_host(source).await;
+    let sink_net = network.add_host(sink).await;
+    if loss > 0.0 {
+        network.drop_packets(loss).await;
+    }
+    let harness = network.start().await;
+
+    let contents = random_bytes(FILE_SIZE);
+    let path = harness.write_file("payload.bin", &contents);
+    let file_id = harness.share(Some(source_net), &path, relay_only).await;
+
+    let download_dir = harness.path("received");
+    harness.fetch(Some(sink_net), file_id, &download_dir, relay_only);
+
+    let received = wait_for_file(&download_dir.join("payload.bin"), FILE_SIZE, TIMEOUT).await;
+    assert!(received == Some(contents), "file wasn't received intact");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_transfer_between_public_hosts() {
+    transfer(Host::Public("1.2.3.5"), Host::Public("1.2.3.6"), false, 0.0).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_transfer_through_full_cone_nats() {
+    transfer(
+        Host::Natted {
+            global: "27.1.1.1",
+            local: "192.168.0.1",
+            nat_type: full_cone(),
+        },
+        Host::Natted {
+            global: "28.1.1.1",
+            local: "10.2.0.1",
+            nat_type: full_cone(),
+        },
+        false,
+        0.0,
+    )
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_transfer_relayed_through_symmetric_nats() {
+    transfer(
+        Host::Natted {
+            global: "27.1.1.1",
+            local: "192.168.0.1",
+            nat_type: symmetric(),
+        },
+        Host::Natted {
+            global: "28.1.1.1",
+            local: "10.2.0.1",
+            nat_type: symmetric(),
+        },
+        true,
+        0.0,
+    )
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_transfer_with_packet_loss() {
+    transfer(
+        Host::Public("1.2.3.5"),
+        Host::Public("1.2.3.6"),
+        false,
+        0.05,
+    )
+    .await;
+}
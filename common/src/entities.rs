@@ -1,18 +1,25 @@
+use std::collections::HashMap;
+
 use serde::{Deserialize, Serialize};
 use webrtc::ice_transport::{ice_credential_type::RTCIceCredentialType, ice_server::RTCIceServer};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub enum IceCredentialType {
+    #[default]
     Unspecified,
     Password,
     Oauth,
 }
 
+// STUN servers need nothing but urls
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct IceServer {
     pub urls: Vec<String>,
+    #[serde(default)]
     pub username: String,
+    #[serde(default)]
     pub credential: String,
+    #[serde(default)]
     pub credential_type: IceCredentialType,
 }
 
@@ -33,36 +40,50 @@ impl IceServer {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// How many pieces of a file a server has, so that servers which are still
+/// downloading a file can already serve it
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct FileCompleteness {
+    pub have: u32,
+    pub total: u32,
+}
+
+impl FileCompleteness {
+    pub fn is_complete(&self) -> bool {
+        self.have == self.total
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct ServerInfo {
     pub files: Vec<String>,
     pub ice_servers: Vec<IceServer>,
     pub url: String,
     pub id: String,
+    // Files missing from here are complete
+    #[serde(default)]
+    pub completeness: HashMap<String, FileCompleteness>,
+    // Fingerprint of the DTLS certificate the server answers offers with
+    #[serde(default)]
+    pub fingerprint: String,
+    // Key the server id belongs to, only registrations signed with it are accepted
+    #[serde(default)]
+    pub public_key: String,
+    // Timestamp of the latest registration, older ones are replays
+    #[serde(default)]
+    pub registered_at: u64,
 }
 
-impl Default for ServerInfo {
-    fn default() -> Self {
-        Self {
-            files: Default::default(),
-            ice_servers: Default::default(),
-            url: String::new(),
-            id: String::new(),
-        }
+impl ServerInfo {
+    pub fn file_completeness(&self, file_id: &str) -> Option<FileCompleteness> {
+        self.completeness.get(file_id).copied()
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct ClientInfo {
     pub url: String,
     pub id: String,
 }
 
-impl Default for ClientInfo {
-    fn default() -> Self {
-        Self {
-            url: String::new(),
-            id: String::new(),
-        }
-    }
-}
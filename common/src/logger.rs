@@ -1,25 +1,56 @@
-use std::error::Error;
+use std::{fs::OpenOptions, io, path::PathBuf, sync::Mutex};
 
-#[derive(Clone)]
-pub struct Logger {
-    debug: bool,
-}
+use tracing_subscriber::{fmt::writer::BoxMakeWriter, EnvFilter};
 
-impl Logger {
-    pub fn new(debug: bool) -> Self {
-        Logger { debug }
-    }
+/// How logs are filtered and where they go
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogConfig {
+    // A filter like `info` or `client=debug,webrtc=warn`
+    pub filter: String,
+    // One JSON object per line, along with the spans the event happened in
+    pub json: bool,
+    // Appended to instead of writing to stdout
+    pub file: Option<PathBuf>,
+}
 
-    pub fn log_debug(&self, log: &str) {
-        if self.debug {
-            println!("{:?}", log);
+impl LogConfig {
+    /// Reads `TURENT_LOG`, `TURENT_LOG_FORMAT` ( `text` or `json` ) and `TURENT_LOG_FILE`
+    pub fn from_env(default_filter: &str) -> Self {
+        Self {
+            filter: std::env::var("TURENT_LOG").unwrap_or_else(|_| default_filter.to_string()),
+            json: std::env::var("TURENT_LOG_FORMAT").is_ok_and(|format| format == "json"),
+            file: std::env::var("TURENT_LOG_FILE").ok().map(PathBuf::from),
         }
     }
+}
 
-    pub fn log(&self, log: String) {
-        println!("{:?}", log);
-    }
-    pub fn log_err(&self, err: &impl Error) {
-        println!("ERR: {:?}", err);
-    }
+/// Installs the global subscriber, events of the `log` crate ( webrtc's and Rocket's )
+/// go through it as well
+pub fn init(config: &LogConfig) -> io::Result<()> {
+    let filter = EnvFilter::try_new(&config.filter).unwrap_or_else(|_| EnvFilter::new("info"));
+
+    let writer = match &config.file {
+        Some(path) => BoxMakeWriter::new(Mutex::new(
+            OpenOptions::new().create(true).append(true).open(path)?,
+        )),
+        None => BoxMakeWriter::new(io::stdout),
+    };
+
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer)
+        .with_ansi(config.file.is_none());
+
+    // Fails when there already is one, like in tests running several engines
+    let _ = if config.json {
+        builder
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .try_init()
+    } else {
+        builder.try_init()
+    };
+
+    Ok(())
 }
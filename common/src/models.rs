@@ -1,10 +1,12 @@
+use std::collections::{BTreeMap, HashMap};
+
 use serde::{Deserialize, Serialize};
 use webrtc::{
     ice_transport::ice_candidate::RTCIceCandidate,
     peer_connection::sdp::session_description::RTCSessionDescription,
 };
 
-use crate::entities::{ClientInfo, IceServer, ServerInfo};
+use crate::entities::{ClientInfo, FileCompleteness, IceServer, ServerInfo};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct FileLookupReq {
@@ -22,6 +24,76 @@ pub struct RegisterOrRefreshServerReq {
     pub ice_candidates: Option<Vec<IceServer>>,
     #[serde(rename = "url")]
     pub url: String,
+    #[serde(rename = "completeness", default)]
+    pub completeness: Option<HashMap<String, FileCompleteness>>,
+    #[serde(rename = "fingerprint", default)]
+    pub fingerprint: Option<String>,
+    // Files only found with a share token signed by this server's key
+    #[serde(rename = "privateFiles", default)]
+    pub private_files: Option<Vec<String>>,
+    // Hex encoded Ed25519 public key the server id belongs to
+    #[serde(rename = "publicKey")]
+    pub public_key: String,
+    // Seconds since the unix epoch
+    #[serde(rename = "timestamp")]
+    pub timestamp: u64,
+    // Hex encoded signature of `signing_payload`
+    #[serde(rename = "signature")]
+    pub signature: String,
+}
+
+impl RegisterOrRefreshServerReq {
+    /// What a registration is signed over, which is everything but the signature. Maps are
+    /// sorted so that both ends serialize them the same way.
+    pub fn signing_payload(&self) -> Vec<u8> {
+        let completeness: Option<BTreeMap<&String, &FileCompleteness>> = self
+            .completeness
+            .as_ref()
+            .map(|completeness| completeness.iter().collect());
+
+        // Serializing plain data into a Vec can't fail
+        serde_json::to_vec(&(
+            &self.server_id,
+            &self.files,
+            &self.ice_candidates,
+            &self.url,
+            completeness,
+            &self.fingerprint,
+            &self.private_files,
+            &self.public_key,
+            self.timestamp,
+        ))
+        .unwrap_or_default()
+    }
+}
+
+/// Removes a server whose peer connection closed, signed by the key which registered it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeregisterServerReq {
+    #[serde(rename = "serverId")]
+    pub server_id: String,
+    #[serde(rename = "publicKey")]
+    pub public_key: String,
+    // Seconds since the unix epoch
+    #[serde(rename = "timestamp")]
+    pub timestamp: u64,
+    // Hex encoded signature of `signing_payload`
+    #[serde(rename = "signature")]
+    pub signature: String,
+}
+
+impl DeregisterServerReq {
+    /// Tagged so a signature over it can't pass for one over a registration
+    pub fn signing_payload(&self) -> Vec<u8> {
+        // Serializing plain data into a Vec can't fail
+        serde_json::to_vec(&(
+            "deregister",
+            &self.server_id,
+            &self.public_key,
+            self.timestamp,
+        ))
+        .unwrap_or_default()
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -44,6 +116,52 @@ pub struct OfferRes {
 pub struct FindServerForFileReq {
     #[serde(rename = "fileId")]
     pub file_id: String,
+    // Needed for private files, sent as the `X-Share-Token` header
+    #[serde(rename = "shareToken", default)]
+    pub share_token: Option<String>,
+}
+
+/// Lets its bearer find the servers of a private file until it expires, minted and
+/// signed by the owner of the file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ShareToken {
+    #[serde(rename = "tokenId")]
+    pub token_id: String,
+    #[serde(rename = "fileId")]
+    pub file_id: String,
+    // Seconds since the unix epoch
+    #[serde(rename = "expiresAt")]
+    pub expires_at: u64,
+    // Lookups allowed with the token, unlimited when missing
+    #[serde(rename = "maxDownloads", default)]
+    pub max_downloads: Option<u32>,
+    // Hex encoded signature of `signing_payload`
+    #[serde(rename = "signature")]
+    pub signature: String,
+}
+
+impl ShareToken {
+    /// What the owner signs, which is everything but the signature
+    pub fn signing_payload(&self) -> Vec<u8> {
+        serde_json::to_vec(&(
+            "turent share token",
+            &self.token_id,
+            &self.file_id,
+            self.expires_at,
+            self.max_downloads,
+        ))
+        .unwrap_or_default()
+    }
+
+    /// The token as it is handed around, hex so it survives shells and headers
+    pub fn encode(&self) -> String {
+        hex::encode(serde_json::to_vec(self).unwrap_or_default())
+    }
+
+    pub fn decode(token: &str) -> Option<Self> {
+        let bytes = hex::decode(token.trim()).ok()?;
+        serde_json::from_slice(&bytes).ok()
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -61,3 +179,37 @@ pub struct CandidateReq {
     #[serde(rename = "candidates")]
     pub candidate: RTCIceCandidate,
 }
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClaimNameplateReq {
+    #[serde(rename = "fileId")]
+    pub file_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClaimNameplateRes {
+    #[serde(rename = "nameplate")]
+    pub nameplate: String,
+    #[serde(rename = "success")]
+    pub success: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LookupNameplateRes {
+    #[serde(rename = "fileId")]
+    pub file_id: String,
+    #[serde(rename = "success")]
+    pub success: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TurnCredentialsRes {
+    // Empty when the deployment has no TURN server
+    #[serde(rename = "iceServers")]
+    pub ice_servers: Vec<IceServer>,
+    // Seconds the credentials are valid for
+    #[serde(rename = "ttl")]
+    pub ttl: u64,
+    #[serde(rename = "success")]
+    pub success: bool,
+}
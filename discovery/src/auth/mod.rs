@@ -0,0 +1,6 @@
+pub use share::*;
+pub use signature::*;
+pub use token::*;
+mod share;
+mod signature;
+mod token;
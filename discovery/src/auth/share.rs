@@ -0,0 +1,103 @@
+use common::models::ShareToken;
+use ring::signature::{UnparsedPublicKey, ED25519};
+use rocket::{
+    request::{self, FromRequest, Request},
+    Outcome,
+};
+
+use crate::errors::DiscoveryError;
+
+/// Checks that a share token is for the file, signed by its owner and not expired
+pub fn verify_share_token(
+    token: &ShareToken,
+    file_id: &str,
+    owner_public_key: &str,
+    now: u64,
+) -> Result<(), DiscoveryError> {
+    if token.file_id != file_id {
+        return Err(DiscoveryError::InvalidShareTokenError);
+    }
+
+    if token.expires_at <= now {
+        return Err(DiscoveryError::ShareTokenExpiredError);
+    }
+
+    let public_key =
+        hex::decode(owner_public_key).map_err(|_| DiscoveryError::InvalidShareTokenError)?;
+    let signature =
+        hex::decode(&token.signature).map_err(|_| DiscoveryError::InvalidShareTokenError)?;
+
+    UnparsedPublicKey::new(&ED25519, public_key)
+        .verify(&token.signing_payload(), &signature)
+        .map_err(|_| DiscoveryError::InvalidShareTokenError)
+}
+
+/// The `X-Share-Token` header, if one was sent
+pub struct ShareTokenHeader(pub Option<String>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for ShareTokenHeader {
+    type Error = DiscoveryError;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let token = request
+            .headers()
+            .get_one("X-Share-Token")
+            .map(|token| token.to_string());
+
+        Outcome::Success(ShareTokenHeader(token))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common::models::ShareToken;
+    use ring::{
+        rand::SystemRandom,
+        signature::{Ed25519KeyPair, KeyPair},
+    };
+
+    use super::verify_share_token;
+
+    const FILE_ID: &str = "67e55044-10b1-426f-9247-bb680e5ff1b8";
+    const NOW: u64 = 1_650_000_000;
+
+    fn key_pair() -> Ed25519KeyPair {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap()
+    }
+
+    fn signed_token(key_pair: &Ed25519KeyPair, expires_at: u64) -> ShareToken {
+        let mut token = ShareToken {
+            token_id: String::from("token"),
+            file_id: String::from(FILE_ID),
+            expires_at,
+            max_downloads: Some(3),
+            signature: String::new(),
+        };
+        token.signature = hex::encode(key_pair.sign(&token.signing_payload()).as_ref());
+        token
+    }
+
+    #[test]
+    fn test_verify_share_token() {
+        let owner = key_pair();
+        let owner_public_key = hex::encode(owner.public_key().as_ref());
+
+        let token = signed_token(&owner, NOW + 60);
+        assert!(verify_share_token(&token, FILE_ID, &owner_public_key, NOW).is_ok());
+
+        // Tokens survive being passed around as text
+        let decoded = ShareToken::decode(&token.encode()).unwrap();
+        assert!(verify_share_token(&decoded, FILE_ID, &owner_public_key, NOW).is_ok());
+
+        assert!(verify_share_token(&token, FILE_ID, &owner_public_key, NOW + 60).is_err());
+        assert!(verify_share_token(&token, "another file", &owner_public_key, NOW).is_err());
+
+        let mut raised = token.clone();
+        raised.max_downloads = None;
+        assert!(verify_share_token(&raised, FILE_ID, &owner_public_key, NOW).is_err());
+
+        let forged = signed_token(&key_pair(), NOW + 60);
+        assert!(verify_share_token(&forged, FILE_ID, &owner_public_key, NOW).is_err());
+    }
+}
@@ -0,0 +1,198 @@
+use common::{
+    entities::ServerInfo,
+    models::{DeregisterServerReq, RegisterOrRefreshServerReq},
+};
+use ring::signature::{UnparsedPublicKey, ED25519};
+
+use crate::errors::DiscoveryError;
+
+// Registrations signed further away from now than this are rejected as replays
+const MAX_CLOCK_SKEW_SECS: u64 = 5 * 60;
+
+/// Checks that a registration was signed with the key it carries, recently
+pub fn verify_registration(
+    req: &RegisterOrRefreshServerReq,
+    now: u64,
+) -> Result<(), DiscoveryError> {
+    verify_signature(
+        &req.signing_payload(),
+        &req.public_key,
+        &req.signature,
+        req.timestamp,
+        now,
+    )
+}
+
+/// Checks that a deregistration was signed recently by the key which registered the
+/// server, after it registered
+pub fn verify_deregistration(
+    req: &DeregisterServerReq,
+    server_info: &ServerInfo,
+    now: u64,
+) -> Result<(), DiscoveryError> {
+    verify_signature(
+        &req.signing_payload(),
+        &req.public_key,
+        &req.signature,
+        req.timestamp,
+        now,
+    )?;
+
+    if req.public_key != server_info.public_key {
+        return Err(DiscoveryError::ServerIdOwnedError);
+    }
+
+    if req.timestamp < server_info.registered_at {
+        return Err(DiscoveryError::StaleRegistrationError);
+    }
+
+    Ok(())
+}
+
+fn verify_signature(
+    payload: &[u8],
+    public_key: &str,
+    signature: &str,
+    timestamp: u64,
+    now: u64,
+) -> Result<(), DiscoveryError> {
+    // Timestamps are whatever the request says, so they can't be added to unchecked
+    if timestamp.saturating_add(MAX_CLOCK_SKEW_SECS) < now
+        || timestamp > now.saturating_add(MAX_CLOCK_SKEW_SECS)
+    {
+        return Err(DiscoveryError::StaleRegistrationError);
+    }
+
+    let public_key = hex::decode(public_key).map_err(|_| DiscoveryError::InvalidSignatureError)?;
+    let signature = hex::decode(signature).map_err(|_| DiscoveryError::InvalidSignatureError)?;
+
+    UnparsedPublicKey::new(&ED25519, public_key)
+        .verify(payload, &signature)
+        .map_err(|_| DiscoveryError::InvalidSignatureError)
+}
+
+/// Checks that a refresh comes from the key which registered the server id first
+pub fn verify_ownership(
+    req: &RegisterOrRefreshServerReq,
+    server_info: &ServerInfo,
+) -> Result<(), DiscoveryError> {
+    if req.public_key != server_info.public_key {
+        return Err(DiscoveryError::ServerIdOwnedError);
+    }
+
+    if req.timestamp < server_info.registered_at {
+        return Err(DiscoveryError::StaleRegistrationError);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use common::{
+        entities::ServerInfo,
+        models::{DeregisterServerReq, RegisterOrRefreshServerReq},
+    };
+    use ring::{
+        rand::SystemRandom,
+        signature::{Ed25519KeyPair, KeyPair},
+    };
+
+    use super::{verify_deregistration, verify_ownership, verify_registration};
+
+    const NOW: u64 = 1_650_000_000;
+
+    fn signed_req(key_pair: &Ed25519KeyPair, timestamp: u64) -> RegisterOrRefreshServerReq {
+        let mut req = RegisterOrRefreshServerReq {
+            server_id: String::from("67e55044-10b1-426f-9247-bb680e5fe0c8"),
+            files: Some(vec![String::from("67e55044-10b1-426f-9247-bb680e5ff1b8")]),
+            ice_candidates: None,
+            url: String::from("http://localhost:8080"),
+            completeness: None,
+            fingerprint: Some(String::from("sha-256 ab:cd")),
+            private_files: None,
+            public_key: hex::encode(key_pair.public_key().as_ref()),
+            timestamp,
+            signature: String::new(),
+        };
+        req.signature = hex::encode(key_pair.sign(&req.signing_payload()).as_ref());
+        req
+    }
+
+    fn key_pair() -> Ed25519KeyPair {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap()
+    }
+
+    #[test]
+    fn test_verify_registration() {
+        let key_pair = key_pair();
+
+        let req = signed_req(&key_pair, NOW);
+        assert!(verify_registration(&req, NOW + 10).is_ok());
+
+        // Too old to not be a replay
+        assert!(verify_registration(&req, NOW + 3600).is_err());
+
+        // Timestamps at the very end of time are too far ahead, not an overflow
+        assert!(verify_registration(&signed_req(&key_pair, u64::MAX), NOW).is_err());
+        assert!(verify_registration(&req, u64::MAX).is_err());
+
+        let mut tampered = req.clone();
+        tampered.url = String::from("http://evil:8080");
+        assert!(verify_registration(&tampered, NOW).is_err());
+
+        // Signed by someone else than the key it carries
+        let mut forged = signed_req(&self::key_pair(), NOW);
+        forged.public_key = req.public_key.clone();
+        assert!(verify_registration(&forged, NOW).is_err());
+    }
+
+    #[test]
+    fn test_verify_ownership() {
+        let key_pair = key_pair();
+
+        let mut server_info = ServerInfo::default();
+        server_info.public_key = hex::encode(key_pair.public_key().as_ref());
+        server_info.registered_at = NOW;
+
+        assert!(verify_ownership(&signed_req(&key_pair, NOW + 1), &server_info).is_ok());
+        assert!(verify_ownership(&signed_req(&key_pair, NOW - 1), &server_info).is_err());
+        assert!(verify_ownership(&signed_req(&self::key_pair(), NOW + 1), &server_info).is_err());
+    }
+
+    #[test]
+    fn test_verify_deregistration() {
+        let key_pair = key_pair();
+        let deregistration = |key_pair: &Ed25519KeyPair, timestamp| {
+            let mut req = DeregisterServerReq {
+                server_id: String::from("67e55044-10b1-426f-9247-bb680e5fe0c8"),
+                public_key: hex::encode(key_pair.public_key().as_ref()),
+                timestamp,
+                signature: String::new(),
+            };
+            req.signature = hex::encode(key_pair.sign(&req.signing_payload()).as_ref());
+            req
+        };
+
+        let mut server_info = ServerInfo::default();
+        server_info.public_key = hex::encode(key_pair.public_key().as_ref());
+        server_info.registered_at = NOW;
+
+        let req = deregistration(&key_pair, NOW + 1);
+        assert!(verify_deregistration(&req, &server_info, NOW + 1).is_ok());
+
+        // Replayed, from before the registration, or by another key
+        assert!(verify_deregistration(&req, &server_info, NOW + 3600).is_err());
+        let req = deregistration(&key_pair, NOW - 1);
+        assert!(verify_deregistration(&req, &server_info, NOW).is_err());
+        let req = deregistration(&self::key_pair(), NOW + 1);
+        assert!(verify_deregistration(&req, &server_info, NOW + 1).is_err());
+
+        // A registration's signature doesn't pass for a deregistration
+        let registration = signed_req(&key_pair, NOW + 1);
+        let mut req = deregistration(&key_pair, NOW + 1);
+        req.signature = registration.signature;
+        assert!(verify_deregistration(&req, &server_info, NOW + 1).is_err());
+    }
+}
@@ -0,0 +1,268 @@
+use std::{fs, marker::PhantomData, path::PathBuf};
+
+use ring::{
+    digest::{digest, SHA256},
+    rand::{SecureRandom, SystemRandom},
+};
+use rocket::{
+    http::Status,
+    request::{self, FromRequest, Request},
+    Outcome, State,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{errors::DiscoveryError, metrics::Metrics};
+
+const DEFAULT_TOKENS_FILE: &str = "discovery_tokens.json";
+const TOKEN_LEN: usize = 32;
+
+/// What an API token is allowed to do, admin implies every other scope
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    Register,
+    Lookup,
+    Admin,
+}
+
+impl Scope {
+    pub fn parse(scope: &str) -> Option<Scope> {
+        match scope {
+            "register" => Some(Scope::Register),
+            "lookup" => Some(Scope::Lookup),
+            "admin" => Some(Scope::Admin),
+            _ => None,
+        }
+    }
+}
+
+/// Only a hash of a token is stored, the token itself is printed once when it's created
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ApiToken {
+    #[serde(rename = "name")]
+    pub name: String,
+    #[serde(rename = "hash")]
+    pub hash: String,
+    #[serde(rename = "scopes")]
+    pub scopes: Vec<Scope>,
+}
+
+impl ApiToken {
+    fn allows(&self, scope: Scope) -> bool {
+        self.scopes
+            .iter()
+            .any(|allowed| *allowed == scope || *allowed == Scope::Admin)
+    }
+}
+
+/// The API tokens of discovery, kept in a JSON file which is read on every request so
+/// revoking a token takes effect without a restart. The API is open while there are none.
+pub struct TokenStore {
+    path: PathBuf,
+}
+
+impl TokenStore {
+    /// Uses the file at `DISCOVERY_TOKENS`, or `discovery_tokens.json`
+    pub fn from_env() -> Self {
+        let path =
+            std::env::var("DISCOVERY_TOKENS").unwrap_or_else(|_| DEFAULT_TOKENS_FILE.to_string());
+
+        Self::new(PathBuf::from(path))
+    }
+
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    pub fn list(&self) -> Result<Vec<ApiToken>, DiscoveryError> {
+        let contents = match fs::read_to_string(&self.path) {
+            Ok(x) => x,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(_) => return Err(DiscoveryError::InternalServerError),
+        };
+
+        serde_json::from_str(&contents).map_err(|_| DiscoveryError::InternalServerError)
+    }
+
+    /// Creates a token under a new name, returning the token itself
+    pub fn add(&self, name: String, scopes: Vec<Scope>) -> Result<String, DiscoveryError> {
+        let mut tokens = self.list()?;
+        if tokens.iter().any(|token| token.name == name) {
+            return Err(DiscoveryError::TokenExistsError);
+        }
+
+        let mut bytes = [0; TOKEN_LEN];
+        SystemRandom::new()
+            .fill(&mut bytes)
+            .map_err(|_| DiscoveryError::InternalServerError)?;
+        let token = hex::encode(bytes);
+
+        tokens.push(ApiToken {
+            name,
+            hash: hash_token(&token),
+            scopes,
+        });
+        self.save(&tokens)?;
+
+        Ok(token)
+    }
+
+    pub fn revoke(&self, name: &str) -> Result<(), DiscoveryError> {
+        let mut tokens = self.list()?;
+        let count = tokens.len();
+        tokens.retain(|token| token.name != name);
+        if tokens.len() == count {
+            return Err(DiscoveryError::TokenNotFoundError);
+        }
+
+        self.save(&tokens)
+    }
+
+    pub fn authorize(&self, token: Option<&str>, scopes: &[Scope]) -> Result<(), DiscoveryError> {
+        authorize(&self.list()?, token, scopes)
+    }
+
+    fn save(&self, tokens: &[ApiToken]) -> Result<(), DiscoveryError> {
+        let contents = serde_json::to_string_pretty(tokens)
+            .map_err(|_| DiscoveryError::InternalServerError)?;
+        fs::write(&self.path, contents).map_err(|_| DiscoveryError::InternalServerError)?;
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&self.path, fs::Permissions::from_mode(0o600))
+                .map_err(|_| DiscoveryError::InternalServerError)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn hash_token(token: &str) -> String {
+    hex::encode(digest(&SHA256, token.as_bytes()))
+}
+
+/// Any one of `scopes` is enough
+fn authorize(
+    tokens: &[ApiToken],
+    token: Option<&str>,
+    scopes: &[Scope],
+) -> Result<(), DiscoveryError> {
+    if tokens.is_empty() {
+        return Ok(());
+    }
+
+    let hash = hash_token(token.ok_or(DiscoveryError::UnauthorizedError)?);
+    match tokens.iter().find(|token| token.hash == hash) {
+        Some(token) if scopes.iter().any(|scope| token.allows(*scope)) => Ok(()),
+        Some(_) => Err(DiscoveryError::ForbiddenError),
+        None => Err(DiscoveryError::UnauthorizedError),
+    }
+}
+
+/// Marks which scopes a route accepts, through `Auth<Register>` and alike
+pub trait RequiredScope {
+    const SCOPES: &'static [Scope];
+}
+
+pub struct Register;
+pub struct Lookup;
+pub struct Admin;
+/// Either end of a transfer
+pub struct Peer;
+
+impl RequiredScope for Register {
+    const SCOPES: &'static [Scope] = &[Scope::Register];
+}
+
+impl RequiredScope for Lookup {
+    const SCOPES: &'static [Scope] = &[Scope::Lookup];
+}
+
+impl RequiredScope for Admin {
+    const SCOPES: &'static [Scope] = &[Scope::Admin];
+}
+
+impl RequiredScope for Peer {
+    const SCOPES: &'static [Scope] = &[Scope::Register, Scope::Lookup];
+}
+
+/// Request guard checking the `Authorization: Bearer <token>` header for a scope
+pub struct Auth<S: RequiredScope>(PhantomData<S>);
+
+impl<'a, 'r, S: RequiredScope> FromRequest<'a, 'r> for Auth<S> {
+    type Error = DiscoveryError;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let tokens = match request.guard::<State<TokenStore>>() {
+            Outcome::Success(x) => x,
+            _ => {
+                return Outcome::Failure((
+                    Status::InternalServerError,
+                    DiscoveryError::InternalServerError,
+                ))
+            }
+        };
+
+        let token = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|header| header.strip_prefix("Bearer "));
+
+        let res = tokens.authorize(token, S::SCOPES);
+        if res.is_err() {
+            if let Outcome::Success(metrics) = request.guard::<State<Metrics>>() {
+                metrics.auth_failures.inc();
+            }
+        }
+
+        match res {
+            Ok(()) => Outcome::Success(Auth(PhantomData)),
+            Err(DiscoveryError::ForbiddenError) => {
+                Outcome::Failure((Status::Forbidden, DiscoveryError::ForbiddenError))
+            }
+            Err(DiscoveryError::UnauthorizedError) => {
+                Outcome::Failure((Status::Unauthorized, DiscoveryError::UnauthorizedError))
+            }
+            Err(err) => Outcome::Failure((Status::InternalServerError, err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{authorize, hash_token, ApiToken, Scope};
+
+    fn token(name: &str, scopes: Vec<Scope>) -> ApiToken {
+        ApiToken {
+            name: name.to_string(),
+            hash: hash_token(name),
+            scopes,
+        }
+    }
+
+    #[test]
+    fn test_authorize() {
+        // Nothing to check against until a token is created
+        assert!(authorize(&[], None, &[Scope::Admin]).is_ok());
+
+        let tokens = vec![
+            token("seeder", vec![Scope::Register]),
+            token("leecher", vec![Scope::Lookup]),
+            token("operator", vec![Scope::Admin]),
+        ];
+
+        assert!(authorize(&tokens, Some("seeder"), &[Scope::Register]).is_ok());
+        assert!(authorize(&tokens, Some("seeder"), &[Scope::Lookup]).is_err());
+        assert!(authorize(&tokens, Some("leecher"), &[Scope::Lookup]).is_ok());
+        assert!(authorize(&tokens, Some("operator"), &[Scope::Register]).is_ok());
+        assert!(authorize(&tokens, Some("operator"), &[Scope::Lookup]).is_ok());
+
+        assert!(authorize(&tokens, Some("intruder"), &[Scope::Lookup]).is_err());
+        assert!(authorize(&tokens, None, &[Scope::Lookup]).is_err());
+
+        // Routes for either end of a transfer accept both
+        assert!(authorize(&tokens, Some("seeder"), &[Scope::Register, Scope::Lookup]).is_ok());
+        assert!(authorize(&tokens, Some("leecher"), &[Scope::Register, Scope::Lookup]).is_ok());
+    }
+}
@@ -1,27 +1,38 @@
 use anyhow::Result;
 
 use crate::errors::DiscoveryError;
-use common::entities::{IceServer, ServerInfo};
+use common::{
+    entities::{IceServer, ServerInfo},
+    models::RegisterOrRefreshServerReq,
+};
 
 // pub type FileType = Uuid;
 
 pub trait DB {
-    fn register(
-        &mut self,
-        server_uuid: String,
-        files: Option<Vec<String>>,
-        ice_servers: Option<Vec<IceServer>>,
-        url: String,
-    ) -> Result<(), DiscoveryError>;
+    fn register(&mut self, req: RegisterOrRefreshServerReq) -> Result<(), DiscoveryError>;
     fn lookup(&self, server_uuid: String) -> bool;
-    fn update(
-        &mut self,
-        server_uuid: String,
-        files: Option<Vec<String>>,
-        ice_servers: Option<Vec<IceServer>>,
-        url: String,
-    ) -> Result<(), DiscoveryError>;
+    fn get_server(&self, server_uuid: String) -> Option<&ServerInfo>;
+    fn update(&mut self, req: RegisterOrRefreshServerReq) -> Result<(), DiscoveryError>;
+    /// Drops a server along with the private claims nobody serves anymore
+    fn deregister(&mut self, server_uuid: String) -> Result<(), DiscoveryError>;
     fn get_file_list(&self, server_uuid: String) -> Option<&Vec<String>>;
     fn get_ice_servers(&self, server_uuid: String) -> Option<&Vec<IceServer>>;
     fn find_servers_by_file(&self, file_id: String) -> Result<Vec<ServerInfo>, DiscoveryError>;
+    /// Hands out the shortest nameplate not in use for a file, the same one every time
+    /// for the same file until it's released
+    fn claim_nameplate(&mut self, file_id: String, now: u64) -> Result<String, DiscoveryError>;
+    /// Nameplates only lead to their file once, they are released when looked up or
+    /// once they expire, whichever comes first
+    fn lookup_nameplate(&mut self, nameplate: String, now: u64) -> Result<String, DiscoveryError>;
+    /// Public key of whoever registered a file as private, if anyone did and some server
+    /// still serves it
+    fn get_file_owner(&self, file_id: String) -> Option<&String>;
+    /// Counts a lookup made with a share token, returns how many were made so far. The
+    /// counts of tokens expired by `now` are dropped, they can't be used anymore
+    fn record_share_token_use(&mut self, token_id: String, expires_at: u64, now: u64) -> u32;
+    fn server_count(&self) -> usize;
+    /// Files at least one server has
+    fn file_count(&self) -> usize;
+    /// Nameplates claimed and not released yet
+    fn nameplate_count(&self, now: u64) -> usize;
 }
@@ -1,40 +1,33 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use anyhow::Result;
-use common::entities::{IceServer, ServerInfo};
+use common::{
+    entities::{IceServer, ServerInfo},
+    models::RegisterOrRefreshServerReq,
+};
 
 use crate::errors::DiscoveryError;
 
 use super::DB;
 
+// Nameplates nobody looked up are released after this long
+const NAMEPLATE_TTL_SECS: u64 = 60 * 60;
+
 pub struct MapDB {
     data: HashMap<String, ServerInfo>,
+    nameplates: HashMap<String, Nameplate>,
+    // Private file id to the public key of its owner
+    private_files: HashMap<String, String>,
+    // Share token id to the lookups made with it
+    share_token_uses: HashMap<String, ShareTokenUses>,
 }
 
 impl DB for MapDB {
-    fn register(
-        &mut self,
-        server_uuid: String,
-        files: Option<Vec<String>>,
-        ice_servers: Option<Vec<IceServer>>,
-        url: String,
-    ) -> Result<(), DiscoveryError> {
-        if !self.data.contains_key(&server_uuid) {
-            let mut server_info: ServerInfo = Default::default();
-
-            if let Some(files) = files {
-                server_info.files = files;
-            }
-
-            if let Some(ice_servers) = ice_servers {
-                server_info.ice_servers = ice_servers;
-            }
-
-            server_info.id = server_uuid.clone();
-
-            server_info.url = url;
-
-            self.data.insert(server_uuid, server_info);
+    fn register(&mut self, req: RegisterOrRefreshServerReq) -> Result<(), DiscoveryError> {
+        if !self.data.contains_key(&req.server_id) {
+            self.record_private_files(&req)?;
+            self.data
+                .insert(req.server_id.clone(), server_info_from(req));
         }
 
         Ok(())
@@ -44,26 +37,24 @@ impl DB for MapDB {
         self.data.contains_key(&server_uuid)
     }
 
-    fn update(
-        &mut self,
-        server_uuid: String,
-        files: Option<Vec<String>>,
-        ice_servers: Option<Vec<IceServer>>,
-        url: String,
-    ) -> Result<(), DiscoveryError> {
-        let mut server_info = ServerInfo::default();
-
-        if let Some(files) = files {
-            server_info.files = files;
-        }
+    fn get_server(&self, server_uuid: String) -> Option<&ServerInfo> {
+        self.data.get(&server_uuid)
+    }
 
-        if let Some(ice_servers) = ice_servers {
-            server_info.ice_servers = ice_servers;
-        }
+    fn update(&mut self, req: RegisterOrRefreshServerReq) -> Result<(), DiscoveryError> {
+        self.record_private_files(&req)?;
+        self.data
+            .insert(req.server_id.clone(), server_info_from(req));
+        self.release_unserved_private_files();
 
-        server_info.url = url;
+        Ok(())
+    }
 
-        self.data.insert(server_uuid, server_info);
+    fn deregister(&mut self, server_uuid: String) -> Result<(), DiscoveryError> {
+        self.data
+            .remove(&server_uuid)
+            .ok_or(DiscoveryError::ServerNotFoundError)?;
+        self.release_unserved_private_files();
 
         Ok(())
     }
@@ -90,12 +81,320 @@ impl DB for MapDB {
 
         Err(DiscoveryError::ServerNotFoundError)
     }
+
+    fn claim_nameplate(&mut self, file_id: String, now: u64) -> Result<String, DiscoveryError> {
+        self.release_expired_nameplates(now);
+
+        for (nameplate, claimed) in &self.nameplates {
+            if claimed.file_id == file_id {
+                return Ok(nameplate.clone());
+            }
+        }
+
+        let mut n = 1;
+        while self.nameplates.contains_key(&n.to_string()) {
+            n += 1;
+        }
+
+        let nameplate = n.to_string();
+        self.nameplates.insert(
+            nameplate.clone(),
+            Nameplate {
+                file_id,
+                claimed_at: now,
+            },
+        );
+
+        Ok(nameplate)
+    }
+
+    fn lookup_nameplate(&mut self, nameplate: String, now: u64) -> Result<String, DiscoveryError> {
+        self.release_expired_nameplates(now);
+
+        self.nameplates
+            .remove(&nameplate)
+            .map(|claimed| claimed.file_id)
+            .ok_or(DiscoveryError::NameplateNotFoundError)
+    }
+
+    fn get_file_owner(&self, file_id: String) -> Option<&String> {
+        self.private_files.get(&file_id)
+    }
+
+    fn record_share_token_use(&mut self, token_id: String, expires_at: u64, now: u64) -> u32 {
+        self.share_token_uses
+            .retain(|_, uses| !uses.is_expired(now));
+
+        let uses = self
+            .share_token_uses
+            .entry(token_id)
+            .or_insert(ShareTokenUses {
+                count: 0,
+                expires_at,
+            });
+        uses.count += 1;
+        uses.count
+    }
+
+    fn server_count(&self) -> usize {
+        self.data.len()
+    }
+
+    fn file_count(&self) -> usize {
+        self.data
+            .values()
+            .flat_map(|server_info| &server_info.files)
+            .collect::<HashSet<_>>()
+            .len()
+    }
+
+    fn nameplate_count(&self, now: u64) -> usize {
+        self.nameplates
+            .values()
+            .filter(|claimed| !claimed.is_expired(now))
+            .count()
+    }
+}
+
+struct Nameplate {
+    file_id: String,
+    // Seconds since the unix epoch
+    claimed_at: u64,
+}
+
+impl Nameplate {
+    fn is_expired(&self, now: u64) -> bool {
+        self.claimed_at + NAMEPLATE_TTL_SECS <= now
+    }
+}
+
+struct ShareTokenUses {
+    count: u32,
+    // Seconds since the unix epoch
+    expires_at: u64,
+}
+
+impl ShareTokenUses {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at <= now
+    }
+}
+
+fn server_info_from(req: RegisterOrRefreshServerReq) -> ServerInfo {
+    let mut server_info = ServerInfo::default();
+
+    if let Some(files) = req.files {
+        server_info.files = files;
+    }
+
+    if let Some(ice_servers) = req.ice_candidates {
+        server_info.ice_servers = ice_servers;
+    }
+
+    if let Some(completeness) = req.completeness {
+        server_info.completeness = completeness;
+    }
+
+    if let Some(fingerprint) = req.fingerprint {
+        server_info.fingerprint = fingerprint;
+    }
+
+    server_info.id = req.server_id;
+    server_info.url = req.url;
+    server_info.public_key = req.public_key;
+    server_info.registered_at = req.timestamp;
+
+    server_info
+}
+
+impl Default for MapDB {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl MapDB {
     pub fn new() -> Self {
         MapDB {
             data: HashMap::new(),
+            nameplates: HashMap::new(),
+            private_files: HashMap::new(),
+            share_token_uses: HashMap::new(),
         }
     }
+
+    fn release_expired_nameplates(&mut self, now: u64) {
+        self.nameplates
+            .retain(|_, claimed| !claimed.is_expired(now));
+    }
+
+    // A file stays private to its owner, whoever else seeds it later. It can't be
+    // claimed by a key while servers of other keys already serve it in public, all
+    // claims are checked before any is recorded so a refused registration changes nothing
+    fn record_private_files(
+        &mut self,
+        req: &RegisterOrRefreshServerReq,
+    ) -> Result<(), DiscoveryError> {
+        let private_files = match &req.private_files {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+
+        for file_id in private_files {
+            match self.private_files.get(file_id) {
+                Some(owner) if owner == &req.public_key => {}
+                Some(_) => return Err(DiscoveryError::PrivateFileClaimedError),
+                None => {
+                    let served_by_others = self.data.values().any(|server_info| {
+                        server_info.id != req.server_id
+                            && server_info.public_key != req.public_key
+                            && server_info.files.contains(file_id)
+                    });
+                    if served_by_others {
+                        return Err(DiscoveryError::PrivateFileClaimedError);
+                    }
+                }
+            }
+        }
+
+        for file_id in private_files {
+            self.private_files
+                .entry(file_id.clone())
+                .or_insert_with(|| req.public_key.clone());
+        }
+
+        Ok(())
+    }
+
+    // Claims only last as long as some registered server serves the file
+    fn release_unserved_private_files(&mut self) {
+        let data = &self.data;
+        self.private_files.retain(|file_id, _| {
+            data.values()
+                .any(|server_info| server_info.files.contains(file_id))
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common::models::RegisterOrRefreshServerReq;
+
+    use super::{MapDB, NAMEPLATE_TTL_SECS};
+    use crate::{db::DB, errors::DiscoveryError};
+
+    const NOW: u64 = 1_650_000_000;
+
+    #[test]
+    fn test_nameplates() {
+        let mut db = MapDB::new();
+
+        assert_eq!(db.claim_nameplate("a".to_string(), NOW).unwrap(), "1");
+        assert_eq!(db.claim_nameplate("b".to_string(), NOW).unwrap(), "2");
+        assert_eq!(db.claim_nameplate("a".to_string(), NOW).unwrap(), "1");
+        assert_eq!(db.nameplate_count(NOW), 2);
+
+        // A nameplate leads to its file only once, and is handed out again after
+        assert_eq!(db.lookup_nameplate("2".to_string(), NOW).unwrap(), "b");
+        assert!(db.lookup_nameplate("2".to_string(), NOW).is_err());
+        assert!(db.lookup_nameplate("3".to_string(), NOW).is_err());
+        assert_eq!(db.claim_nameplate("c".to_string(), NOW).unwrap(), "2");
+
+        // Nameplates nobody looked up expire
+        let later = NOW + NAMEPLATE_TTL_SECS;
+        assert_eq!(db.nameplate_count(later), 0);
+        assert!(db.lookup_nameplate("1".to_string(), later).is_err());
+        assert_eq!(db.claim_nameplate("d".to_string(), later).unwrap(), "1");
+    }
+
+    #[test]
+    fn test_private_files() {
+        let mut db = MapDB::new();
+
+        let mut req = RegisterOrRefreshServerReq {
+            server_id: "server".to_string(),
+            files: Some(vec!["a".to_string(), "b".to_string()]),
+            ice_candidates: None,
+            url: "http://localhost:8080".to_string(),
+            completeness: None,
+            fingerprint: None,
+            private_files: Some(vec!["a".to_string()]),
+            public_key: "owner".to_string(),
+            timestamp: 0,
+            signature: String::new(),
+        };
+        db.register(req.clone()).unwrap();
+
+        // Someone else seeding the file doesn't take it over
+        req.server_id = "seeder".to_string();
+        req.public_key = "seeder".to_string();
+        req.private_files = None;
+        db.register(req.clone()).unwrap();
+
+        assert_eq!(db.get_file_owner("a".to_string()).unwrap(), "owner");
+        assert!(db.get_file_owner("b".to_string()).is_none());
+
+        // Nor can they claim it for themselves
+        req.server_id = "thief".to_string();
+        req.public_key = "thief".to_string();
+        req.private_files = Some(vec!["a".to_string()]);
+        assert!(db.register(req.clone()).is_err());
+        assert!(!db.lookup("thief".to_string()));
+        assert_eq!(db.get_file_owner("a".to_string()).unwrap(), "owner");
+
+        // Once no server serves the file anymore, its claim goes with it
+        let mut owner_req = req.clone();
+        owner_req.server_id = "server".to_string();
+        owner_req.public_key = "owner".to_string();
+        owner_req.files = Some(vec!["b".to_string()]);
+        owner_req.private_files = None;
+        db.update(owner_req).unwrap();
+        assert_eq!(db.get_file_owner("a".to_string()).unwrap(), "owner");
+        db.deregister("seeder".to_string()).unwrap();
+        assert!(db.get_file_owner("a".to_string()).is_none());
+        assert!(db.deregister("seeder".to_string()).is_err());
+
+        assert_eq!(db.record_share_token_use("token".to_string(), 100, 0), 1);
+        assert_eq!(db.record_share_token_use("token".to_string(), 100, 0), 2);
+
+        // Uses of expired tokens aren't kept around
+        assert_eq!(db.record_share_token_use("other".to_string(), 200, 100), 1);
+        assert_eq!(db.share_token_uses.len(), 1);
+    }
+
+    #[test]
+    fn test_public_files_cant_be_claimed() {
+        let mut db = MapDB::new();
+
+        let mut req = RegisterOrRefreshServerReq {
+            server_id: "server".to_string(),
+            files: Some(vec!["a".to_string()]),
+            ice_candidates: None,
+            url: "http://localhost:8080".to_string(),
+            completeness: None,
+            fingerprint: None,
+            private_files: None,
+            public_key: "server".to_string(),
+            timestamp: 0,
+            signature: String::new(),
+        };
+        db.register(req.clone()).unwrap();
+
+        // Claiming a file other keys serve in public would hide it from their peers
+        req.server_id = "claimer".to_string();
+        req.public_key = "claimer".to_string();
+        req.private_files = Some(vec!["a".to_string()]);
+        assert!(matches!(
+            db.register(req.clone()),
+            Err(DiscoveryError::PrivateFileClaimedError)
+        ));
+        assert!(db.get_file_owner("a".to_string()).is_none());
+        assert_eq!(db.find_servers_by_file("a".to_string()).unwrap().len(), 1);
+
+        // The key which serves it may still make it private
+        req.server_id = "server".to_string();
+        req.public_key = "server".to_string();
+        db.update(req).unwrap();
+        assert_eq!(db.get_file_owner("a".to_string()).unwrap(), "server");
+    }
 }
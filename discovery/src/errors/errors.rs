@@ -1,6 +1,20 @@
 #[derive(Debug, Clone)]
 pub enum DiscoveryError {
     ServerNotFoundError,
+    NameplateNotFoundError,
+    InvalidSignatureError,
+    StaleRegistrationError,
+    ServerIdOwnedError,
+    UnauthorizedError,
+    ForbiddenError,
+    TokenExistsError,
+    TokenNotFoundError,
+    ShareTokenRequiredError,
+    InvalidShareTokenError,
+    ShareTokenExpiredError,
+    ShareTokenExhaustedError,
+    PrivateFileClaimedError,
+    TurnServerError(String),
     InternalServerError,
 }
 
@@ -10,6 +24,31 @@ impl std::fmt::Display for DiscoveryError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             DiscoveryError::ServerNotFoundError => write!(f, "Server not found in database"),
+            DiscoveryError::NameplateNotFoundError => write!(f, "Nameplate not found in database"),
+            DiscoveryError::InvalidSignatureError => write!(f, "Invalid registration signature"),
+            DiscoveryError::StaleRegistrationError => write!(f, "Registration is too old"),
+            DiscoveryError::ServerIdOwnedError => {
+                write!(f, "Server id is owned by another key")
+            }
+            DiscoveryError::UnauthorizedError => write!(f, "Missing or unknown API token"),
+            DiscoveryError::ForbiddenError => write!(f, "API token lacks the needed scope"),
+            DiscoveryError::TokenExistsError => write!(f, "A token with this name exists"),
+            DiscoveryError::TokenNotFoundError => write!(f, "No token with this name"),
+            DiscoveryError::ShareTokenRequiredError => {
+                write!(f, "File is private, a share token is needed")
+            }
+            DiscoveryError::InvalidShareTokenError => write!(f, "Invalid share token"),
+            DiscoveryError::ShareTokenExpiredError => write!(f, "Share token expired"),
+            DiscoveryError::ShareTokenExhaustedError => {
+                write!(f, "Share token was used up")
+            }
+            DiscoveryError::PrivateFileClaimedError => {
+                write!(
+                    f,
+                    "File is served or owned by another key, it can't be made private"
+                )
+            }
+            DiscoveryError::TurnServerError(err) => write!(f, "TURN server error: {}", err),
             DiscoveryError::InternalServerError => write!(f, "Internal Server Error"),
         }
     }
@@ -1,39 +1,426 @@
-// pub mod db;
-// pub mod errors;
-// pub mod models;
+#![feature(proc_macro_hygiene, decl_macro)]
 
-// pub struct Discovery {
-//     db: Box<dyn DB>,
-// }
+#[macro_use]
+extern crate rocket;
+
+pub mod auth;
+pub mod db;
+pub mod errors;
+pub mod metrics;
+pub mod turn;
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Result};
+use common::models::{
+    ClaimNameplateReq, ClaimNameplateRes, DeregisterServerReq, FindServerForFileRes,
+    LookupNameplateRes, ShareToken, TurnCredentialsRes,
+};
+use rocket::{http::ContentType, response::content::Content, State};
+use rocket_contrib::json::Json;
+use serde_json::{json, Value};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use auth::{
+    verify_deregistration, verify_ownership, verify_registration, verify_share_token, Admin, Auth,
+    Lookup, Peer, Register, ShareTokenHeader, TokenStore,
+};
+use db::{MapDB, DB};
+use errors::DiscoveryError;
+use metrics::{Gauges, Metrics};
+use turn::{EmbeddedTurn, TurnConfig};
+
+pub type TDBService = Box<dyn DB + 'static + Send + Sync>;
+
+pub struct Discovery {
+    db: Arc<Mutex<TDBService>>,
+}
+
+// #[("/file/lookup?file_id")]
+// pub fn file_lookup(req: Json<models::FileLookupReq>) -> Result<Json<Value>> {
+//     Ok(Json(json!({
+//         "success":  true,
+//     })))
 
-// impl Discovery {
-//     pub fn new() -> Discovery {
-//         let db = MapDB::new();
-//         Self { db: Box::new(db) }
-//     }
-
-//     pub fn file_lookup(&self, server_uuid: Uuid) -> Option<&Vec<FileType>> {
-//         self.db.get_file_list(server_uuid)
-//     }
-
-//     // pub fn add_file(server_id: Uuid, file_id: Uuid) {}
-
-//     pub fn add_ice_candidates() {}
-
-//     pub fn register_or_refresh_server(
-//         &mut self,
-//         server_uuid: Uuid,
-//         files: Option<Vec<FileType>>,
-//         ice_candidates: Option<Vec<RTCIceServer>>,
-//         // on_offer_cb: |offer: RTCSessionDescription| -> (),
-//     ) -> Result<(), DiscoveryError> {
-//         Ok(self.db.update(server_uuid, files, ice_candidates)?)
-//     }
-
-//     pub fn get_server_by_file_id(
-//         &self,
-//         file_id: Uuid,
-//     ) -> Result<(Uuid, ServerInfo), DiscoveryError> {
-//         self.db.find_server_by_file(file_id)
-//     }
+//     // self.db.get_file_list(server_uuid)
 // }
+
+// TODO: Implement Catchers later
+
+#[post("/register", format = "application/json", data = "<req>")]
+pub fn register_or_refresh_server(
+    req: Json<common::models::RegisterOrRefreshServerReq>,
+    discovery: State<Discovery>,
+    metrics: State<Metrics>,
+    _auth: Auth<Register>,
+) -> Result<Json<Value>> {
+    let discovery_data = discovery.inner();
+
+    let mut unwrapped_data = match discovery_data.db.lock() {
+        Ok(x) => x,
+        Err(_) => bail!("Internal Server Error"),
+    };
+
+    let rejected = |err| {
+        metrics.rejected_registrations.inc();
+        err
+    };
+
+    verify_registration(&req, now()).map_err(rejected)?;
+
+    let server_id = req.server_id.clone();
+
+    // Servers which are still downloading their files refresh their registration
+    // as their completeness changes, only the key which registered first may do so
+    if let Some(server_info) = unwrapped_data.get_server(server_id.clone()) {
+        verify_ownership(&req, server_info).map_err(rejected)?;
+        unwrapped_data.update(req.into_inner()).map_err(rejected)?;
+        metrics.refreshes.inc();
+
+        info!(server_id = %server_id, "Refreshed server");
+    } else {
+        unwrapped_data
+            .register(req.into_inner())
+            .map_err(rejected)?;
+        metrics.registrations.inc();
+
+        info!(server_id = %server_id, "Registered server");
+    }
+
+    Ok(Json(json!({
+        "success":  true,
+    })))
+}
+
+/// Servers serve a single peer connection, once it closes they deregister so nobody
+/// else is sent to them
+#[post("/deregister", format = "application/json", data = "<req>")]
+pub fn deregister_server(
+    req: Json<DeregisterServerReq>,
+    discovery: State<Discovery>,
+    metrics: State<Metrics>,
+    _auth: Auth<Register>,
+) -> Result<Json<Value>> {
+    let discovery_data = discovery.inner();
+
+    let mut unwrapped_data = match discovery_data.db.lock() {
+        Ok(x) => x,
+        Err(_) => bail!("Internal Server Error"),
+    };
+
+    let server_info = unwrapped_data
+        .get_server(req.server_id.clone())
+        .ok_or(DiscoveryError::ServerNotFoundError)?;
+    verify_deregistration(&req, server_info, now()).map_err(|err| {
+        metrics.rejected_registrations.inc();
+        err
+    })?;
+
+    unwrapped_data.deregister(req.server_id.clone())?;
+    metrics.deregistrations.inc();
+
+    info!(server_id = %req.server_id, "Deregistered server");
+
+    Ok(Json(json!({
+        "success":  true,
+    })))
+}
+
+#[get("/<file_id>", format = "application/json")]
+pub fn get_servers_by_file_id(
+    discovery: State<Discovery>,
+    file_id: String,
+    share_token: ShareTokenHeader,
+    metrics: State<Metrics>,
+    _auth: Auth<Lookup>,
+) -> Result<Json<FindServerForFileRes>> {
+    let discovery_data = discovery.inner();
+
+    let mut unwrapped_data = match discovery_data.db.lock() {
+        Ok(x) => x,
+        Err(_) => bail!("Internal Server Error"),
+    };
+
+    //Just to make sure correct format of uuid is sent
+    let _ = match Uuid::parse_str(&file_id) {
+        Ok(uuid) => uuid,
+        Err(_) => bail!("Invalid ID format"),
+    };
+    metrics.lookups.inc();
+
+    check_share_token(&mut unwrapped_data, &file_id, share_token.0).map_err(|err| {
+        metrics.share_token_denials.inc();
+        err
+    })?;
+
+    let servers_info = unwrapped_data
+        .find_servers_by_file(file_id.to_string())
+        .map_err(|err| {
+            metrics.lookup_misses.inc();
+            err
+        })?;
+
+    Ok(Json(FindServerForFileRes {
+        servers_info,
+        success: true,
+    }))
+}
+
+/// Private files are only found with a share token their owner signed
+fn check_share_token(
+    db: &mut TDBService,
+    file_id: &str,
+    share_token: Option<String>,
+) -> Result<(), DiscoveryError> {
+    let owner = match db.get_file_owner(file_id.to_string()).cloned() {
+        Some(x) => x,
+        None => return Ok(()),
+    };
+
+    let token = share_token.ok_or(DiscoveryError::ShareTokenRequiredError)?;
+    let token = ShareToken::decode(&token).ok_or(DiscoveryError::InvalidShareTokenError)?;
+    let now = now();
+    verify_share_token(&token, file_id, &owner, now)?;
+
+    let uses = db.record_share_token_use(token.token_id.clone(), token.expires_at, now);
+    if let Some(max_downloads) = token.max_downloads {
+        if uses > max_downloads {
+            return Err(DiscoveryError::ShareTokenExhaustedError);
+        }
+    }
+
+    Ok(())
+}
+
+/// Short codes are made of a nameplate handed out here and words only the two ends know,
+/// so the file can be found without discovery ever learning the passphrase
+#[post("/", format = "application/json", data = "<req>")]
+pub fn claim_nameplate(
+    req: Json<ClaimNameplateReq>,
+    discovery: State<Discovery>,
+    metrics: State<Metrics>,
+    _auth: Auth<Register>,
+) -> Result<Json<ClaimNameplateRes>> {
+    let discovery_data = discovery.inner();
+
+    let mut unwrapped_data = match discovery_data.db.lock() {
+        Ok(x) => x,
+        Err(_) => bail!("Internal Server Error"),
+    };
+
+    //Just to make sure correct format of uuid is sent
+    let _ = match Uuid::parse_str(&req.file_id) {
+        Ok(uuid) => uuid,
+        Err(_) => bail!("Invalid ID format"),
+    };
+
+    let nameplate = unwrapped_data.claim_nameplate(req.file_id.clone(), now())?;
+    metrics.nameplates_claimed.inc();
+
+    Ok(Json(ClaimNameplateRes {
+        nameplate,
+        success: true,
+    }))
+}
+
+#[get("/<nameplate>", format = "application/json")]
+pub fn lookup_nameplate(
+    discovery: State<Discovery>,
+    nameplate: String,
+    metrics: State<Metrics>,
+    _auth: Auth<Lookup>,
+) -> Result<Json<LookupNameplateRes>> {
+    let discovery_data = discovery.inner();
+
+    let mut unwrapped_data = match discovery_data.db.lock() {
+        Ok(x) => x,
+        Err(_) => bail!("Internal Server Error"),
+    };
+
+    metrics.nameplate_lookups.inc();
+    let file_id = unwrapped_data
+        .lookup_nameplate(nameplate, now())
+        .map_err(|err| {
+            metrics.nameplate_misses.inc();
+            err
+        })?;
+
+    Ok(Json(LookupNameplateRes {
+        file_id,
+        success: true,
+    }))
+}
+
+/// The STUN and TURN servers of the deployment, TURN credentials expire after its ttl
+#[get("/credentials", format = "application/json")]
+pub fn turn_credentials(
+    turn: State<Option<TurnConfig>>,
+    metrics: State<Metrics>,
+    _auth: Auth<Peer>,
+) -> Result<Json<TurnCredentialsRes>> {
+    let (ice_servers, ttl) = match turn.inner() {
+        Some(turn) => {
+            metrics.credentials_issued.inc();
+            (turn.credentials(now()), turn.ttl_secs)
+        }
+        None => (vec![], 0),
+    };
+
+    Ok(Json(TurnCredentialsRes {
+        ice_servers,
+        ttl,
+        success: true,
+    }))
+}
+
+/// Counters and gauges in Prometheus' text format, for scrapers with an admin token
+#[get("/metrics")]
+pub fn metrics(
+    discovery: State<Discovery>,
+    metrics: State<Metrics>,
+    _auth: Auth<Admin>,
+) -> Result<Content<String>> {
+    let gauges = {
+        let unwrapped_data = match discovery.inner().db.lock() {
+            Ok(x) => x,
+            Err(_) => bail!("Internal Server Error"),
+        };
+
+        Gauges {
+            servers: unwrapped_data.server_count(),
+            files: unwrapped_data.file_count(),
+            nameplates: unwrapped_data.nameplate_count(now()),
+        }
+    };
+
+    Ok(Content(
+        ContentType::with_params("text", "plain", ("version", "0.0.4")),
+        metrics.render(gauges),
+    ))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+impl Default for Discovery {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Discovery {
+    pub fn new() -> Discovery {
+        let db = MapDB::new();
+        Self {
+            db: Arc::new(Mutex::new(Box::new(db))),
+        }
+    }
+
+    // pub fn add_file(server_id: Uuid, file_id: Uuid) {}
+
+    pub fn add_ice_candidates() {}
+}
+
+#[get("/")]
+fn hello() -> Result<Json<Value>> {
+    Ok(Json(json!({
+        "success":  true,
+    })))
+}
+
+/// Discovery as configured by the environment
+pub fn rocket() -> rocket::Rocket {
+    let tokens = TokenStore::from_env();
+    match tokens.list() {
+        Ok(x) if x.is_empty() => warn!("No API tokens configured, the API is open"),
+        Ok(x) => info!(tokens = x.len(), "Loaded API tokens"),
+        Err(err) => error!(?err, "Error reading API tokens"),
+    }
+
+    let embedded_turn = EmbeddedTurn::from_env();
+    let mut turn = TurnConfig::from_env(embedded_turn.as_ref());
+    let secret = turn.as_ref().map(|turn| turn.secret.clone());
+    if let (Some(embedded_turn), Some(secret)) = (&embedded_turn, secret) {
+        match embedded_turn.start(secret) {
+            Ok(()) => info!(port = embedded_turn.port, "Embedded TURN server listening"),
+            Err(err) => {
+                error!(?err, "Error starting embedded TURN server");
+                // Only the other servers are handed out then
+                turn = TurnConfig::from_env(None);
+            }
+        }
+    }
+    match &turn {
+        Some(turn) => info!(urls = ?turn.urls, "Issuing credentials for TURN servers"),
+        None => info!("No TURN server configured"),
+    }
+
+    mount(rocket::ignite(), tokens, turn)
+}
+
+/// Discovery with its state, on an instance configured by the caller
+pub fn mount(
+    rocket: rocket::Rocket,
+    tokens: TokenStore,
+    turn: Option<TurnConfig>,
+) -> rocket::Rocket {
+    let rocket = rocket.manage(Discovery::new());
+    let rocket = rocket.manage(tokens);
+    let rocket = rocket.manage(turn);
+    let rocket = rocket.manage(Metrics::default());
+    let rocket = rocket.mount("/", routes![hello, metrics]);
+    let rocket = rocket.mount(
+        "/api/server",
+        routes![
+            register_or_refresh_server,
+            deregister_server,
+            get_servers_by_file_id
+        ],
+    );
+    let rocket = rocket.mount("/api/nameplate", routes![claim_nameplate, lookup_nameplate]);
+    rocket.mount("/api/turn", routes![turn_credentials])
+}
+
+#[cfg(test)]
+mod test {
+    use super::{mount, rocket, TokenStore};
+    use rocket::http::{Accept, Status};
+    use rocket::local::Client;
+    use uuid::Uuid;
+
+    #[test]
+    fn hello_world() {
+        let client = Client::new(rocket()).expect("valid rocket instance");
+        let mut response = client.get("/").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.body_string(), Some(r#"{"success":true}"#.into()));
+    }
+
+    #[test]
+    fn metrics() {
+        // No tokens, so the API is open
+        let path = std::env::temp_dir().join(format!("tokens-{}.json", Uuid::new_v4()));
+        let tokens = TokenStore::new(path);
+        let client =
+            Client::new(mount(rocket::ignite(), tokens, None)).expect("valid rocket instance");
+        client
+            .get(format!("/api/server/{}", Uuid::new_v4()))
+            .header(Accept::JSON)
+            .dispatch();
+
+        let mut response = client.get("/metrics").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let body = response.body_string().expect("metrics");
+        assert!(body.contains("discovery_lookups_total 1\n"));
+        assert!(body.contains("discovery_lookup_misses_total 1\n"));
+        assert!(body.contains("discovery_servers 0\n"));
+    }
+}
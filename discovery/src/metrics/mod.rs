@@ -0,0 +1,2 @@
+pub use prometheus::*;
+mod prometheus;
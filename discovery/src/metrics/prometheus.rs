@@ -0,0 +1,159 @@
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// A count which only goes up for as long as discovery runs
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// What discovery was asked to do since it started, counted by the route handlers
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub registrations: Counter,
+    pub refreshes: Counter,
+    pub deregistrations: Counter,
+    // Bad signatures, stale registrations and server ids owned by another key
+    pub rejected_registrations: Counter,
+    pub lookups: Counter,
+    // Lookups of files no server has
+    pub lookup_misses: Counter,
+    pub share_token_denials: Counter,
+    pub nameplates_claimed: Counter,
+    pub nameplate_lookups: Counter,
+    pub nameplate_misses: Counter,
+    pub credentials_issued: Counter,
+    pub auth_failures: Counter,
+}
+
+/// What the DB holds when scraped
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Gauges {
+    pub servers: usize,
+    pub files: usize,
+    pub nameplates: usize,
+}
+
+impl Metrics {
+    /// Every counter and gauge in Prometheus' text format
+    pub fn render(&self, gauges: Gauges) -> String {
+        let counters = [
+            ("registrations", "Servers registered", &self.registrations),
+            ("refreshes", "Registrations refreshed", &self.refreshes),
+            (
+                "deregistrations",
+                "Servers deregistered once their peer connection closed",
+                &self.deregistrations,
+            ),
+            (
+                "rejected_registrations",
+                "Registrations and refreshes rejected",
+                &self.rejected_registrations,
+            ),
+            ("lookups", "Lookups of the servers of a file", &self.lookups),
+            (
+                "lookup_misses",
+                "Lookups of files no server has",
+                &self.lookup_misses,
+            ),
+            (
+                "share_token_denials",
+                "Lookups of private files without a valid share token",
+                &self.share_token_denials,
+            ),
+            (
+                "nameplates_claimed",
+                "Nameplates claimed",
+                &self.nameplates_claimed,
+            ),
+            (
+                "nameplate_lookups",
+                "Lookups of the file of a nameplate",
+                &self.nameplate_lookups,
+            ),
+            (
+                "nameplate_misses",
+                "Lookups of nameplates nobody claimed",
+                &self.nameplate_misses,
+            ),
+            (
+                "credentials_issued",
+                "TURN credentials handed out",
+                &self.credentials_issued,
+            ),
+            (
+                "auth_failures",
+                "Requests with a missing, unknown or insufficient API token",
+                &self.auth_failures,
+            ),
+        ];
+        let gauges = [
+            ("servers", "Servers registered", gauges.servers),
+            ("files", "Files at least one server has", gauges.files),
+            ("nameplates", "Nameplates in use", gauges.nameplates),
+        ];
+
+        let mut out = String::new();
+        for (name, help, counter) in counters {
+            write_metric(
+                &mut out,
+                &format!("discovery_{}_total", name),
+                help,
+                "counter",
+                counter.get(),
+            );
+        }
+        for (name, help, value) in gauges {
+            write_metric(
+                &mut out,
+                &format!("discovery_{}", name),
+                help,
+                "gauge",
+                value as u64,
+            );
+        }
+
+        out
+    }
+}
+
+fn write_metric(out: &mut String, name: &str, help: &str, kind: &str, value: u64) {
+    // Writing to a string can't fail
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Gauges, Metrics};
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        metrics.lookups.inc();
+        metrics.lookups.inc();
+        metrics.lookup_misses.inc();
+
+        let out = metrics.render(Gauges {
+            servers: 3,
+            files: 5,
+            nameplates: 0,
+        });
+
+        assert!(out.contains("# TYPE discovery_lookups_total counter\ndiscovery_lookups_total 2\n"));
+        assert!(out.contains("discovery_lookup_misses_total 1\n"));
+        assert!(out.contains("# TYPE discovery_servers gauge\ndiscovery_servers 3\n"));
+        assert!(out.contains("discovery_files 5\n"));
+    }
+}
@@ -0,0 +1,120 @@
+use common::entities::{IceCredentialType, IceServer};
+use ring::{
+    hmac,
+    rand::{SecureRandom, SystemRandom},
+};
+
+use super::EmbeddedTurn;
+
+const DEFAULT_TTL_SECS: u64 = 60 * 60;
+const USERNAME: &str = "turent";
+
+/// TURN servers sharing a secret with discovery, which hands out credentials for them
+/// following the TURN REST API scheme ( coturn's `use-auth-secret` )
+#[derive(Debug, Clone)]
+pub struct TurnConfig {
+    pub urls: Vec<String>,
+    // Handed out along with the TURN servers, they need no credentials
+    pub stun_urls: Vec<String>,
+    pub secret: String,
+    pub ttl_secs: u64,
+}
+
+impl TurnConfig {
+    /// Reads `TURN_URLS` ( comma separated ), `TURN_SECRET` and `TURN_TTL`, and adds the
+    /// embedded server if there is one. Without a secret the embedded server gets a
+    /// random one, other servers can't be used.
+    pub fn from_env(embedded: Option<&EmbeddedTurn>) -> Option<Self> {
+        let mut urls: Vec<String> = std::env::var("TURN_URLS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|url| url.trim().to_string())
+            .filter(|url| !url.is_empty())
+            .collect();
+        let mut stun_urls = vec![];
+        let mut secret = std::env::var("TURN_SECRET").unwrap_or_default();
+
+        if let Some(embedded) = embedded {
+            if secret.is_empty() {
+                urls.clear();
+                secret = random_secret()?;
+            }
+            urls.push(embedded.turn_url());
+            stun_urls.push(embedded.stun_url());
+        }
+
+        if urls.is_empty() || secret.is_empty() {
+            return None;
+        }
+
+        let ttl_secs = std::env::var("TURN_TTL")
+            .ok()
+            .and_then(|ttl| ttl.parse().ok())
+            .unwrap_or(DEFAULT_TTL_SECS);
+
+        Some(Self {
+            urls,
+            stun_urls,
+            secret,
+            ttl_secs,
+        })
+    }
+
+    /// The username is the expiry and the credential its HMAC-SHA1 under the secret, so
+    /// the TURN server checks them without ever talking to discovery
+    pub fn credentials(&self, now: u64) -> Vec<IceServer> {
+        let username = format!("{}:{}", now + self.ttl_secs, USERNAME);
+        let credential = credential(&self.secret, &username);
+
+        let mut ice_servers = vec![];
+        if !self.stun_urls.is_empty() {
+            ice_servers.push(IceServer {
+                urls: self.stun_urls.clone(),
+                username: String::new(),
+                credential: String::new(),
+                credential_type: IceCredentialType::Unspecified,
+            });
+        }
+
+        ice_servers.push(IceServer {
+            urls: self.urls.clone(),
+            username,
+            credential,
+            credential_type: IceCredentialType::Password,
+        });
+
+        ice_servers
+    }
+}
+
+pub fn credential(secret: &str, username: &str) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret.as_bytes());
+    base64::encode(hmac::sign(&key, username.as_bytes()).as_ref())
+}
+
+fn random_secret() -> Option<String> {
+    let mut bytes = [0; 32];
+    SystemRandom::new().fill(&mut bytes).ok()?;
+    Some(hex::encode(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TurnConfig;
+
+    #[test]
+    fn test_credentials() {
+        let turn = TurnConfig {
+            urls: vec!["turn:turn.example.com:3478".to_string()],
+            stun_urls: vec![],
+            secret: "north".to_string(),
+            ttl_secs: 3600,
+        };
+
+        let ice_servers = turn.credentials(1_650_000_000);
+        assert_eq!(ice_servers.len(), 1);
+        assert_eq!(ice_servers[0].urls, turn.urls);
+        assert_eq!(ice_servers[0].username, "1650003600:turent");
+        assert_eq!(ice_servers[0].credential, "BrsbgG95O6g6lMbUx3sDG0fheac=");
+    }
+}
@@ -0,0 +1,4 @@
+pub use credentials::*;
+pub use server::*;
+mod credentials;
+mod server;
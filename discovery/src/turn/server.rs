@@ -0,0 +1,210 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{mpsc, Arc},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use webrtc::{
+    turn::{
+        auth::{generate_auth_key, AuthHandler},
+        relay::relay_static::RelayAddressGeneratorStatic,
+        server::{
+            config::{ConnConfig, ServerConfig},
+            Server,
+        },
+        Error,
+    },
+    util::{vnet::net::Net, Conn},
+};
+
+use crate::errors::DiscoveryError;
+
+use super::credential;
+
+const DEFAULT_PORT: u16 = 3478;
+const REALM: &str = "turent";
+
+/// A STUN and TURN server running inside discovery, so a deployment needs no coturn
+#[derive(Debug, Clone)]
+pub struct EmbeddedTurn {
+    // Address relayed candidates are advertised with
+    pub public_ip: IpAddr,
+    pub port: u16,
+}
+
+impl EmbeddedTurn {
+    /// Enabled by `TURN_PUBLIC_IP`, listening on `TURN_PORT` or 3478
+    pub fn from_env() -> Option<Self> {
+        let public_ip = std::env::var("TURN_PUBLIC_IP").ok()?.parse().ok()?;
+        let port = std::env::var("TURN_PORT")
+            .ok()
+            .and_then(|port| port.parse().ok())
+            .unwrap_or(DEFAULT_PORT);
+
+        Some(Self { public_ip, port })
+    }
+
+    pub fn stun_url(&self) -> String {
+        format!("stun:{}", SocketAddr::new(self.public_ip, self.port))
+    }
+
+    pub fn turn_url(&self) -> String {
+        format!(
+            "turn:{}?transport=udp",
+            SocketAddr::new(self.public_ip, self.port)
+        )
+    }
+
+    /// Runs the server on a thread of its own, since Rocket doesn't run on tokio, and
+    /// waits for it to listen
+    pub fn start(&self, secret: String) -> Result<(), DiscoveryError> {
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let embedded = self.clone();
+
+        thread::spawn(move || {
+            let runtime = match tokio::runtime::Runtime::new() {
+                Ok(x) => x,
+                Err(err) => {
+                    let _ = ready_tx.send(Err(err.to_string()));
+                    return;
+                }
+            };
+
+            runtime.block_on(async move {
+                // The server stops once dropped, which is never
+                let _server = match embedded.serve(secret, Arc::new(Net::new(None))).await {
+                    Ok(x) => x,
+                    Err(err) => {
+                        let _ = ready_tx.send(Err(err.to_string()));
+                        return;
+                    }
+                };
+                let _ = ready_tx.send(Ok(()));
+
+                std::future::pending::<()>().await;
+            });
+        });
+
+        match ready_rx.recv() {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(err)) => Err(DiscoveryError::TurnServerError(err)),
+            Err(_) => Err(DiscoveryError::TurnServerError(
+                "TURN server stopped".to_string(),
+            )),
+        }
+    }
+
+    /// Serves on `net`, the host's network or a virtual one
+    pub async fn serve(&self, secret: String, net: Arc<Net>) -> Result<Server, Error> {
+        // A virtual network only delivers to the addresses of its interfaces
+        let ip = if net.is_virtual() {
+            self.public_ip
+        } else {
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+        };
+        let conn = net.bind(SocketAddr::new(ip, self.port)).await?;
+
+        self.serve_on(secret, conn, net).await
+    }
+
+    /// Serves on an already bound `conn`, relaying through `net`
+    pub async fn serve_on(
+        &self,
+        secret: String,
+        conn: Arc<dyn Conn + Send + Sync>,
+        net: Arc<Net>,
+    ) -> Result<Server, Error> {
+        Server::new(ServerConfig {
+            conn_configs: vec![ConnConfig {
+                conn,
+                relay_addr_generator: Box::new(RelayAddressGeneratorStatic {
+                    relay_address: self.public_ip,
+                    address: Ipv4Addr::UNSPECIFIED.to_string(),
+                    net,
+                }),
+            }],
+            realm: REALM.to_owned(),
+            auth_handler: Arc::new(RestAuthHandler { secret }),
+            // Defaults to 10 minutes
+            channel_bind_timeout: Duration::from_secs(0),
+        })
+        .await
+    }
+}
+
+/// Accepts the credentials handed out by `TurnConfig::credentials` until they expire
+struct RestAuthHandler {
+    secret: String,
+}
+
+impl AuthHandler for RestAuthHandler {
+    fn auth_handle(
+        &self,
+        username: &str,
+        realm: &str,
+        _src_addr: SocketAddr,
+    ) -> Result<Vec<u8>, Error> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or_default();
+
+        let expires_at: u64 = username
+            .split(':')
+            .next()
+            .and_then(|expires_at| expires_at.parse().ok())
+            .ok_or(Error::ErrNoSuchUser)?;
+        if expires_at <= now {
+            return Err(Error::ErrNoSuchUser);
+        }
+
+        Ok(generate_auth_key(
+            username,
+            realm,
+            &credential(&self.secret, username),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use webrtc::turn::auth::{generate_auth_key, AuthHandler};
+
+    use super::{RestAuthHandler, REALM};
+    use crate::turn::TurnConfig;
+
+    #[test]
+    fn test_rest_auth_handler() {
+        let turn = TurnConfig {
+            urls: vec!["turn:127.0.0.1:3478".to_string()],
+            stun_urls: vec![],
+            secret: "north".to_string(),
+            ttl_secs: 3600,
+        };
+        let handler = RestAuthHandler {
+            secret: turn.secret.clone(),
+        };
+        let src_addr: SocketAddr = "127.0.0.1:50000".parse().unwrap();
+
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let ice_server = turn.credentials(now).pop().unwrap();
+        assert_eq!(
+            handler
+                .auth_handle(&ice_server.username, REALM, src_addr)
+                .unwrap(),
+            generate_auth_key(&ice_server.username, REALM, &ice_server.credential)
+        );
+
+        let expired = turn.credentials(now - 2 * 3600).pop().unwrap();
+        assert!(handler
+            .auth_handle(&expired.username, REALM, src_addr)
+            .is_err());
+        assert!(handler.auth_handle("turent", REALM, src_addr).is_err());
+    }
+}